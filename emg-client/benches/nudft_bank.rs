@@ -0,0 +1,137 @@
+//! Compares the `NudftBank` with the way `Signal` used to compute the same DFTs: three `Window`s
+//! per frequency, and a sine and cosine per frequency per sample.
+//!
+//! emg-client is only a binary, so the modules are included directly.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use num_complex::Complex;
+use std::f64::consts::TAU;
+
+// its tests don't run here
+#[allow(dead_code, unused_imports)]
+#[path = "../src/nudft.rs"]
+mod nudft;
+#[allow(dead_code)]
+#[path = "../src/window.rs"]
+mod window;
+
+use nudft::NudftBank;
+use window::Window;
+
+// like `SignalParameters` at 1020 Hz
+const SAMPLE_RATE: f64 = 1020.0;
+const FFT_WINDOW: usize = 50;
+const FFT_HISTORY_SIZE: usize = 3000;
+const NUM_CHANNELS: usize = 4;
+const NUM_SAMPLES: usize = 1020;
+
+fn frequencies() -> impl Iterator<Item = f64> {
+    (1..FFT_WINDOW).map(|f| f as f64 * SAMPLE_RATE / (FFT_WINDOW as f64 * 2.0))
+}
+
+struct WindowedFrequency {
+    frequency: f64,
+    raw_signal_values: Window<f64>,
+    directions: Window<Complex<f64>>,
+    nudft_summands: Window<Complex<f64>>,
+    corrected_nudft_norms: Window<f64>,
+    latest_norm: f64,
+}
+
+impl WindowedFrequency {
+    fn new(frequency: f64) -> WindowedFrequency {
+        WindowedFrequency {
+            frequency,
+            raw_signal_values: Window::new(FFT_WINDOW),
+            directions: Window::new(FFT_WINDOW),
+            nudft_summands: Window::new(FFT_WINDOW),
+            corrected_nudft_norms: Window::new(FFT_HISTORY_SIZE),
+            latest_norm: 0.0,
+        }
+    }
+
+    fn observe(&mut self, raw_signal_value: f64, time: f64) {
+        let direction = Complex::cis(time * TAU * self.frequency);
+        self.raw_signal_values.push(raw_signal_value);
+        self.directions.push(direction);
+        self.nudft_summands.push(direction * raw_signal_value);
+        let corrected_nudft =
+            self.nudft_summands.mean() - self.raw_signal_values.mean() * self.directions.mean();
+        self.latest_norm = corrected_nudft.norm();
+        self.corrected_nudft_norms.push(self.latest_norm);
+    }
+}
+
+/// A second of slightly jittery samples, for each channel, starting well after time 0.
+fn samples() -> Vec<Vec<(f64, f64)>> {
+    (0..NUM_CHANNELS)
+        .map(|channel| {
+            (0..NUM_SAMPLES)
+                .map(|index| {
+                    let time = 3600.0 + index as f64 / SAMPLE_RATE + (index % 5) as f64 * 2e-5;
+                    let value = (index as f64 * (0.3 + channel as f64 * 0.1)).sin() * 0.1
+                        + ((index * 7919 + channel) % 101) as f64 * 0.001;
+                    (time, value)
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn check_equivalence(samples: &[Vec<(f64, f64)>]) {
+    for channel_samples in samples {
+        let mut windowed: Vec<WindowedFrequency> =
+            frequencies().map(WindowedFrequency::new).collect();
+        let mut bank = NudftBank::new(frequencies().next().unwrap(), FFT_WINDOW - 1, FFT_WINDOW);
+        for &(time, value) in channel_samples {
+            bank.push(time, value);
+            for (state, &norm) in windowed.iter_mut().zip(bank.norms()) {
+                state.observe(value, time);
+                assert!(
+                    (state.latest_norm - norm).abs() <= 1e-9 * (1.0 + norm),
+                    "{} Hz: windowed {} != sliding {}",
+                    state.frequency,
+                    state.latest_norm,
+                    norm
+                );
+            }
+        }
+    }
+}
+
+fn nudft_bank(c: &mut Criterion) {
+    let samples = samples();
+    check_equivalence(&samples);
+
+    let mut group = c.benchmark_group("one second of 4 channels");
+    group.bench_function("windowed", |b| {
+        b.iter(|| {
+            for channel_samples in &samples {
+                let mut states: Vec<WindowedFrequency> =
+                    frequencies().map(WindowedFrequency::new).collect();
+                for &(time, value) in channel_samples {
+                    for state in &mut states {
+                        state.observe(black_box(value), black_box(time));
+                    }
+                }
+                black_box(states.iter().map(|s| s.latest_norm).sum::<f64>());
+            }
+        })
+    });
+    group.bench_function("sliding", |b| {
+        b.iter(|| {
+            for channel_samples in &samples {
+                let mut bank =
+                    NudftBank::new(frequencies().next().unwrap(), FFT_WINDOW - 1, FFT_WINDOW);
+                for &(time, value) in channel_samples {
+                    bank.push(black_box(time), black_box(value));
+                }
+                black_box(bank.norms().iter().sum::<f64>());
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, nudft_bank);
+criterion_main!(benches);
@@ -0,0 +1,567 @@
+use crate::nudft::NudftBank;
+use crate::signal::{ActivityThresholdStats, SignalParameters, SingleFrequencyState};
+use crate::variables::{get_channel_variable, with_frequency_weights};
+use crate::window::Window;
+use anyhow::bail;
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/*
+
+The different ways of turning a signal into an activity level, so they can be compared on the same
+data. Each channel uses the one that its `activity_detector` variable says, and switching starts it
+over, learning its idle thresholds from scratch.
+
+Everything around them is the same for all of them: `Signal` filters out the line noise before a
+detector sees the samples, and afterwards, it subtracts cross-talk, checks for motion artifacts, and
+compares the level to `activity_threshold`. They all learn what idle looks like the same way as well:
+each feature they measure has a `SingleFrequencyState`, which learns its idle threshold from the
+maxima of chunks of it while the signal is idle, and measures the feature in increments above that.
+
+- DFT bank: the DFT norm at every frequency (see `nudft.rs`), with each frequency's level capped at
+  `max_activity_contribution_per_frequency`, summed with the frequency weights from calibrating.
+- RMS envelope: the RMS of the signal (around its mean) over the DFT window.
+- Teager-Kaiser energy: the mean of x[n]² - x[n-1]·x[n+1] over the DFT window (square-rooted, to be
+  in the same units as the signal). It responds to how fast the signal changes as well as how big
+  it is, so it picks up onsets quickly, and ignores slow baseline movement.
+- FFT filter bank: a Hann-windowed FFT, at the same frequency spacing as the DFT bank, every
+  frequencies frame, grouped into log-spaced bands. Each band counts for as many frequencies as it
+  covers, so the level comes out on the same scale as the DFT bank's.
+
+The single-feature detectors (RMS and Teager-Kaiser) don't cap their levels, since there's nothing
+else for the cap to be relative to, so the same `activity_threshold` means a bigger step above idle
+than it does for the DFT bank; it can be overridden per channel.
+
+*/
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DetectorKind {
+    Nudft,
+    Rms,
+    TeagerKaiser,
+    FilterBank,
+}
+
+impl DetectorKind {
+    /// In the order of the `activity_detector` variable's values.
+    pub const ALL: [DetectorKind; 4] = [
+        DetectorKind::Nudft,
+        DetectorKind::Rms,
+        DetectorKind::TeagerKaiser,
+        DetectorKind::FilterBank,
+    ];
+
+    pub fn from_variable(value: f64) -> DetectorKind {
+        DetectorKind::ALL
+            .get(value as usize)
+            .copied()
+            .unwrap_or(DetectorKind::Nudft)
+    }
+
+    pub fn variable_value(self) -> f64 {
+        DetectorKind::ALL.iter().position(|&k| k == self).unwrap() as f64
+    }
+
+    /// What it's called on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            DetectorKind::Nudft => "dft-bank",
+            DetectorKind::Rms => "rms",
+            DetectorKind::TeagerKaiser => "teager-kaiser",
+            DetectorKind::FilterBank => "filter-bank",
+        }
+    }
+
+    /// What the GUI calls it.
+    pub fn description(self) -> &'static str {
+        match self {
+            DetectorKind::Nudft => "DFT bank",
+            DetectorKind::Rms => "RMS envelope",
+            DetectorKind::TeagerKaiser => "Teager-Kaiser energy",
+            DetectorKind::FilterBank => "FFT filter bank",
+        }
+    }
+}
+
+impl fmt::Display for DetectorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for DetectorKind {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> anyhow::Result<DetectorKind> {
+        match DetectorKind::ALL.iter().find(|kind| kind.name() == text) {
+            Some(&kind) => Ok(kind),
+            None => {
+                let names: Vec<&str> = DetectorKind::ALL.iter().map(|kind| kind.name()).collect();
+                bail!(
+                    "there's no activity detector called {:?}; try one of {}",
+                    text,
+                    names.join(", ")
+                )
+            }
+        }
+    }
+}
+
+pub trait ActivityDetector: Send {
+    fn kind(&self) -> DetectorKind;
+
+    /// Takes in one sample, with the line noise already filtered out, and returns the activity
+    /// level, which is compared to `activity_threshold`. While `idle`, the detector learns what the
+    /// signal looks like at rest. `channel` is the signal's number across all servers.
+    fn observe(&mut self, value: f64, time: f64, channel: usize, idle: bool) -> f64;
+
+    /// Rows of values from 0 to 1 for the GUI to plot, like the DFT bank's norms, thresholds and
+    /// levels at each frequency.
+    fn debug_frame(&self, channel: usize) -> Vec<Vec<f64>>;
+
+    /// Forget everything that assumes the samples are contiguous, but keep the learned thresholds.
+    fn reset_windows(&mut self);
+
+    /// The latest amplitude at each frequency, if the detector looks at frequencies separately,
+    /// for telling impulses apart from muscle activity.
+    fn spectrum(&self) -> &[f64] {
+        &[]
+    }
+
+    /// How much of the spectrum is above its idle thresholds, from 0 to 1.
+    fn fraction_above_idle(&self) -> f64 {
+        0.0
+    }
+
+    /// The DFT bank's norms, which calibrating and learning weights work with; empty for the
+    /// other detectors.
+    fn nudft_norms(&self) -> &[f64] {
+        &[]
+    }
+
+    /// One per frequency, like `nudft_norms`.
+    fn set_activity_threshold_stats(&mut self, _stats: &[ActivityThresholdStats]) {}
+}
+
+pub fn new_detector(kind: DetectorKind, parameters: SignalParameters) -> Box<dyn ActivityDetector> {
+    match kind {
+        DetectorKind::Nudft => Box::new(NudftDetector::new(parameters)),
+        DetectorKind::Rms => Box::new(RmsDetector::new(parameters)),
+        DetectorKind::TeagerKaiser => Box::new(TeagerKaiserDetector::new(parameters)),
+        DetectorKind::FilterBank => Box::new(FilterBankDetector::new(parameters)),
+    }
+}
+
+// the GUI's plots are drawn with the norms at this scale
+const DEBUG_FRAME_SCALE: f64 = 50.0;
+
+/// The rows of a debug frame for some features: their values, their idle thresholds, and their
+/// levels as a fraction of `max_level`.
+fn feature_rows(states: &[SingleFrequencyState], max_level: f64) -> Vec<Vec<f64>> {
+    vec![
+        states
+            .iter()
+            .map(|state| state.latest_value() * DEBUG_FRAME_SCALE)
+            .collect(),
+        states
+            .iter()
+            .map(|state| state.activity_threshold_stats().threshold * DEBUG_FRAME_SCALE)
+            .collect(),
+        states
+            .iter()
+            .map(|state| state.latest_activity_level(max_level) / max_level)
+            .collect(),
+    ]
+}
+
+fn fraction_above_idle(states: &[SingleFrequencyState]) -> f64 {
+    states
+        .iter()
+        .filter(|state| state.latest_activity_level(f64::MAX) > 0.0)
+        .count() as f64
+        / states.len().max(1) as f64
+}
+
+pub struct NudftDetector {
+    parameters: SignalParameters,
+    bank: NudftBank,
+    frequency_states: Vec<SingleFrequencyState>,
+}
+
+impl NudftDetector {
+    pub fn new(parameters: SignalParameters) -> NudftDetector {
+        NudftDetector {
+            parameters,
+            bank: parameters.new_nudft_bank(),
+            frequency_states: (0..parameters.num_frequencies())
+                .map(|_| SingleFrequencyState::new(parameters))
+                .collect(),
+        }
+    }
+}
+
+impl ActivityDetector for NudftDetector {
+    fn kind(&self) -> DetectorKind {
+        DetectorKind::Nudft
+    }
+
+    fn observe(&mut self, value: f64, time: f64, channel: usize, idle: bool) -> f64 {
+        self.bank.push(time, value);
+        for (state, &norm) in self.frequency_states.iter_mut().zip(self.bank.norms()) {
+            state.observe_value(norm, idle);
+        }
+        let max_activity_contribution_per_frequency =
+            get_channel_variable(channel, "max_activity_contribution_per_frequency");
+        with_frequency_weights(channel, |weights| {
+            self.frequency_states
+                .iter()
+                .enumerate()
+                .map(|(index, state)| {
+                    let weight = weights.and_then(|w| w.get(index)).copied().unwrap_or(1.0);
+                    weight * state.latest_activity_level(max_activity_contribution_per_frequency)
+                })
+                .sum::<f64>()
+        })
+    }
+
+    fn debug_frame(&self, channel: usize) -> Vec<Vec<f64>> {
+        feature_rows(
+            &self.frequency_states,
+            get_channel_variable(channel, "max_activity_contribution_per_frequency"),
+        )
+    }
+
+    fn reset_windows(&mut self) {
+        self.bank = self.parameters.new_nudft_bank();
+        for state in &mut self.frequency_states {
+            state.reset_windows();
+        }
+    }
+
+    fn spectrum(&self) -> &[f64] {
+        self.bank.norms()
+    }
+
+    fn fraction_above_idle(&self) -> f64 {
+        fraction_above_idle(&self.frequency_states)
+    }
+
+    fn nudft_norms(&self) -> &[f64] {
+        self.bank.norms()
+    }
+
+    fn set_activity_threshold_stats(&mut self, stats: &[ActivityThresholdStats]) {
+        for (state, &stats) in self.frequency_states.iter_mut().zip(stats) {
+            state.set_activity_threshold_stats(stats);
+        }
+    }
+}
+
+pub struct RmsDetector {
+    window_size: usize,
+    values: Window<f64>,
+    squares: Window<f64>,
+    state: SingleFrequencyState,
+}
+
+impl RmsDetector {
+    pub fn new(parameters: SignalParameters) -> RmsDetector {
+        RmsDetector {
+            window_size: parameters.fft_window,
+            values: Window::new(parameters.fft_window),
+            squares: Window::new(parameters.fft_window),
+            state: SingleFrequencyState::new(parameters),
+        }
+    }
+}
+
+impl ActivityDetector for RmsDetector {
+    fn kind(&self) -> DetectorKind {
+        DetectorKind::Rms
+    }
+
+    fn observe(&mut self, value: f64, _time: f64, _channel: usize, idle: bool) -> f64 {
+        self.values.push(value);
+        self.squares.push(value * value);
+        let rms = (self.squares.mean() - self.values.mean().powi(2))
+            .max(0.0)
+            .sqrt();
+        self.state.observe_value(rms, idle);
+        self.state.latest_activity_level(f64::INFINITY)
+    }
+
+    fn debug_frame(&self, channel: usize) -> Vec<Vec<f64>> {
+        feature_rows(
+            std::slice::from_ref(&self.state),
+            get_channel_variable(channel, "activity_threshold"),
+        )
+    }
+
+    fn reset_windows(&mut self) {
+        self.values = Window::new(self.window_size);
+        self.squares = Window::new(self.window_size);
+        self.state.reset_windows();
+    }
+}
+
+pub struct TeagerKaiserDetector {
+    window_size: usize,
+    // the two samples before the latest one
+    previous: Option<(f64, f64)>,
+    energies: Window<f64>,
+    state: SingleFrequencyState,
+}
+
+impl TeagerKaiserDetector {
+    pub fn new(parameters: SignalParameters) -> TeagerKaiserDetector {
+        TeagerKaiserDetector {
+            window_size: parameters.fft_window,
+            previous: None,
+            energies: Window::new(parameters.fft_window),
+            state: SingleFrequencyState::new(parameters),
+        }
+    }
+}
+
+impl ActivityDetector for TeagerKaiserDetector {
+    fn kind(&self) -> DetectorKind {
+        DetectorKind::TeagerKaiser
+    }
+
+    fn observe(&mut self, value: f64, _time: f64, _channel: usize, idle: bool) -> f64 {
+        // the energy is of the middle sample, so it lags one sample behind
+        if let Some((before, middle)) = self.previous {
+            self.energies.push(middle * middle - before * value);
+            self.state
+                .observe_value(self.energies.mean().max(0.0).sqrt(), idle);
+            self.previous = Some((middle, value));
+        } else {
+            self.previous = Some((value, value));
+        }
+        self.state.latest_activity_level(f64::INFINITY)
+    }
+
+    fn debug_frame(&self, channel: usize) -> Vec<Vec<f64>> {
+        feature_rows(
+            std::slice::from_ref(&self.state),
+            get_channel_variable(channel, "activity_threshold"),
+        )
+    }
+
+    fn reset_windows(&mut self) {
+        self.previous = None;
+        self.energies = Window::new(self.window_size);
+        self.state.reset_windows();
+    }
+}
+
+const FILTER_BANK_BANDS: usize = 8;
+
+pub struct FilterBankDetector {
+    fft: Arc<dyn Fft<f64>>,
+    fft_size: usize,
+    samples_per_frame: usize,
+    num_samples_seen: usize,
+    samples: VecDeque<f64>,
+    window_function: Vec<f64>,
+    // FFT bins, not counting DC and Nyquist
+    bands: Vec<std::ops::Range<usize>>,
+    bin_amplitudes: Vec<f64>,
+    band_states: Vec<SingleFrequencyState>,
+    // the latest amplitude of each band, kept between FFTs
+    band_amplitudes: Vec<f64>,
+}
+
+impl FilterBankDetector {
+    pub fn new(parameters: SignalParameters) -> FilterBankDetector {
+        // bins at the same spacing as the DFT bank's frequencies
+        let fft_size = parameters.fft_window * 2;
+        let num_bins = parameters.num_frequencies();
+        // from 0 to `num_bins`, log-spaced but one lower, so that the lowest bin is in a band too
+        let mut edges: Vec<usize> = (0..=FILTER_BANK_BANDS)
+            .map(|band| {
+                ((num_bins + 1) as f64)
+                    .powf(band as f64 / FILTER_BANK_BANDS as f64)
+                    .round() as usize
+                    - 1
+            })
+            .collect();
+        edges.dedup();
+        let bands: Vec<_> = edges.windows(2).map(|pair| pair[0]..pair[1]).collect();
+        FilterBankDetector {
+            fft: FftPlanner::new().plan_fft_forward(fft_size),
+            fft_size,
+            samples_per_frame: parameters.samples_per_frequencies_frame,
+            num_samples_seen: 0,
+            samples: VecDeque::with_capacity(fft_size + 1),
+            window_function: (0..fft_size)
+                .map(|index| {
+                    0.5 - 0.5 * (std::f64::consts::TAU * index as f64 / fft_size as f64).cos()
+                })
+                .collect(),
+            bin_amplitudes: vec![0.0; num_bins],
+            band_states: bands
+                .iter()
+                .map(|_| SingleFrequencyState::new(parameters))
+                .collect(),
+            band_amplitudes: vec![0.0; bands.len()],
+            bands,
+        }
+    }
+
+    fn transform(&mut self) {
+        let mut buffer: Vec<Complex<f64>> = self
+            .samples
+            .iter()
+            .zip(&self.window_function)
+            .map(|(&sample, &weight)| Complex::new(sample * weight, 0.0))
+            .collect();
+        self.fft.process(&mut buffer);
+        // so that a sinusoid has the same amplitude as in the DFT bank
+        let scale = 1.0 / self.window_function.iter().sum::<f64>();
+        for (amplitude, bin) in self.bin_amplitudes.iter_mut().zip(&buffer[1..]) {
+            *amplitude = bin.norm() * scale;
+        }
+        for (band_amplitude, band) in self.band_amplitudes.iter_mut().zip(&self.bands) {
+            let powers = self.bin_amplitudes[band.clone()].iter().map(|a| a * a);
+            *band_amplitude = (powers.sum::<f64>() / band.len() as f64).sqrt();
+        }
+    }
+}
+
+impl ActivityDetector for FilterBankDetector {
+    fn kind(&self) -> DetectorKind {
+        DetectorKind::FilterBank
+    }
+
+    fn observe(&mut self, value: f64, _time: f64, channel: usize, idle: bool) -> f64 {
+        self.num_samples_seen += 1;
+        self.samples.push_back(value);
+        if self.samples.len() > self.fft_size {
+            self.samples.pop_front();
+        }
+        if self.samples.len() == self.fft_size
+            && self.num_samples_seen.is_multiple_of(self.samples_per_frame)
+        {
+            self.transform();
+        }
+        for (state, &amplitude) in self.band_states.iter_mut().zip(&self.band_amplitudes) {
+            state.observe_value(amplitude, idle);
+        }
+        let max_activity_contribution_per_frequency =
+            get_channel_variable(channel, "max_activity_contribution_per_frequency");
+        self.band_states
+            .iter()
+            .zip(&self.bands)
+            .map(|(state, band)| {
+                band.len() as f64
+                    * state.latest_activity_level(max_activity_contribution_per_frequency)
+            })
+            .sum()
+    }
+
+    fn debug_frame(&self, channel: usize) -> Vec<Vec<f64>> {
+        feature_rows(
+            &self.band_states,
+            get_channel_variable(channel, "max_activity_contribution_per_frequency"),
+        )
+    }
+
+    fn reset_windows(&mut self) {
+        self.samples.clear();
+        self.bin_amplitudes.fill(0.0);
+        self.band_amplitudes.fill(0.0);
+        for state in &mut self.band_states {
+            state.reset_windows();
+        }
+    }
+
+    fn spectrum(&self) -> &[f64] {
+        &self.bin_amplitudes
+    }
+
+    fn fraction_above_idle(&self) -> f64 {
+        fraction_above_idle(&self.band_states)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn every_detector_notices_a_burst_after_learning_rest() {
+        let sample_rate = 1020.0;
+        let activity_threshold = get_channel_variable(0, "activity_threshold");
+        for kind in DetectorKind::ALL {
+            let mut detector = new_detector(kind, SignalParameters::new(sample_rate));
+            let mut rng = StdRng::seed_from_u64(0);
+            let mut noise = || rng.gen_range(-0.5..0.5);
+            let mut index = 0;
+            let mut run = |detector: &mut Box<dyn ActivityDetector>, samples, amplitude, idle| {
+                let mut max_level: f64 = 0.0;
+                for _ in 0..samples {
+                    let time = index as f64 / sample_rate;
+                    let burst = amplitude * (time * std::f64::consts::TAU * 150.0).sin();
+                    let value = noise() * 0.01 + burst;
+                    max_level = max_level.max(detector.observe(value, time, 0, idle));
+                    index += 1;
+                }
+                max_level
+            };
+            run(&mut detector, 20000, 0.0, true);
+            let rest_level = run(&mut detector, 2000, 0.0, false);
+            let burst_level = run(&mut detector, 500, 0.2, false);
+            assert!(
+                rest_level < activity_threshold && burst_level > activity_threshold,
+                "{}: rest {}, burst {}",
+                kind,
+                rest_level,
+                burst_level
+            );
+        }
+    }
+
+    #[test]
+    fn filter_bank_bands_cover_every_bin() {
+        let parameters = SignalParameters::new(1020.0);
+        let mut detector = FilterBankDetector::new(parameters);
+        let num_bins = parameters.num_frequencies();
+        assert_eq!(detector.bands.first().unwrap().start, 0);
+        assert_eq!(detector.bands.last().unwrap().end, num_bins);
+        for pair in detector.bands.windows(2) {
+            assert_eq!(pair[0].end, pair[1].start);
+            assert!(pair[0].len() <= pair[1].len());
+        }
+
+        // a sinusoid right at the lowest bin's frequency
+        let frequency = parameters.sample_rate / detector.fft_size as f64;
+        for index in 0..detector.fft_size {
+            let time = index as f64 / parameters.sample_rate;
+            detector.observe(
+                (time * std::f64::consts::TAU * frequency).sin(),
+                time,
+                0,
+                true,
+            );
+        }
+        let loudest = |amplitudes: &[f64]| {
+            (0..amplitudes.len())
+                .max_by(|&a, &b| amplitudes[a].total_cmp(&amplitudes[b]))
+                .unwrap()
+        };
+        assert_eq!(loudest(&detector.bin_amplitudes), 0);
+        assert_eq!(loudest(&detector.band_amplitudes), 0);
+        assert!(
+            detector.band_amplitudes[0] > 0.4,
+            "{:?}",
+            detector.band_amplitudes
+        );
+    }
+}
@@ -0,0 +1,317 @@
+use crate::variables::{get_channel_variable, variables_generation};
+use crate::window::Window;
+use std::fmt;
+
+/*
+
+Motion artifacts: things that aren't muscle activity, but would look like it to the DFTs, like
+tapping the cable, moving the arm, or an electrode shifting on the skin. We look for three kinds:
+
+- Baseline swings: the signal, averaged over a short window, wandering away from its average over a
+  longer one. Muscle activity is fast and averages out over the short window; movement is slow and
+  doesn't.
+- Broadband impulses: a single spike, which shows up at every frequency equally, so the spectrum
+  is much flatter (geometric mean close to arithmetic mean) than muscle activity ever makes it,
+  while most frequencies are above their idle thresholds.
+- DC jumps: the average over the short window differing a lot from the average over the short
+  window just before it.
+
+The swings and jumps are measured in multiples of the signal's fast variation (its RMS after taking
+out the short-window average), so they mean the same thing whatever the gain of the electrodes is,
+and a strong contraction doesn't make its own slow parts count. Each check can be turned off by
+setting its variable to 0 (or the flatness to 1).
+
+While there's an artifact, and for `artifact_hold_time` afterwards, `Signal` doesn't let the channel
+become active, and the idle thresholds don't learn from anything around it, just like around
+activity.
+
+*/
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ArtifactKind {
+    BaselineSwing,
+    Impulse,
+    DcJump,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Artifact {
+    pub kind: ArtifactKind,
+    /// in multiples of the fast variation, except for impulses, where it's the spectral flatness
+    pub strength: f64,
+}
+
+impl fmt::Display for Artifact {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            ArtifactKind::BaselineSwing => write!(
+                f,
+                "baseline swing of {:.1} times the fast variation",
+                self.strength
+            ),
+            ArtifactKind::Impulse => write!(
+                f,
+                "broadband impulse (spectral flatness {:.2})",
+                self.strength
+            ),
+            ArtifactKind::DcJump => {
+                write!(
+                    f,
+                    "DC jump of {:.1} times the fast variation",
+                    self.strength
+                )
+            }
+        }
+    }
+}
+
+// that many of the frequencies have to be above their idle thresholds for a flat spectrum to count
+const IMPULSE_MIN_FRACTION_ABOVE_IDLE: f64 = 0.5;
+
+// the channel's `artifact_*` variables
+#[derive(Copy, Clone)]
+struct Thresholds {
+    impulse_flatness: f64,
+    dc_jump_ratio: f64,
+    baseline_swing_ratio: f64,
+}
+
+impl Thresholds {
+    fn for_channel(channel: usize) -> Thresholds {
+        Thresholds {
+            impulse_flatness: get_channel_variable(channel, "artifact_impulse_flatness"),
+            dc_jump_ratio: get_channel_variable(channel, "artifact_dc_jump_ratio"),
+            baseline_swing_ratio: get_channel_variable(channel, "artifact_baseline_swing_ratio"),
+        }
+    }
+}
+
+pub struct ArtifactDetector {
+    recent: Window<f64>,
+    // the values that dropped out of `recent`
+    before: Window<f64>,
+    long: Window<f64>,
+    recent_fast_squares: Window<f64>,
+    long_fast_squares: Window<f64>,
+    // read again whenever the variables change
+    thresholds: Option<(u64, Thresholds)>,
+}
+
+impl ArtifactDetector {
+    /// The window sizes are in samples.
+    pub fn new(short_window: usize, long_window: usize) -> ArtifactDetector {
+        ArtifactDetector {
+            recent: Window::new(short_window),
+            before: Window::new(short_window),
+            long: Window::new(long_window),
+            recent_fast_squares: Window::new(short_window),
+            long_fast_squares: Window::new(long_window),
+            thresholds: None,
+        }
+    }
+
+    /// Takes in one (line-noise filtered) sample, along with the latest DFT norms, and how many of
+    /// them are above their idle thresholds, and says whether it's part of an artifact.
+    pub fn observe(
+        &mut self,
+        value: f64,
+        nudft_norms: &[f64],
+        fraction_above_idle: f64,
+        channel: usize,
+    ) -> Option<Artifact> {
+        if let Some(dropped) = self.recent.push(value) {
+            self.before.push(dropped);
+        }
+        self.long.push(value);
+        let fast = value - self.recent.mean();
+        self.recent_fast_squares.push(fast * fast);
+        self.long_fast_squares.push(fast * fast);
+        if !self.long.is_full() {
+            return None;
+        }
+
+        let generation = variables_generation();
+        let thresholds = match self.thresholds {
+            Some((read_at, thresholds)) if read_at == generation => thresholds,
+            _ => {
+                let thresholds = Thresholds::for_channel(channel);
+                self.thresholds = Some((generation, thresholds));
+                thresholds
+            }
+        };
+
+        if fraction_above_idle >= IMPULSE_MIN_FRACTION_ABOVE_IDLE {
+            let flatness = spectral_flatness(nudft_norms);
+            if flatness > thresholds.impulse_flatness {
+                return Some(Artifact {
+                    kind: ArtifactKind::Impulse,
+                    strength: flatness,
+                });
+            }
+        }
+
+        let fast_variation = self
+            .recent_fast_squares
+            .mean()
+            .max(self.long_fast_squares.mean())
+            .sqrt()
+            .max(f64::MIN_POSITIVE);
+        let checks = [
+            (
+                ArtifactKind::DcJump,
+                thresholds.dc_jump_ratio,
+                self.recent.mean() - self.before.mean(),
+            ),
+            (
+                ArtifactKind::BaselineSwing,
+                thresholds.baseline_swing_ratio,
+                self.recent.mean() - self.long.mean(),
+            ),
+        ];
+        for (kind, ratio, difference) in checks {
+            let strength = difference.abs() / fast_variation;
+            if ratio > 0.0 && strength > ratio {
+                return Some(Artifact { kind, strength });
+            }
+        }
+        None
+    }
+}
+
+/// The geometric mean of the powers over their arithmetic mean: 1 if they're all the same.
+fn spectral_flatness(norms: &[f64]) -> f64 {
+    let powers = norms.iter().map(|norm| norm * norm + f64::MIN_POSITIVE);
+    let count = norms.len().max(1) as f64;
+    let log_mean = powers.clone().map(f64::ln).sum::<f64>() / count;
+    let mean = powers.sum::<f64>() / count;
+    log_mean.exp() / mean
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::variables::set_channel_variable;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    const SHORT_WINDOW: usize = 50;
+    const LONG_WINDOW: usize = 1000;
+    // a peaked spectrum, like muscle activity
+    const PEAKED: [f64; 8] = [0.1, 0.2, 3.0, 5.0, 2.0, 0.3, 0.1, 0.1];
+
+    /// What the detector says about each sample, on a channel without overrides unless the test
+    /// sets some, where the spectrum is `spectrum(index)`.
+    fn observe(
+        detector: &mut ArtifactDetector,
+        channel: usize,
+        values: impl IntoIterator<Item = f64>,
+        spectrum: impl Fn(usize) -> ([f64; 8], f64),
+    ) -> Vec<Option<Artifact>> {
+        values
+            .into_iter()
+            .enumerate()
+            .map(|(index, value)| {
+                let (norms, fraction_above_idle) = spectrum(index);
+                detector.observe(value, &norms, fraction_above_idle, channel)
+            })
+            .collect()
+    }
+
+    /// A repeatable stand-in for noise, from -0.5 to 0.5.
+    fn noise(seed: u64) -> impl Iterator<Item = f64> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        std::iter::repeat_with(move || rng.gen_range(-0.5..0.5))
+    }
+
+    fn kinds(artifacts: &[Option<Artifact>]) -> Vec<ArtifactKind> {
+        let mut kinds: Vec<ArtifactKind> = Vec::new();
+        for artifact in artifacts.iter().flatten() {
+            if kinds.last() != Some(&artifact.kind) {
+                kinds.push(artifact.kind);
+            }
+        }
+        kinds
+    }
+
+    fn quiet(_: usize) -> ([f64; 8], f64) {
+        (PEAKED, 0.0)
+    }
+
+    #[test]
+    fn noise_and_muscle_activity_are_not_artifacts() {
+        let mut detector = ArtifactDetector::new(SHORT_WINDOW, LONG_WINDOW);
+        let values = noise(1).take(3000);
+        assert_eq!(kinds(&observe(&mut detector, 1300, values, quiet)), vec![]);
+        // a burst is fast, and its spectrum is peaked
+        let values = noise(2)
+            .take(1000)
+            .enumerate()
+            .map(|(index, value)| value + (index as f64 * 0.7).sin() * 20.0);
+        let active = |_| (PEAKED, 1.0);
+        assert_eq!(kinds(&observe(&mut detector, 1300, values, active)), vec![]);
+    }
+
+    #[test]
+    fn spikes_are_impulses() {
+        let mut detector = ArtifactDetector::new(SHORT_WINDOW, LONG_WINDOW);
+        observe(&mut detector, 1301, noise(1).take(2000), quiet);
+        // a spike lights up every frequency equally
+        let values = noise(2)
+            .take(200)
+            .enumerate()
+            .map(|(index, value)| if index == 100 { 30.0 } else { value });
+        let spectrum = |index| match index {
+            100..=110 => ([4.0; 8], 1.0),
+            _ => quiet(index),
+        };
+        let artifacts = observe(&mut detector, 1301, values, spectrum);
+        assert_eq!(kinds(&artifacts), vec![ArtifactKind::Impulse]);
+        assert!(artifacts[100].is_some_and(|artifact| artifact.strength > 0.99));
+        assert!(artifacts[..100].iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn saturation_moves_the_baseline() {
+        let mut detector = ArtifactDetector::new(SHORT_WINDOW, LONG_WINDOW);
+        observe(&mut detector, 1302, noise(1).take(2000), quiet);
+        // the signal gets stuck at the rail, far from where it was
+        let values =
+            noise(2)
+                .take(3000)
+                .enumerate()
+                .map(|(index, value)| if index < 100 { value } else { 50.0 + value });
+        let artifacts = observe(&mut detector, 1302, values, quiet);
+        assert!(artifacts[..100].iter().all(Option::is_none));
+        // once the short window has mostly moved
+        assert!(artifacts[100..100 + 2 * SHORT_WINDOW]
+            .iter()
+            .any(Option::is_some));
+        assert!(kinds(&artifacts).contains(&ArtifactKind::BaselineSwing));
+        assert!(!kinds(&artifacts).contains(&ArtifactKind::Impulse));
+        // until the long window is used to the new baseline
+        assert!(artifacts[2500..].iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn changed_thresholds_are_used_right_away() {
+        let channel = 1303;
+        let jump = |index: usize, value: f64| {
+            if index % 2000 < 1000 {
+                value
+            } else {
+                50.0 + value
+            }
+        };
+        let mut detector = ArtifactDetector::new(SHORT_WINDOW, LONG_WINDOW);
+        let values = noise(1).take(4000).enumerate().map(|(i, v)| jump(i, v));
+        assert!(!kinds(&observe(&mut detector, channel, values, quiet)).is_empty());
+
+        set_channel_variable(channel, "artifact_dc_jump_ratio", Some(0.0)).unwrap();
+        set_channel_variable(channel, "artifact_baseline_swing_ratio", Some(0.0)).unwrap();
+        let values = noise(2).take(4000).enumerate().map(|(i, v)| jump(i, v));
+        let artifacts = observe(&mut detector, channel, values, quiet);
+        set_channel_variable(channel, "artifact_dc_jump_ratio", None).unwrap();
+        set_channel_variable(channel, "artifact_baseline_swing_ratio", None).unwrap();
+        assert_eq!(kinds(&artifacts), vec![]);
+    }
+}
@@ -1,22 +1,18 @@
+use crate::frame_parser::FrameParser;
+use crate::sample_source::SourceEvent;
+use crate::serial_port_communication::LINK_STATISTICS_INTERVAL;
 use btleplug::api::{
     Central, CentralEvent, CharPropFlags, Manager as _, Peripheral as _, ScanFilter,
 };
 use btleplug::platform::{Manager, Peripheral};
+use futures::channel::mpsc::UnboundedReceiver;
 use futures::stream::StreamExt;
-use futures::{SinkExt, Stream};
-use std::convert::TryInto;
-use std::time::Duration;
+use futures::SinkExt;
+use std::time::{Duration, Instant};
 use tokio::task;
 use tokio::time::timeout;
 
-#[derive(Debug)]
-pub struct ReportFromServer {
-    pub server_run_id: u64,
-    pub first_sample_index: u64,
-    pub samples: Vec<[u16; 4]>,
-}
-
-pub fn messages_from_server() -> impl Stream<Item = ReportFromServer> {
+pub fn messages_from_server(name: String) -> UnboundedReceiver<SourceEvent> {
     let (mut sender, receiver) = futures::channel::mpsc::unbounded();
     task::spawn(async move {
         let manager = Manager::new().await.unwrap();
@@ -35,7 +31,7 @@ pub fn messages_from_server() -> impl Stream<Item = ReportFromServer> {
                     println!("DeviceDiscovered: {:?}", id);
                     let p = central.peripheral(&id).await.unwrap();
                     if let Ok(Some(pr)) = p.properties().await {
-                        if pr.local_name.as_deref() == Some("ELI_EMG_SERVER") {
+                        if pr.local_name.as_deref() == Some(name.as_str()) {
                             server_id = id;
                             server_peripheral = p;
                             break;
@@ -61,40 +57,40 @@ pub fn messages_from_server() -> impl Stream<Item = ReportFromServer> {
         let mut stream = server_peripheral.notifications().await.unwrap();
         let _ = connect_and_subscribe(&server_peripheral).await;
 
+        // each notification should be exactly one frame, but check it the same way as a byte stream anyway
+        let mut parser = FrameParser::new();
+        let mut last_statistics_sent = Instant::now();
         loop {
             match timeout(Duration::from_secs(1), stream.next()).await {
                 Ok(Some(notification)) => {
-                    let server_run_id =
-                        u64::from_le_bytes((&notification.value[8..16]).try_into().unwrap());
-                    let first_sample_index =
-                        u64::from_le_bytes((&notification.value[16..24]).try_into().unwrap());
-                    let samples = notification.value[26..]
-                        .chunks_exact(6)
-                        .map(|chunk| {
-                            [
-                                (u16::from(chunk[0]) << 4) + (u16::from(chunk[4]) >> 4),
-                                (u16::from(chunk[1]) << 4) + (u16::from(chunk[4]) & 15),
-                                (u16::from(chunk[2]) << 4) + (u16::from(chunk[5]) >> 4),
-                                (u16::from(chunk[3]) << 4) + (u16::from(chunk[5]) & 15),
-                            ]
-                        })
-                        .collect();
-                    let report = ReportFromServer {
-                        server_run_id,
-                        first_sample_index,
-                        samples,
-                    };
-
-                    if sender.send(report).await.is_err() {
-                        break;
+                    parser.push_bytes(&notification.value);
+                    while let Some(report) = parser.next_report() {
+                        if sender.send(SourceEvent::Report(report)).await.is_err() {
+                            return;
+                        }
+                    }
+                    if last_statistics_sent.elapsed() >= LINK_STATISTICS_INTERVAL {
+                        last_statistics_sent = Instant::now();
+                        if sender
+                            .send(SourceEvent::LinkStatistics(parser.statistics()))
+                            .await
+                            .is_err()
+                        {
+                            break;
+                        }
                     }
                 }
                 _ => {
                     eprintln!("Timeout...?");
                     if let Ok(p) = central.peripheral(&server_id).await {
                         server_peripheral = p;
+                        parser.discard_partial_frame();
                         stream = server_peripheral.notifications().await.unwrap();
-                        let _ = connect_and_subscribe(&server_peripheral).await;
+                        if connect_and_subscribe(&server_peripheral).await.is_ok()
+                            && sender.send(SourceEvent::Reconnected).await.is_err()
+                        {
+                            break;
+                        }
                     }
                 }
             }
@@ -0,0 +1,631 @@
+use crate::crosstalk::CrossTalk;
+use crate::gestures::{ChannelGesture, GestureMapping};
+use crate::signal::{ActivityThresholdStats, Signal};
+use crate::variables::{get_channel_variable, variable_info};
+use crate::weight_learning::{
+    frequency_levels, learn_frequency_weights, record_levels, rest_threshold_stats, weighted_sum,
+    RECORD_INTERVAL,
+};
+use anyhow::bail;
+use ordered_float::OrderedFloat;
+use serde::{Deserialize, Serialize};
+
+/*
+
+A guided calibration, so that the thresholds are sensible right away, instead of only after a few
+seconds of idle signal, and so that each channel's activity level listens to the frequencies its
+muscle actually produces.
+
+The GUI prompts the user to rest, then to contract each channel's muscle in turn, then to perform
+each gesture rule that's about channels being active (not taps). Meanwhile we take each channel's
+DFT norms at every frequency, every `RECORD_INTERVAL`, skipping the first `SETTLING_TIME` of each
+step while the user reacts to the prompt. Afterwards, for each channel:
+
+- The idle thresholds of each frequency come from the rest step, the same way `Signal` learns them.
+- Each frequency's weight is learned from the rest and contraction steps (see `weight_learning.rs`),
+  or they're all 1 if it can't be.
+- With those, `activity_threshold` goes halfway between the rest level (95th percentile) and the
+  contraction level (median), if the contraction is higher at all.
+
+Then the cross-talk between channels (see `crosstalk.rs`): while channel j's muscle is contracted,
+channel i's mean activity level rises above its rest level by some fraction of how much channel j's
+own level rises, and that fraction (if it's more than `MIN_CROSSTALK`) is what channel i picks up
+from channel j.
+
+Only channels whose `activity_detector` is the DFT bank are calibrated (see
+`activity_detectors.rs`), since the others don't have per-frequency norms to take; the rest keep
+their thresholds, and are left out of the cross-talk.
+
+The results show the separation of each channel (in standard deviations), and for each gesture,
+how much of the time its rule would have held while it was performed, and while resting. The user
+can then apply them or throw them away.
+
+*/
+
+const SETTLING_TIME: f64 = 1.0;
+const REST_DURATION: f64 = 6.0;
+const CONTRACTION_DURATION: f64 = 4.0;
+const GESTURE_DURATION: f64 = 5.0;
+const REST_PERCENTILE: f64 = 0.95;
+// less than this is noise, not worth compensating for
+const MIN_CROSSTALK: f64 = 0.01;
+
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub enum CalibrationStep {
+    Rest,
+    Contract {
+        channel: usize,
+    },
+    /// the rule with this index in the gesture mapping
+    Gesture {
+        rule: usize,
+    },
+}
+
+/// What the GUI shows.
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub enum CalibrationState {
+    Idle,
+    Prompt {
+        step_index: usize,
+        num_steps: usize,
+        instruction: String,
+        /// in seconds
+        duration: f64,
+    },
+    Finished(CalibrationResults),
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct CalibrationResults {
+    pub channels: Vec<ChannelCalibration>,
+    pub gestures: Vec<GestureCalibration>,
+    pub crosstalk: CrossTalk,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct ChannelCalibration {
+    pub channel: usize,
+    /// activity levels with the new weights, in the same units as `activity_threshold`
+    pub rest_level: f64,
+    pub contraction_level: f64,
+    /// how far apart the activity levels during rest and contraction are, in standard deviations
+    pub separation: f64,
+    /// `None` if the contraction couldn't be told apart from rest, so the threshold should stay as it is
+    pub activity_threshold: Option<f64>,
+    pub frequency_weights: Vec<f64>,
+    #[serde(skip)]
+    pub threshold_stats: Vec<ActivityThresholdStats>,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct GestureCalibration {
+    pub rule: usize,
+    /// the fraction of the time that the rule would have held while the gesture was performed
+    pub recognized: f64,
+    /// and while resting
+    pub false_alarms: f64,
+}
+
+/// Something the supervisor has to tell the GUI about.
+pub enum CalibrationProgress {
+    NextStep,
+    Finished,
+}
+
+pub struct Calibration {
+    steps: Vec<CalibrationStep>,
+    mapping: GestureMapping,
+    num_channels: usize,
+    current_step: usize,
+    // set by the first sample of the step
+    step_start: Option<f64>,
+    // indexed by channel
+    last_recorded: Vec<f64>,
+    // indexed by step, then channel, then record, then frequency
+    records: Vec<Vec<Vec<Vec<f64>>>>,
+}
+
+/// The channels that have to be active, and inactive, for a rule to hold, or `None` if it also
+/// waits for a tap, which we can't judge from activity levels.
+fn rule_channels(mapping: &GestureMapping, rule: usize) -> Option<(Vec<usize>, Vec<usize>)> {
+    let condition = &mapping.rules[rule].when;
+    let mut active = condition.active.clone();
+    match condition.gesture {
+        None => {}
+        Some(ChannelGesture::Hold(channel)) => active.push(channel),
+        Some(_) => return None,
+    }
+    Some((active, condition.inactive.clone()))
+}
+
+fn channel_list(channels: &[usize]) -> String {
+    let list: Vec<String> = channels.iter().map(usize::to_string).collect();
+    list.join(", ")
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len().max(1) as f64
+}
+
+fn variance(values: &[f64]) -> f64 {
+    let mean = mean(values);
+    values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / values.len().max(1) as f64
+}
+
+fn percentile(values: &[f64], fraction: f64) -> f64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_by_key(|&v| OrderedFloat(v));
+    sorted
+        .get(((sorted.len() as f64 * fraction) as usize).min(sorted.len().saturating_sub(1)))
+        .copied()
+        .unwrap_or(0.0)
+}
+
+/// `difference` in units of the pooled standard deviation, but never infinite.
+fn separation(difference: f64, a: &[f64], b: &[f64]) -> f64 {
+    difference / ((variance(a) + variance(b)) / 2.0).sqrt().max(1e-9)
+}
+
+impl Calibration {
+    /// Steps for every channel there is now, and every rule in `mapping` that can be judged.
+    pub fn new(num_channels: usize, mapping: &GestureMapping) -> anyhow::Result<Calibration> {
+        if num_channels == 0 {
+            bail!("there aren't any channels to calibrate yet");
+        }
+        let steps: Vec<CalibrationStep> = std::iter::once(CalibrationStep::Rest)
+            .chain((0..num_channels).map(|channel| CalibrationStep::Contract { channel }))
+            .chain(
+                (0..mapping.rules.len())
+                    .filter(|&rule| {
+                        rule_channels(mapping, rule).is_some_and(|(active, inactive)| {
+                            active.iter().chain(&inactive).all(|&c| c < num_channels)
+                        })
+                    })
+                    .map(|rule| CalibrationStep::Gesture { rule }),
+            )
+            .collect();
+        Ok(Calibration {
+            records: vec![vec![Vec::new(); num_channels]; steps.len()],
+            steps,
+            mapping: mapping.clone(),
+            num_channels,
+            current_step: 0,
+            step_start: None,
+            last_recorded: vec![f64::MIN; num_channels],
+        })
+    }
+
+    pub fn state(&self) -> CalibrationState {
+        let (instruction, duration) = match &self.steps[self.current_step] {
+            CalibrationStep::Rest => (
+                "Relax all of your muscles, and keep still".to_string(),
+                REST_DURATION,
+            ),
+            CalibrationStep::Contract { channel } => (
+                format!(
+                    "Firmly contract the muscle under channel {}, and hold it, keeping the others relaxed",
+                    channel
+                ),
+                CONTRACTION_DURATION,
+            ),
+            &CalibrationStep::Gesture { rule } => {
+                let (active, inactive) = rule_channels(&self.mapping, rule).unwrap();
+                let mut instruction = format!(
+                    "Repeatedly do the gesture for rule {} ({:?}): activate channel(s) {}",
+                    rule,
+                    self.mapping.rules[rule].action,
+                    channel_list(&active)
+                );
+                if !inactive.is_empty() {
+                    instruction += &format!(" while keeping {} relaxed", channel_list(&inactive));
+                }
+                (instruction, GESTURE_DURATION)
+            }
+        };
+        CalibrationState::Prompt {
+            step_index: self.current_step,
+            num_steps: self.steps.len(),
+            instruction,
+            duration,
+        }
+    }
+
+    fn step_duration(&self) -> f64 {
+        match self.steps[self.current_step] {
+            CalibrationStep::Rest => REST_DURATION,
+            CalibrationStep::Contract { .. } => CONTRACTION_DURATION,
+            CalibrationStep::Gesture { .. } => GESTURE_DURATION,
+        }
+    }
+
+    /// Takes in one sample's worth of one server's signals. `first_channel` is the number of the
+    /// server's first channel, and `time` is the sample's local time.
+    pub fn observe(
+        &mut self,
+        signals: &[Signal],
+        first_channel: usize,
+        time: f64,
+    ) -> Option<CalibrationProgress> {
+        let step_start = *self.step_start.get_or_insert(time);
+        if time >= step_start + SETTLING_TIME {
+            for (index, signal) in signals.iter().enumerate() {
+                let channel = first_channel + index;
+                if channel >= self.num_channels
+                    || time < self.last_recorded[channel] + RECORD_INTERVAL
+                {
+                    continue;
+                }
+                self.last_recorded[channel] = time;
+                let norms: Vec<f64> = signal.nudft_norms().collect();
+                // channels using other detectors don't have any
+                if !norms.is_empty() {
+                    self.records[self.current_step][channel].push(norms);
+                }
+            }
+        }
+        if time < step_start + self.step_duration() {
+            return None;
+        }
+        self.current_step += 1;
+        self.step_start = None;
+        Some(if self.current_step == self.steps.len() {
+            CalibrationProgress::Finished
+        } else {
+            CalibrationProgress::NextStep
+        })
+    }
+
+    fn step_records(&self, step: &CalibrationStep, channel: usize) -> &[Vec<f64>] {
+        let index = self.steps.iter().position(|s| s == step).unwrap();
+        &self.records[index][channel]
+    }
+
+    fn calibrate_channel(&self, channel: usize) -> Option<ChannelCalibration> {
+        let rest = self.step_records(&CalibrationStep::Rest, channel);
+        let contraction = self.step_records(&CalibrationStep::Contract { channel }, channel);
+        if contraction.is_empty() {
+            return None;
+        }
+        let threshold_stats = rest_threshold_stats(rest)?;
+        let max_contribution =
+            get_channel_variable(channel, "max_activity_contribution_per_frequency");
+        let rest_frequency_levels = frequency_levels(rest, &threshold_stats, max_contribution);
+        let contraction_frequency_levels =
+            frequency_levels(contraction, &threshold_stats, max_contribution);
+        let frequency_weights = learn_frequency_weights(
+            &rest_frequency_levels,
+            &contraction_frequency_levels,
+            get_channel_variable(channel, "activity_threshold"),
+        )
+        .unwrap_or_else(|| vec![1.0; threshold_stats.len()]);
+
+        let aggregate = |levels: &[Vec<f64>]| -> Vec<f64> {
+            levels
+                .iter()
+                .map(|levels| weighted_sum(levels, &frequency_weights))
+                .collect()
+        };
+        let (rest_levels, contraction_levels) = (
+            aggregate(&rest_frequency_levels),
+            aggregate(&contraction_frequency_levels),
+        );
+        let rest_level = percentile(&rest_levels, REST_PERCENTILE);
+        let contraction_level = percentile(&contraction_levels, 0.5);
+        let info = variable_info()
+            .iter()
+            .find(|info| info.name == "activity_threshold")
+            .unwrap();
+        Some(ChannelCalibration {
+            channel,
+            rest_level,
+            contraction_level,
+            separation: separation(
+                mean(&contraction_levels) - mean(&rest_levels),
+                &rest_levels,
+                &contraction_levels,
+            ),
+            activity_threshold: (contraction_level > rest_level)
+                .then(|| ((rest_level + contraction_level) / 2.0).clamp(info.min, info.max)),
+            frequency_weights,
+            threshold_stats,
+        })
+    }
+
+    /// How much of each calibrated channel's activity level comes from each other calibrated
+    /// channel, with channel activity levels given by `level(channel, record)`.
+    fn estimate_crosstalk(
+        &self,
+        channels: &[ChannelCalibration],
+        level: impl Fn(usize, &[f64]) -> f64,
+    ) -> CrossTalk {
+        let mean_level = |step: &CalibrationStep, channel: usize| {
+            let levels: Vec<f64> = self
+                .step_records(step, channel)
+                .iter()
+                .map(|record| level(channel, record))
+                .collect();
+            mean(&levels)
+        };
+        let rises: Vec<Vec<f64>> = channels
+            .iter()
+            .map(|picker| {
+                let rest_level = mean_level(&CalibrationStep::Rest, picker.channel);
+                channels
+                    .iter()
+                    .map(|source| {
+                        mean_level(
+                            &CalibrationStep::Contract {
+                                channel: source.channel,
+                            },
+                            picker.channel,
+                        ) - rest_level
+                    })
+                    .collect()
+            })
+            .collect();
+        let mut crosstalk = CrossTalk::new();
+        for (i, picker) in channels.iter().enumerate() {
+            for (j, source) in channels.iter().enumerate() {
+                let own_rise = rises[j][j];
+                if i == j || own_rise <= 0.0 {
+                    continue;
+                }
+                let fraction = (rises[i][j] / own_rise).clamp(0.0, 1.0);
+                if fraction > MIN_CROSSTALK {
+                    crosstalk
+                        .entry(picker.channel)
+                        .or_default()
+                        .insert(source.channel, fraction);
+                }
+            }
+        }
+        crosstalk
+    }
+
+    /// The fraction of the records in `step` during which `rule` would have held, if each channel's
+    /// activity level were `level(channel, record)`.
+    fn rule_held_fraction(
+        &self,
+        rule: usize,
+        step: &CalibrationStep,
+        threshold: impl Fn(usize) -> f64,
+        level: impl Fn(usize, &[f64]) -> f64,
+    ) -> f64 {
+        let (active, inactive) = rule_channels(&self.mapping, rule).unwrap();
+        // each channel is recorded separately, so line them up by count
+        let num_records = active
+            .iter()
+            .chain(&inactive)
+            .map(|&channel| self.step_records(step, channel).len())
+            .min()
+            .unwrap_or(0);
+        let held = (0..num_records)
+            .filter(|&index| {
+                let above = |channel: usize| {
+                    level(channel, &self.step_records(step, channel)[index]) > threshold(channel)
+                };
+                active.iter().all(|&c| above(c)) && !inactive.iter().any(|&c| above(c))
+            })
+            .count();
+        held as f64 / num_records.max(1) as f64
+    }
+
+    pub fn results(&self) -> CalibrationResults {
+        let channels: Vec<ChannelCalibration> = (0..self.num_channels)
+            .filter_map(|channel| self.calibrate_channel(channel))
+            .collect();
+        let calibrated = |channel: usize| channels.iter().find(|c| c.channel == channel);
+        let threshold = |channel: usize| {
+            calibrated(channel)
+                .and_then(|c| c.activity_threshold)
+                .unwrap_or_else(|| get_channel_variable(channel, "activity_threshold"))
+        };
+        // channels that couldn't be calibrated count as never active
+        let level = |channel: usize, record: &[f64]| {
+            calibrated(channel).map_or(0.0, |c| {
+                let max_contribution =
+                    get_channel_variable(channel, "max_activity_contribution_per_frequency");
+                let levels = record_levels(record, &c.threshold_stats, max_contribution);
+                weighted_sum(&levels, &c.frequency_weights)
+            })
+        };
+        let gestures = self
+            .steps
+            .iter()
+            .filter_map(|step| match step {
+                &CalibrationStep::Gesture { rule } => Some(GestureCalibration {
+                    rule,
+                    recognized: self.rule_held_fraction(rule, step, threshold, level),
+                    false_alarms: self.rule_held_fraction(
+                        rule,
+                        &CalibrationStep::Rest,
+                        threshold,
+                        level,
+                    ),
+                }),
+                _ => None,
+            })
+            .collect();
+        let crosstalk = self.estimate_crosstalk(&channels, level);
+        CalibrationResults {
+            channels,
+            gestures,
+            crosstalk,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gestures::{Action, Condition, GestureRule};
+    use crate::signal::MainsFrequency;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::f64::consts::TAU;
+
+    // a power of 2, so that the sample times add up exactly
+    const SAMPLE_RATE: f64 = 1024.0;
+
+    fn click(
+        active: Vec<usize>,
+        inactive: Vec<usize>,
+        gesture: Option<ChannelGesture>,
+    ) -> GestureRule {
+        GestureRule {
+            when: Condition {
+                active,
+                inactive,
+                held_for: 0.0,
+                gesture,
+            },
+            action: Action::Click {
+                button: Default::default(),
+            },
+            toggle: false,
+        }
+    }
+
+    /// Rest, contract each of the 2 channels, and then rule 0; the tap and the channel that doesn't
+    /// exist can't be calibrated.
+    fn mapping() -> GestureMapping {
+        GestureMapping {
+            rules: vec![
+                click(vec![0], vec![1], None),
+                click(vec![], vec![], Some(ChannelGesture::Tap(1))),
+                click(vec![5], vec![], None),
+            ],
+        }
+    }
+
+    /// Feeds the calibration 2 channels of noise, in which `contracting(channel, time)` adds
+    /// muscle-like oscillations, until it finishes. Returns the sample indices at which it said
+    /// it moved on, and the index of the step it was on by then.
+    fn run(
+        calibration: &mut Calibration,
+        contracting: impl Fn(usize, f64) -> bool,
+    ) -> Vec<(usize, usize)> {
+        let mut signals: Vec<Signal> = (0..2)
+            .map(|_| Signal::new(SAMPLE_RATE, MainsFrequency::Fixed(60.0)))
+            .collect();
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut noise = || rng.gen_range(-0.5..0.5);
+        let mut progress = Vec::new();
+        for index in 0.. {
+            let time = index as f64 / SAMPLE_RATE;
+            for (channel, signal) in signals.iter_mut().enumerate() {
+                let mut value = 2048.0 + noise() * 16.0;
+                if contracting(channel, time) {
+                    for frequency in [80.0, 110.0, 170.0] {
+                        value += (time * TAU * frequency).sin() * 200.0;
+                    }
+                }
+                signal.receive_raw(value, time, channel, |_| {});
+            }
+            match calibration.observe(&signals, 0, time) {
+                None => {}
+                Some(CalibrationProgress::NextStep) => {
+                    let CalibrationState::Prompt { step_index, .. } = calibration.state() else {
+                        panic!("calibration stopped prompting");
+                    };
+                    progress.push((index, step_index));
+                }
+                Some(CalibrationProgress::Finished) => {
+                    progress.push((index, calibration.steps.len()));
+                    return progress;
+                }
+            }
+        }
+        unreachable!()
+    }
+
+    #[test]
+    fn steps_follow_each_other() {
+        let mut calibration = Calibration::new(2, &mapping()).unwrap();
+        assert_eq!(
+            calibration.steps,
+            vec![
+                CalibrationStep::Rest,
+                CalibrationStep::Contract { channel: 0 },
+                CalibrationStep::Contract { channel: 1 },
+                CalibrationStep::Gesture { rule: 0 },
+            ]
+        );
+        let CalibrationState::Prompt {
+            step_index,
+            num_steps,
+            duration,
+            ..
+        } = calibration.state()
+        else {
+            panic!("calibration isn't prompting");
+        };
+        assert_eq!((step_index, num_steps, duration), (0, 4, REST_DURATION));
+
+        // each step starts on the sample after the previous one ended
+        let samples = |seconds: f64| (seconds * SAMPLE_RATE) as usize;
+        let rest_end = samples(REST_DURATION);
+        let contract_0_end = rest_end + 1 + samples(CONTRACTION_DURATION);
+        let contract_1_end = contract_0_end + 1 + samples(CONTRACTION_DURATION);
+        let gesture_end = contract_1_end + 1 + samples(GESTURE_DURATION);
+        assert_eq!(
+            run(&mut calibration, |_, _| false),
+            vec![
+                (rest_end, 1),
+                (contract_0_end, 2),
+                (contract_1_end, 3),
+                (gesture_end, 4)
+            ]
+        );
+    }
+
+    #[test]
+    fn contractions_set_the_thresholds_and_gestures_are_recognized() {
+        let mut calibration = Calibration::new(2, &mapping()).unwrap();
+        // channel 0 during its contraction and the gesture, channel 1 during its contraction
+        let contract_0 = REST_DURATION..REST_DURATION + CONTRACTION_DURATION;
+        let contract_1 = contract_0.end..contract_0.end + CONTRACTION_DURATION;
+        let gesture = contract_1.end..contract_1.end + GESTURE_DURATION;
+        run(&mut calibration, |channel, time| match channel {
+            0 => contract_0.contains(&time) || gesture.contains(&time),
+            _ => contract_1.contains(&time),
+        });
+
+        let results = calibration.results();
+        assert_eq!(results.channels.len(), 2);
+        for channel in &results.channels {
+            assert!(channel.contraction_level > channel.rest_level);
+            assert!(channel.separation > 3.0, "{:?}", channel.separation);
+            let threshold = channel.activity_threshold.unwrap();
+            assert!(channel.rest_level < threshold && threshold < channel.contraction_level);
+        }
+        assert_eq!(results.gestures.len(), 1);
+        let gesture = &results.gestures[0];
+        assert_eq!(gesture.rule, 0);
+        assert!(gesture.recognized > 0.8, "{:?}", gesture);
+        assert!(gesture.false_alarms < 0.1, "{:?}", gesture);
+        // the channels don't pick each other up
+        assert_eq!(results.crosstalk, CrossTalk::new());
+    }
+
+    #[test]
+    fn channels_without_data_are_left_out() {
+        assert!(Calibration::new(0, &mapping()).is_err());
+
+        let mut calibration = Calibration::new(2, &mapping()).unwrap();
+        // channel 0 never shows up, e.g. because its server went away
+        let signal = Signal::new(SAMPLE_RATE, MainsFrequency::Fixed(60.0));
+        let mut index = 0;
+        while !matches!(
+            calibration.observe(std::slice::from_ref(&signal), 1, index as f64 / SAMPLE_RATE),
+            Some(CalibrationProgress::Finished)
+        ) {
+            index += 1;
+        }
+        let results = calibration.results();
+        assert!(results.channels.iter().all(|c| c.channel == 1));
+        assert_eq!(results.gestures.len(), 1);
+        assert_eq!(results.gestures[0].recognized, 0.0);
+    }
+}
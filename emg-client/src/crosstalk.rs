@@ -0,0 +1,108 @@
+use anyhow::bail;
+use std::collections::BTreeMap;
+
+/*
+
+A contracting muscle doesn't only show up on its own channel's electrodes; a hard click on
+channel 2 can make the scroll channels next to it look active too. A `CrossTalk` says, for each
+channel, what fraction of each other channel's activity level it picks up, e.g.
+
+{ "0": { "2": 0.3 }, "1": { "2": 0.25 } }
+
+means that channel 0 picks up 30% of channel 2's activity level, and channel 1 picks up 25%.
+Before deciding whether a channel is active, that much is subtracted from its activity level.
+The other channels' levels are the ones before their own compensation, and channels of other
+servers, whose samples don't arrive at quite the same time, count with their latest levels.
+
+The calibration estimates it, from how much each channel's activity rises while each other
+channel's muscle is contracted (see `calibration.rs`), and it's kept in the profile.
+
+*/
+
+/// By the channel that picks it up, then the channel it comes from.
+pub type CrossTalk = BTreeMap<usize, BTreeMap<usize, f64>>;
+
+pub fn validate_crosstalk(crosstalk: &CrossTalk) -> anyhow::Result<()> {
+    for (&channel, sources) in crosstalk {
+        for (&source, &fraction) in sources {
+            if source == channel {
+                bail!("channel {} can't pick up cross-talk from itself", channel);
+            }
+            if !(0.0..=1.0).contains(&fraction) {
+                bail!(
+                    "channel {} picks up {} of channel {}, but it has to be from 0 to 1",
+                    channel,
+                    fraction,
+                    source
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// How much of `channel`'s activity level is presumably picked up from the other channels, given
+/// all of their (uncompensated) activity levels.
+pub fn crosstalk_level(crosstalk: &CrossTalk, channel: usize, activity_levels: &[f64]) -> f64 {
+    crosstalk.get(&channel).map_or(0.0, |sources| {
+        sources
+            .iter()
+            .map(|(&source, fraction)| {
+                fraction * activity_levels.get(source).copied().unwrap_or(0.0)
+            })
+            .sum()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_crosstalk_is_rejected() {
+        validate_crosstalk(&CrossTalk::new()).unwrap();
+        validate_crosstalk(&CrossTalk::from([(
+            0,
+            BTreeMap::from([(2, 0.0), (3, 1.0)]),
+        )]))
+        .unwrap();
+        for invalid in [
+            CrossTalk::from([(2, BTreeMap::from([(2, 0.3)]))]),
+            CrossTalk::from([(0, BTreeMap::from([(2, -0.1)]))]),
+            CrossTalk::from([(0, BTreeMap::from([(2, 1.5)]))]),
+            CrossTalk::from([(0, BTreeMap::from([(2, f64::NAN)]))]),
+        ] {
+            assert!(validate_crosstalk(&invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn levels_are_picked_up_from_each_source() {
+        let crosstalk = CrossTalk::from([
+            (0, BTreeMap::from([(2, 0.5), (3, 0.25)])),
+            (1, BTreeMap::from([(7, 0.5)])),
+        ]);
+        let levels = [1.0, 2.0, 4.0, 8.0];
+        assert_eq!(crosstalk_level(&crosstalk, 0, &levels), 4.0);
+        // channels that don't exist (yet) don't contribute anything
+        assert_eq!(crosstalk_level(&crosstalk, 1, &levels), 0.0);
+        // nor do channels that don't pick up anything
+        assert_eq!(crosstalk_level(&crosstalk, 2, &levels), 0.0);
+        assert_eq!(crosstalk_level(&crosstalk, 9, &levels), 0.0);
+    }
+
+    #[test]
+    fn a_scaled_copy_is_subtracted_away() {
+        // channel 1 picks up 40% of channel 0, and only sometimes has activity of its own
+        let crosstalk = CrossTalk::from([(1, BTreeMap::from([(0, 0.4)]))]);
+        for step in 0..20 {
+            let level_0 = step as f64 * 7.5;
+            let own_level_1 = if step % 4 == 0 { 30.0 } else { 0.0 };
+            let levels = [level_0, 0.4 * level_0 + own_level_1];
+            let compensated = levels[1] - crosstalk_level(&crosstalk, 1, &levels);
+            assert!((compensated - own_level_1).abs() < 1e-9, "{}", compensated);
+            // and channel 0 doesn't pick anything up from channel 1
+            assert_eq!(crosstalk_level(&crosstalk, 0, &levels), 0.0);
+        }
+    }
+}
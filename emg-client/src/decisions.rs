@@ -0,0 +1,781 @@
+use crate::artifacts::Artifact;
+use crate::crosstalk::{crosstalk_level, validate_crosstalk, CrossTalk};
+use crate::follower::MessageToFollower;
+use crate::gestures::{Action, ChannelGesture, Condition, GestureMapping, GestureRule};
+use crate::signal::Signal;
+use crate::taps::{TapEvent, TapRecognizer, TapTimings};
+use crate::timeline::{GapHandling, SampleGap, TimelineSample};
+use crate::variables::{get_channel_variable, get_variable, variables_generation};
+use crate::webserver::{FrequenciesFrame, HistoryFrame};
+
+/// What was going on outside of the EMG signals when a sample arrived.
+#[derive(Copy, Clone, Debug)]
+pub struct DecisionContext {
+    pub enabled: bool,
+    pub recently_moved: bool,
+    pub anywhere_near_recently_moved: bool,
+}
+
+/// Receives everything that `DecisionMaker` decides, so that the same decisions can be
+/// sent to a real follower by the supervisor, or just printed out during a replay.
+/// Channels are numbered within their server here.
+pub trait DecisionObserver {
+    fn history_frame(&mut self, _server_index: usize, _channel: usize, _frame: HistoryFrame) {}
+    fn frequencies_frame(
+        &mut self,
+        _server_index: usize,
+        _channel: usize,
+        _frame: FrequenciesFrame,
+    ) {
+    }
+    fn activity_changed(&mut self, _server_index: usize, _channel: usize, _active: bool) {}
+    /// the channel would have become active, but there was a motion artifact; this happens for
+    /// every frame of activity that the artifact holds back
+    fn activation_suppressed(
+        &mut self,
+        _server_index: usize,
+        _channel: usize,
+        _artifact: Artifact,
+    ) {
+    }
+    fn tap_event(&mut self, _server_index: usize, _channel: usize, _event: TapEvent) {}
+    fn samples_dropped(&mut self, _server_index: usize, _gap: SampleGap) {}
+    fn mains_frequency_changed(
+        &mut self,
+        _server_index: usize,
+        _channel: usize,
+        _frequency: f64,
+        _detected: bool,
+    ) {
+    }
+    fn follower_message(&mut self, message: MessageToFollower);
+}
+
+#[derive(Clone, Default)]
+struct RuleState {
+    // in local time
+    satisfied_since: Option<f64>,
+    // the condition has held for long enough
+    triggered: bool,
+    // the action is in effect: while the condition is triggered, or for toggles, from one trigger to the next
+    engaged: bool,
+    // we pressed something that has to be released when the action stops
+    holding: bool,
+    // while a scroll action is engaged
+    scroll: Option<ScrollProgress>,
+}
+
+#[derive(Clone)]
+struct ScrollProgress {
+    // in local time
+    start_time: f64,
+    last_time: f64,
+    // in steps, which are only sent once they are whole
+    distance: f64,
+    steps_sent: usize,
+}
+
+/// The scroll variables, read once for all the rules of a sample.
+#[derive(Copy, Clone, Debug)]
+struct ScrollSettings {
+    proportional: bool,
+    proportional_speed: f64,
+    initial_speed: f64,
+    acceleration: f64,
+    max_speed: f64,
+}
+
+impl ScrollSettings {
+    fn from_variables() -> ScrollSettings {
+        ScrollSettings {
+            proportional: get_variable("scroll_proportional") != 0.0,
+            proportional_speed: get_variable("scroll_proportional_speed"),
+            initial_speed: get_variable("scroll_initial_speed").max(0.0),
+            acceleration: get_variable("scroll_acceleration").max(0.0),
+            max_speed: get_variable("scroll_max_speed"),
+        }
+    }
+
+    /// How far a scroll should have gone after `elapsed` seconds, if it speeds up the longer it goes on:
+    /// starting at `scroll_initial_speed` steps per second, and speeding up by `scroll_acceleration`
+    /// steps per second per second, until it reaches `scroll_max_speed` (if that isn't 0).
+    fn accelerating_distance(&self, elapsed: f64) -> f64 {
+        let ScrollSettings {
+            initial_speed,
+            acceleration,
+            max_speed,
+            ..
+        } = *self;
+        if max_speed == 0.0 {
+            return initial_speed * elapsed + acceleration * elapsed * elapsed / 2.0;
+        }
+        let max_speed = max_speed.max(initial_speed);
+        let accelerating_time = if acceleration > 0.0 {
+            ((max_speed - initial_speed) / acceleration).min(elapsed)
+        } else {
+            elapsed
+        };
+        initial_speed * accelerating_time
+            + acceleration * accelerating_time * accelerating_time / 2.0
+            + max_speed * (elapsed - accelerating_time)
+    }
+}
+
+/// The logic that turns processed signals into clicks, scrolls etc., according to a `GestureMapping`.
+pub struct DecisionMaker {
+    mapping: GestureMapping,
+    // one per rule of the mapping
+    rule_states: Vec<RuleState>,
+    // indexed by channel across all servers
+    active: Vec<bool>,
+    // also indexed by channel across all servers
+    tap_recognizers: Vec<TapRecognizer>,
+    // each channel's activity level after cross-talk compensation, as a multiple of the activity threshold
+    activity_levels: Vec<f64>,
+    // each channel's aggregate activity level before compensation, which is what cross-talk comes from
+    aggregate_activity_levels: Vec<f64>,
+    crosstalk: CrossTalk,
+    // what we last told the observer, also indexed by channel across all servers
+    mains_frequencies: Vec<Option<(f64, bool)>>,
+    // each channel's activity threshold, read again whenever the variables change
+    activity_thresholds: Option<(u64, Vec<f64>)>,
+}
+
+impl Default for DecisionMaker {
+    fn default() -> Self {
+        DecisionMaker::new(GestureMapping::default())
+    }
+}
+
+/// Whether the channels are as the condition wants them, apart from its gesture.
+fn channels_match(active: &[bool], condition: &Condition) -> bool {
+    let is_active = |&channel: &usize| active.get(channel).copied().unwrap_or(false);
+    condition.active.iter().all(is_active) && !condition.inactive.iter().any(is_active)
+}
+
+fn start_action(
+    rule: &GestureRule,
+    state: &mut RuleState,
+    allowed: bool,
+    observer: &mut impl DecisionObserver,
+) {
+    state.engaged = true;
+    if !allowed {
+        return;
+    }
+    match &rule.action {
+        &Action::Click { button } => {
+            observer.follower_message(MessageToFollower::ButtonDown(button));
+            observer.follower_message(MessageToFollower::ButtonUp(button));
+        }
+        &Action::Drag { button } => {
+            observer.follower_message(MessageToFollower::ButtonDown(button));
+            state.holding = true;
+        }
+        Action::Key(press) => {
+            observer.follower_message(MessageToFollower::KeyDown(press.clone()));
+            state.holding = true;
+        }
+        Action::Type { text } => {
+            observer.follower_message(MessageToFollower::Type(text.clone()));
+        }
+        &Action::MovePointer { x, y } => {
+            observer.follower_message(MessageToFollower::MovePointerBy { x, y });
+        }
+        &Action::MovePointerTo { x, y } => {
+            observer.follower_message(MessageToFollower::MovePointerTo { x, y });
+        }
+        &Action::Scroll { x, y } => {
+            // an instantaneous scroll doesn't last long enough to speed up
+            if rule.when.instantaneous() && !rule.toggle {
+                scroll_step(x, y, observer);
+            }
+        }
+    }
+}
+
+fn stop_action(rule: &GestureRule, state: &mut RuleState, observer: &mut impl DecisionObserver) {
+    state.engaged = false;
+    if state.holding {
+        observer.follower_message(release_message(&rule.action).unwrap());
+        state.holding = false;
+    }
+}
+
+fn scroll_step(x: i32, y: i32, observer: &mut impl DecisionObserver) {
+    if x != 0 {
+        observer.follower_message(MessageToFollower::ScrollX(x));
+    }
+    if y != 0 {
+        observer.follower_message(MessageToFollower::ScrollY(y));
+    }
+}
+
+fn release_message(action: &Action) -> Option<MessageToFollower> {
+    match action {
+        &Action::Drag { button } => Some(MessageToFollower::ButtonUp(button)),
+        Action::Key(press) => Some(MessageToFollower::KeyUp(press.clone())),
+        _ => None,
+    }
+}
+
+impl DecisionMaker {
+    pub fn new(mapping: GestureMapping) -> DecisionMaker {
+        DecisionMaker {
+            rule_states: vec![RuleState::default(); mapping.rules.len()],
+            mapping,
+            active: Vec::new(),
+            tap_recognizers: Vec::new(),
+            activity_levels: Vec::new(),
+            aggregate_activity_levels: Vec::new(),
+            crosstalk: CrossTalk::new(),
+            mains_frequencies: Vec::new(),
+            activity_thresholds: None,
+        }
+    }
+
+    pub fn mapping(&self) -> &GestureMapping {
+        &self.mapping
+    }
+
+    pub fn crosstalk(&self) -> &CrossTalk {
+        &self.crosstalk
+    }
+
+    pub fn set_crosstalk(&mut self, crosstalk: CrossTalk) -> anyhow::Result<()> {
+        validate_crosstalk(&crosstalk)?;
+        self.crosstalk = crosstalk;
+        Ok(())
+    }
+
+    /// Switch to a different mapping, releasing anything that the old one was holding down.
+    /// Channels that are already active don't trigger the new rules until they are activated again.
+    pub fn set_mapping(&mut self, mapping: GestureMapping, observer: &mut impl DecisionObserver) {
+        self.release_all(observer);
+        self.rule_states = mapping
+            .rules
+            .iter()
+            .map(|rule| RuleState {
+                triggered: !rule.when.instantaneous()
+                    && channels_match(&self.active, &rule.when)
+                    && match rule.when.gesture {
+                        Some(gesture) => self
+                            .tap_recognizers
+                            .get(gesture.channel())
+                            .is_some_and(TapRecognizer::holding),
+                        None => true,
+                    },
+                ..Default::default()
+            })
+            .collect();
+        self.mapping = mapping;
+    }
+
+    /// Whether any button or key is being held down, in which case the follower shouldn't change.
+    pub fn holding_anything(&self) -> bool {
+        self.rule_states.iter().any(|state| state.holding)
+    }
+
+    /// Release anything we were holding down, e.g. because input was just disabled.
+    /// Toggled actions stay switched on, but don't press anything again until they're switched off and on.
+    pub fn release_all(&mut self, observer: &mut impl DecisionObserver) {
+        for (rule, state) in self.mapping.rules.iter().zip(&mut self.rule_states) {
+            if state.holding {
+                observer.follower_message(release_message(&rule.action).unwrap());
+                state.holding = false;
+            }
+        }
+    }
+
+    /// Processes one sample from one server. `first_channel` is the number of that server's first channel
+    /// across all servers. `time` is the server's own idea of when the sample was taken, which signal processing
+    /// needs to be precise, while the sample's `local_time` puts it on the same timeline as the other servers.
+    pub fn process_sample(
+        &mut self,
+        signals: &mut [Signal],
+        first_channel: usize,
+        sample: &TimelineSample,
+        time: f64,
+        context: DecisionContext,
+        observer: &mut impl DecisionObserver,
+    ) {
+        let &TimelineSample {
+            server_index,
+            local_time,
+            ref inputs,
+            ..
+        } = sample;
+        if self.active.len() < first_channel + signals.len() {
+            self.active.resize(first_channel + signals.len(), false);
+            self.tap_recognizers
+                .resize_with(first_channel + signals.len(), Default::default);
+            self.activity_levels
+                .resize(first_channel + signals.len(), 0.0);
+            self.aggregate_activity_levels
+                .resize(first_channel + signals.len(), 0.0);
+        }
+        if self.mains_frequencies.len() < first_channel + signals.len() {
+            self.mains_frequencies
+                .resize(first_channel + signals.len(), None);
+        }
+        if let Some(gap) = sample.gap_before {
+            // anything that was active gets deactivated below, like any other deactivation
+            if gap.handling == GapHandling::Reset {
+                for signal in signals.iter_mut() {
+                    signal.reset_after_gap(time);
+                }
+            }
+            observer.samples_dropped(server_index, gap);
+        }
+        let generation = variables_generation();
+        let activity_thresholds = match &mut self.activity_thresholds {
+            Some((read_at, thresholds))
+                if *read_at == generation && thresholds.len() == self.activity_levels.len() =>
+            {
+                thresholds
+            }
+            activity_thresholds => {
+                let thresholds = (0..self.activity_levels.len())
+                    .map(|channel| get_channel_variable(channel, "activity_threshold"))
+                    .collect();
+                &mut activity_thresholds.insert((generation, thresholds)).1
+            }
+        };
+        let mut tap_events = Vec::new();
+        let tap_timings = TapTimings::from_variables();
+        for (channel, (signal, &input)) in signals.iter_mut().zip(inputs).enumerate() {
+            let mut frequencies_frame = None;
+            signal.receive_raw(input as f64, time, first_channel + channel, |f| {
+                frequencies_frame = Some(f)
+            });
+            if let Some(frame) = frequencies_frame {
+                observer.frequencies_frame(server_index, channel, frame);
+            }
+            self.aggregate_activity_levels[first_channel + channel] =
+                signal.aggregate_activity_level;
+        }
+        // only once every channel of this sample has its new level
+        for (channel, signal) in signals.iter_mut().enumerate() {
+            let mut history_frame = None;
+            let mut suppressed_activation = None;
+            signal.update_activity(
+                crosstalk_level(
+                    &self.crosstalk,
+                    first_channel + channel,
+                    &self.aggregate_activity_levels,
+                ),
+                time,
+                first_channel + channel,
+                |f| history_frame = Some(f),
+                |artifact| suppressed_activation = Some(artifact),
+            );
+            if let Some(frame) = history_frame {
+                observer.history_frame(server_index, channel, frame);
+            }
+            if let Some(artifact) = suppressed_activation {
+                observer.activation_suppressed(server_index, channel, artifact);
+            }
+            let active = &mut self.active[first_channel + channel];
+            if signal.is_active() != *active {
+                *active = signal.is_active();
+                observer.activity_changed(server_index, channel, signal.is_active());
+            }
+            self.activity_levels[first_channel + channel] =
+                signal.compensated_activity_level / activity_thresholds[first_channel + channel];
+            self.tap_recognizers[first_channel + channel].update(
+                *active,
+                local_time,
+                &tap_timings,
+                |event| {
+                    observer.tap_event(server_index, channel, event);
+                    tap_events.push((first_channel + channel, event));
+                },
+            );
+            let mains_frequency = &mut self.mains_frequencies[first_channel + channel];
+            if *mains_frequency != Some(signal.mains_frequency()) {
+                *mains_frequency = Some(signal.mains_frequency());
+                let (frequency, detected) = signal.mains_frequency();
+                observer.mains_frequency_changed(server_index, channel, frequency, detected);
+            }
+        }
+
+        self.apply_rules(
+            &tap_events,
+            local_time,
+            ScrollSettings::from_variables(),
+            context,
+            observer,
+        );
+    }
+
+    /// Starts and stops the rules' actions, now that the channels are as in `self.active`, and
+    /// `tap_events` (by channel across all servers) just happened.
+    fn apply_rules(
+        &mut self,
+        tap_events: &[(usize, TapEvent)],
+        local_time: f64,
+        scroll_settings: ScrollSettings,
+        context: DecisionContext,
+        observer: &mut impl DecisionObserver,
+    ) {
+        let DecisionContext {
+            enabled,
+            recently_moved,
+            anywhere_near_recently_moved,
+        } = context;
+        let pointing = !recently_moved && anywhere_near_recently_moved;
+        for (rule, state) in self.mapping.rules.iter().zip(&mut self.rule_states) {
+            let allowed = enabled && (pointing || !rule.action.needs_pointer());
+            let channels_match = channels_match(&self.active, &rule.when);
+            // whether the condition just started holding
+            let triggered_now = match rule.when.gesture {
+                Some(gesture) if gesture.instantaneous() => {
+                    let wanted_event = match gesture {
+                        ChannelGesture::Tap(_) => TapEvent::Tap,
+                        _ => TapEvent::DoubleTap,
+                    };
+                    channels_match && tap_events.contains(&(gesture.channel(), wanted_event))
+                }
+                gesture => {
+                    let holds = channels_match
+                        && gesture.is_none_or(|gesture| {
+                            self.tap_recognizers
+                                .get(gesture.channel())
+                                .is_some_and(TapRecognizer::holding)
+                        });
+                    if holds {
+                        let since = *state.satisfied_since.get_or_insert(local_time);
+                        let triggered_now =
+                            !state.triggered && local_time - since >= rule.when.held_for;
+                        state.triggered |= triggered_now;
+                        triggered_now
+                    } else {
+                        state.satisfied_since = None;
+                        state.triggered = false;
+                        false
+                    }
+                }
+            };
+
+            if rule.toggle {
+                if triggered_now {
+                    if state.engaged {
+                        stop_action(rule, state, observer);
+                    } else {
+                        start_action(rule, state, allowed, observer);
+                    }
+                }
+            } else if rule.when.instantaneous() {
+                if triggered_now {
+                    start_action(rule, state, allowed, observer);
+                    stop_action(rule, state, observer);
+                }
+            } else if triggered_now {
+                start_action(rule, state, allowed, observer);
+            } else if state.engaged && !state.triggered {
+                // this releases anything we pressed, even if input has been disabled since
+                stop_action(rule, state, observer);
+            }
+
+            if let Action::Scroll { x, y } = rule.action {
+                if enabled && state.engaged {
+                    let scroll = state.scroll.get_or_insert(ScrollProgress {
+                        start_time: local_time,
+                        last_time: local_time,
+                        distance: 0.0,
+                        steps_sent: 0,
+                    });
+                    if scroll_settings.proportional {
+                        // a combination is only as strong as its weakest channel
+                        let level = rule
+                            .when
+                            .driving_channels()
+                            .map(|channel| {
+                                self.activity_levels.get(channel).copied().unwrap_or(0.0)
+                            })
+                            .reduce(f64::min)
+                            .unwrap_or(0.0);
+                        scroll.distance += scroll_settings.proportional_speed
+                            * level.max(0.0)
+                            * (local_time - scroll.last_time).max(0.0);
+                    } else {
+                        scroll.distance = scroll_settings
+                            .accelerating_distance((local_time - scroll.start_time).max(0.0));
+                    }
+                    scroll.last_time = local_time;
+                    // the first step is sent straight away
+                    while scroll.steps_sent < 1 + scroll.distance as usize {
+                        scroll_step(x, y, observer);
+                        scroll.steps_sent += 1;
+                    }
+                } else {
+                    state.scroll = None;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::follower::Button;
+
+    #[derive(Default)]
+    struct Messages(Vec<MessageToFollower>);
+
+    impl DecisionObserver for Messages {
+        fn follower_message(&mut self, message: MessageToFollower) {
+            self.0.push(message);
+        }
+    }
+
+    const POINTING: DecisionContext = DecisionContext {
+        enabled: true,
+        recently_moved: false,
+        anywhere_near_recently_moved: true,
+    };
+
+    fn rule(active: Vec<usize>, inactive: Vec<usize>, action: Action) -> GestureRule {
+        GestureRule {
+            when: Condition {
+                active,
+                inactive,
+                held_for: 0.0,
+                gesture: None,
+            },
+            action,
+            toggle: false,
+        }
+    }
+
+    fn decision_maker(rules: Vec<GestureRule>) -> DecisionMaker {
+        let mut decision_maker = DecisionMaker::new(GestureMapping { rules });
+        decision_maker.active = vec![false; 4];
+        decision_maker.activity_levels = vec![0.0; 4];
+        decision_maker
+    }
+
+    /// What the rules do with the channels active like this at `time`.
+    fn step(
+        decision_maker: &mut DecisionMaker,
+        active: [bool; 4],
+        time: f64,
+        context: DecisionContext,
+        tap_events: &[(usize, TapEvent)],
+    ) -> Vec<MessageToFollower> {
+        decision_maker.active = active.to_vec();
+        let mut messages = Messages::default();
+        decision_maker.apply_rules(
+            tap_events,
+            time,
+            ScrollSettings::from_variables(),
+            context,
+            &mut messages,
+        );
+        messages.0
+    }
+
+    #[test]
+    fn a_drag_holds_the_button_while_its_channel_is_active() {
+        let mut decision_maker = decision_maker(GestureMapping::default().rules);
+        let active = [false, false, true, false];
+        assert_eq!(
+            step(&mut decision_maker, active, 0.0, POINTING, &[]),
+            vec![MessageToFollower::ButtonDown(Button::Left)]
+        );
+        assert_eq!(
+            step(&mut decision_maker, active, 0.1, POINTING, &[]),
+            vec![]
+        );
+        assert!(decision_maker.holding_anything());
+        assert_eq!(
+            step(&mut decision_maker, [false; 4], 0.2, POINTING, &[]),
+            vec![MessageToFollower::ButtonUp(Button::Left)]
+        );
+        assert!(!decision_maker.holding_anything());
+    }
+
+    #[test]
+    fn inactive_channels_have_to_stay_inactive() {
+        let mut decision_maker = decision_maker(GestureMapping::default().rules);
+        // channels 0 and 1 scroll in opposite directions, so together they do nothing
+        assert_eq!(
+            step(
+                &mut decision_maker,
+                [true, true, false, false],
+                0.0,
+                POINTING,
+                &[]
+            ),
+            vec![]
+        );
+        assert_eq!(
+            step(
+                &mut decision_maker,
+                [true, false, false, false],
+                0.1,
+                POINTING,
+                &[]
+            ),
+            vec![MessageToFollower::ScrollY(1)]
+        );
+    }
+
+    #[test]
+    fn held_for_delays_the_action() {
+        let mut click = rule(
+            vec![3],
+            vec![],
+            Action::Click {
+                button: Button::Right,
+            },
+        );
+        click.when.held_for = 0.5;
+        let mut decision_maker = decision_maker(vec![click]);
+        let active = [false, false, false, true];
+        assert_eq!(
+            step(&mut decision_maker, active, 1.0, POINTING, &[]),
+            vec![]
+        );
+        assert_eq!(
+            step(&mut decision_maker, active, 1.4, POINTING, &[]),
+            vec![]
+        );
+        assert_eq!(
+            step(&mut decision_maker, active, 1.5, POINTING, &[]),
+            vec![
+                MessageToFollower::ButtonDown(Button::Right),
+                MessageToFollower::ButtonUp(Button::Right)
+            ]
+        );
+        assert_eq!(
+            step(&mut decision_maker, active, 1.6, POINTING, &[]),
+            vec![]
+        );
+        // letting go starts the wait over
+        assert_eq!(
+            step(&mut decision_maker, [false; 4], 1.7, POINTING, &[]),
+            vec![]
+        );
+        assert_eq!(
+            step(&mut decision_maker, active, 1.8, POINTING, &[]),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn a_held_button_is_released_after_input_is_disabled() {
+        let mut decision_maker = decision_maker(GestureMapping::default().rules);
+        let active = [false, false, true, false];
+        let disabled = DecisionContext {
+            enabled: false,
+            ..POINTING
+        };
+        assert_eq!(
+            step(&mut decision_maker, active, 0.0, POINTING, &[]),
+            vec![MessageToFollower::ButtonDown(Button::Left)]
+        );
+        assert_eq!(
+            step(&mut decision_maker, active, 0.1, disabled, &[]),
+            vec![]
+        );
+        assert_eq!(
+            step(&mut decision_maker, [false; 4], 0.2, disabled, &[]),
+            vec![MessageToFollower::ButtonUp(Button::Left)]
+        );
+        // and nothing is pressed while disabled
+        assert_eq!(
+            step(&mut decision_maker, active, 0.3, disabled, &[]),
+            vec![]
+        );
+        assert_eq!(
+            step(&mut decision_maker, [false; 4], 0.4, disabled, &[]),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn mouse_buttons_wait_for_pointing() {
+        let mut decision_maker = decision_maker(GestureMapping::default().rules);
+        let moving = DecisionContext {
+            recently_moved: true,
+            ..POINTING
+        };
+        assert_eq!(
+            step(
+                &mut decision_maker,
+                [false, false, true, false],
+                0.0,
+                moving,
+                &[]
+            ),
+            vec![]
+        );
+        assert_eq!(
+            step(&mut decision_maker, [false; 4], 0.1, POINTING, &[]),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn taps_and_toggles() {
+        let mut tap = rule(vec![], vec![], Action::Type { text: "a".into() });
+        tap.when.gesture = Some(ChannelGesture::Tap(1));
+        let mut drag_lock = rule(
+            vec![],
+            vec![],
+            Action::Drag {
+                button: Button::Left,
+            },
+        );
+        drag_lock.when.gesture = Some(ChannelGesture::DoubleTap(3));
+        drag_lock.toggle = true;
+        let mut decision_maker = decision_maker(vec![tap, drag_lock]);
+        assert_eq!(
+            step(
+                &mut decision_maker,
+                [false; 4],
+                0.0,
+                POINTING,
+                &[(1, TapEvent::Tap)]
+            ),
+            vec![MessageToFollower::Type("a".into())]
+        );
+        // a tap on another channel does nothing
+        assert_eq!(
+            step(
+                &mut decision_maker,
+                [false; 4],
+                0.1,
+                POINTING,
+                &[(3, TapEvent::Tap)]
+            ),
+            vec![]
+        );
+        assert_eq!(
+            step(
+                &mut decision_maker,
+                [false; 4],
+                0.2,
+                POINTING,
+                &[(3, TapEvent::DoubleTap)]
+            ),
+            vec![MessageToFollower::ButtonDown(Button::Left)]
+        );
+        assert_eq!(
+            step(&mut decision_maker, [false; 4], 0.3, POINTING, &[]),
+            vec![]
+        );
+        assert_eq!(
+            step(
+                &mut decision_maker,
+                [false; 4],
+                0.4,
+                POINTING,
+                &[(3, TapEvent::DoubleTap)]
+            ),
+            vec![MessageToFollower::ButtonUp(Button::Left)]
+        );
+    }
+}
@@ -1,7 +1,7 @@
 use crate::remote_time_estimator::RemoteTimeEstimator;
 use crate::utils::{load_sound, ConnectionExt, DatagramsExt, LoadedSound};
 use async_bincode::{AsyncBincodeReader, AsyncBincodeWriter, AsyncDestination};
-use enigo::{Enigo, MouseButton, MouseControllable};
+use enigo::{Enigo, Key, KeyboardControllable, MouseControllable};
 use futures::executor::block_on;
 use futures::sink::SinkExt;
 use rodio::source::Buffered;
@@ -19,9 +19,77 @@ use tokio_stream::StreamExt;
 
 #[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
 pub enum MessageToFollower {
-    Mousedown,
-    MouseUp,
+    ButtonDown(Button),
+    ButtonUp(Button),
+    ScrollX(i32),
     ScrollY(i32),
+    KeyDown(KeyPress),
+    KeyUp(KeyPress),
+    Type(String),
+    MovePointerBy { x: i32, y: i32 },
+    MovePointerTo { x: i32, y: i32 },
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Serialize, Deserialize, Debug, Default)]
+pub enum Button {
+    #[default]
+    Left,
+    Right,
+    Middle,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub enum KeyName {
+    Alt,
+    Backspace,
+    CapsLock,
+    Control,
+    Delete,
+    DownArrow,
+    End,
+    Escape,
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
+    F9,
+    F10,
+    F11,
+    F12,
+    Home,
+    LeftArrow,
+    Meta,
+    PageDown,
+    PageUp,
+    Return,
+    RightArrow,
+    Shift,
+    Space,
+    Tab,
+    UpArrow,
+    /// whichever key types this character in the follower's keyboard layout
+    Char(char),
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub enum Modifier {
+    Shift,
+    Control,
+    Alt,
+    Meta,
+}
+
+/// A key, along with the modifiers that are held down around it: pressed before it goes down,
+/// and released (in the opposite order) after it comes up.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub struct KeyPress {
+    pub key: KeyName,
+    #[serde(default)]
+    pub modifiers: Vec<Modifier>,
 }
 
 #[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
@@ -51,21 +119,45 @@ pub struct RemoteFollower {
 pub trait Follower {
     fn handle_message(&mut self, message: MessageToFollower) {
         match message {
-            MessageToFollower::Mousedown => self.mousedown(),
-            MessageToFollower::MouseUp => self.mouse_up(),
+            MessageToFollower::ButtonDown(button) => self.button_down(button),
+            MessageToFollower::ButtonUp(button) => self.button_up(button),
+            MessageToFollower::ScrollX(length) => self.scroll_x(length),
             MessageToFollower::ScrollY(length) => self.scroll_y(length),
+            MessageToFollower::KeyDown(press) => self.key_down(press),
+            MessageToFollower::KeyUp(press) => self.key_up(press),
+            MessageToFollower::Type(text) => self.type_text(text),
+            MessageToFollower::MovePointerBy { x, y } => self.move_pointer_by(x, y),
+            MessageToFollower::MovePointerTo { x, y } => self.move_pointer_to(x, y),
         }
     }
 
-    fn mousedown(&mut self) {
-        self.handle_message(MessageToFollower::Mousedown)
+    fn button_down(&mut self, button: Button) {
+        self.handle_message(MessageToFollower::ButtonDown(button))
     }
-    fn mouse_up(&mut self) {
-        self.handle_message(MessageToFollower::MouseUp)
+    fn button_up(&mut self, button: Button) {
+        self.handle_message(MessageToFollower::ButtonUp(button))
+    }
+    fn scroll_x(&mut self, length: i32) {
+        self.handle_message(MessageToFollower::ScrollX(length))
     }
     fn scroll_y(&mut self, length: i32) {
         self.handle_message(MessageToFollower::ScrollY(length))
     }
+    fn key_down(&mut self, press: KeyPress) {
+        self.handle_message(MessageToFollower::KeyDown(press))
+    }
+    fn key_up(&mut self, press: KeyPress) {
+        self.handle_message(MessageToFollower::KeyUp(press))
+    }
+    fn type_text(&mut self, text: String) {
+        self.handle_message(MessageToFollower::Type(text))
+    }
+    fn move_pointer_by(&mut self, x: i32, y: i32) {
+        self.handle_message(MessageToFollower::MovePointerBy { x, y })
+    }
+    fn move_pointer_to(&mut self, x: i32, y: i32) {
+        self.handle_message(MessageToFollower::MovePointerTo { x, y })
+    }
 }
 
 pub struct SupervisedFollower<F> {
@@ -78,24 +170,117 @@ pub enum SupervisedFollowerMut<'a> {
     Remote(&'a mut SupervisedFollower<RemoteFollower>),
 }
 
+impl From<Button> for enigo::MouseButton {
+    fn from(button: Button) -> Self {
+        match button {
+            Button::Left => enigo::MouseButton::Left,
+            Button::Right => enigo::MouseButton::Right,
+            Button::Middle => enigo::MouseButton::Middle,
+        }
+    }
+}
+
+impl From<KeyName> for Key {
+    fn from(key: KeyName) -> Self {
+        match key {
+            KeyName::Alt => Key::Alt,
+            KeyName::Backspace => Key::Backspace,
+            KeyName::CapsLock => Key::CapsLock,
+            KeyName::Control => Key::Control,
+            KeyName::Delete => Key::Delete,
+            KeyName::DownArrow => Key::DownArrow,
+            KeyName::End => Key::End,
+            KeyName::Escape => Key::Escape,
+            KeyName::F1 => Key::F1,
+            KeyName::F2 => Key::F2,
+            KeyName::F3 => Key::F3,
+            KeyName::F4 => Key::F4,
+            KeyName::F5 => Key::F5,
+            KeyName::F6 => Key::F6,
+            KeyName::F7 => Key::F7,
+            KeyName::F8 => Key::F8,
+            KeyName::F9 => Key::F9,
+            KeyName::F10 => Key::F10,
+            KeyName::F11 => Key::F11,
+            KeyName::F12 => Key::F12,
+            KeyName::Home => Key::Home,
+            KeyName::LeftArrow => Key::LeftArrow,
+            KeyName::Meta => Key::Meta,
+            KeyName::PageDown => Key::PageDown,
+            KeyName::PageUp => Key::PageUp,
+            KeyName::Return => Key::Return,
+            KeyName::RightArrow => Key::RightArrow,
+            KeyName::Shift => Key::Shift,
+            KeyName::Space => Key::Space,
+            KeyName::Tab => Key::Tab,
+            KeyName::UpArrow => Key::UpArrow,
+            KeyName::Char(character) => Key::Layout(character),
+        }
+    }
+}
+
+impl From<Modifier> for Key {
+    fn from(modifier: Modifier) -> Self {
+        match modifier {
+            Modifier::Shift => Key::Shift,
+            Modifier::Control => Key::Control,
+            Modifier::Alt => Key::Alt,
+            Modifier::Meta => Key::Meta,
+        }
+    }
+}
+
 impl Follower for LocalFollower {
-    fn mousedown(&mut self) {
-        self.enigo.mouse_down(MouseButton::Left);
+    fn button_down(&mut self, button: Button) {
+        self.enigo.mouse_down(button.into());
         self.audio_output_stream_handle
             .play_raw(self.click_sound.clone())
             .unwrap();
     }
 
-    fn mouse_up(&mut self) {
-        self.enigo.mouse_up(MouseButton::Left);
+    fn button_up(&mut self, button: Button) {
+        self.enigo.mouse_up(button.into());
         self.audio_output_stream_handle
             .play_raw(self.unclick_sound.clone())
             .unwrap();
     }
 
+    fn scroll_x(&mut self, length: i32) {
+        self.enigo.mouse_scroll_x(length);
+    }
+
     fn scroll_y(&mut self, length: i32) {
         self.enigo.mouse_scroll_y(length);
     }
+
+    fn key_down(&mut self, press: KeyPress) {
+        for &modifier in &press.modifiers {
+            self.enigo.key_down(modifier.into());
+        }
+        self.enigo.key_down(press.key.into());
+    }
+
+    fn key_up(&mut self, press: KeyPress) {
+        self.enigo.key_up(press.key.into());
+        for &modifier in press.modifiers.iter().rev() {
+            self.enigo.key_up(modifier.into());
+        }
+    }
+
+    fn type_text(&mut self, text: String) {
+        self.enigo.key_sequence(&text);
+    }
+
+    // moves that we make ourselves don't count as the user moving the mouse
+    fn move_pointer_by(&mut self, x: i32, y: i32) {
+        self.enigo.mouse_move_relative(x, y);
+        self.most_recent_mouse_location = self.enigo.mouse_location();
+    }
+
+    fn move_pointer_to(&mut self, x: i32, y: i32) {
+        self.enigo.mouse_move_to(x, y);
+        self.most_recent_mouse_location = self.enigo.mouse_location();
+    }
 }
 
 impl Follower for RemoteFollower {
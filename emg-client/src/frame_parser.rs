@@ -0,0 +1,403 @@
+use crate::sample_source::ReportFromServer;
+use emg_mouse_shared::codec::{
+    decode_frame, encode_frame, DecodeError, FrameHeader, FrameVersion, MARKER_SIZE, MAX_FRAME_SIZE,
+};
+use serde::{Deserialize, Serialize};
+
+/*
+
+Finds frames (see `emg_mouse_shared::codec`) in a byte stream that may have garbage between them,
+or be corrupted or truncated.
+
+The marker can also appear by chance inside a frame, so whenever a frame turns out to be bad,
+we only skip past its marker and look for the next one from there, rather than skipping the whole
+(possibly misreported) length.
+
+Frames from servers from before the checksum was added can't be checked for corruption, only for
+a plausible length. So once a server has sent a checksummed frame, anything that looks like an
+old-style frame is treated as garbage.
+
+*/
+
+pub fn encode_frame_vec<S: AsRef<[u16]>>(
+    server_run_id: u64,
+    first_sample_index: u64,
+    samples: &[S],
+) -> Vec<u8> {
+    let mut buffer = [0; MAX_FRAME_SIZE];
+    let size = encode_frame(server_run_id, first_sample_index, samples, &mut buffer);
+    buffer[..size].to_vec()
+}
+
+/// How healthy the link to one server has been, counted since the parser was created.
+#[derive(Copy, Clone, PartialEq, Eq, Serialize, Deserialize, Debug, Default)]
+pub struct LinkStatistics {
+    pub frames: u64,
+    pub samples: u64,
+    /// samples missing from the sequence of sample indices, whether they were never received
+    /// or were in frames that got thrown away
+    pub dropped_samples: u64,
+    /// frames that had a bad checksum or an impossible length
+    pub corrupted_frames: u64,
+    /// how many samples the corrupted frames claimed to contain, as far as we can tell
+    pub corrupted_samples: u64,
+    /// bytes that weren't part of any good frame
+    pub skipped_bytes: u64,
+}
+
+/// Turns a byte stream into `ReportFromServer`s, however mangled the stream is.
+#[derive(Default)]
+pub struct FrameParser {
+    buffer: Vec<u8>,
+    // the run id and first sample index we expect the next frame to have
+    expected_next: Option<(u64, u64)>,
+    checksummed_frames_seen: bool,
+    statistics: LinkStatistics,
+}
+
+impl FrameParser {
+    pub fn new() -> FrameParser {
+        FrameParser::default()
+    }
+
+    pub fn statistics(&self) -> LinkStatistics {
+        self.statistics
+    }
+
+    pub fn push_bytes(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Forget any partial frame, e.g. because the connection was reopened and the rest of it will never come.
+    /// Gap detection continues across this, since the server kept counting samples in the meantime.
+    pub fn discard_partial_frame(&mut self) {
+        self.statistics.skipped_bytes += self.buffer.len() as u64;
+        self.buffer.clear();
+    }
+
+    fn skip(&mut self, num_bytes: usize) {
+        self.statistics.skipped_bytes += num_bytes as u64;
+        self.buffer.drain(..num_bytes);
+    }
+
+    fn reject_frame(&mut self, claimed_samples: usize) {
+        self.statistics.corrupted_frames += 1;
+        self.statistics.corrupted_samples += claimed_samples as u64;
+        self.skip(1);
+    }
+
+    /// The next complete, valid frame in the bytes pushed so far, if there is one.
+    pub fn next_report(&mut self) -> Option<ReportFromServer> {
+        loop {
+            let marker_position = self
+                .buffer
+                .windows(MARKER_SIZE)
+                .position(|window| FrameVersion::from_marker(window).is_some());
+            let Some(marker_position) = marker_position else {
+                // keep anything that could be the beginning of a marker
+                self.skip(self.buffer.len().saturating_sub(MARKER_SIZE - 1));
+                return None;
+            };
+            self.skip(marker_position);
+            let version = FrameVersion::from_marker(&self.buffer[..MARKER_SIZE]).unwrap();
+            let header_bytes = self.buffer.get(..version.header_size())?;
+
+            let header = match FrameHeader::decode(header_bytes) {
+                Ok(header) => header,
+                Err(_) => {
+                    self.reject_frame(0);
+                    continue;
+                }
+            };
+            if !header.version.checksummed() && self.checksummed_frames_seen {
+                self.skip(1);
+                continue;
+            }
+            let frame_bytes = self.buffer.get(..header.frame_size())?;
+            let report = match decode_frame(frame_bytes) {
+                Ok(frame) => ReportFromServer {
+                    server_run_id: header.server_run_id,
+                    first_sample_index: header.first_sample_index,
+                    samples: frame.samples().map(Iterator::collect).collect(),
+                },
+                Err(DecodeError::BadChecksum) => {
+                    self.reject_frame(header.num_samples as usize);
+                    continue;
+                }
+                Err(e) => unreachable!("{}", e),
+            };
+            self.checksummed_frames_seen |= header.version.checksummed();
+            self.buffer.drain(..header.frame_size());
+            self.observe_indices(&header);
+            return Some(report);
+        }
+    }
+
+    fn observe_indices(&mut self, header: &FrameHeader) {
+        if let Some((expected_run_id, expected_index)) = self.expected_next {
+            // a different run id means the server restarted, which isn't a gap;
+            // and an index going backwards is a duplicate, not a gap either
+            if expected_run_id == header.server_run_id && header.first_sample_index > expected_index
+            {
+                self.statistics.dropped_samples += header.first_sample_index - expected_index;
+            }
+        }
+        self.expected_next = Some((
+            header.server_run_id,
+            header.first_sample_index + header.num_samples as u64,
+        ));
+        self.statistics.frames += 1;
+        self.statistics.samples += header.num_samples as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recording::pack_samples;
+    use emg_mouse_shared::codec::{MAX_HEADER_SIZE, MAX_SAMPLES_PER_FRAME};
+
+    fn samples_with_channels(first: u16, count: usize, num_channels: usize) -> Vec<Vec<u16>> {
+        (0..count as u16)
+            .map(|i| {
+                let v = (first + i) & 0xfff;
+                [v, 4095 - v, v / 2, 7]
+                    .into_iter()
+                    .cycle()
+                    .take(num_channels)
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn samples(first: u16, count: usize) -> Vec<Vec<u16>> {
+        samples_with_channels(first, count, 4)
+    }
+
+    fn parse_all(parser: &mut FrameParser, bytes: &[u8]) -> Vec<ReportFromServer> {
+        parser.push_bytes(bytes);
+        std::iter::from_fn(|| parser.next_report()).collect()
+    }
+
+    #[test]
+    fn clean_stream() {
+        let mut bytes = Vec::new();
+        for frame in 0..5 {
+            bytes.extend(encode_frame_vec(
+                7,
+                frame * 10,
+                &samples(frame as u16 * 10, 10),
+            ));
+        }
+        let mut parser = FrameParser::new();
+        let reports = parse_all(&mut parser, &bytes);
+        assert_eq!(reports.len(), 5);
+        assert_eq!(reports[3].first_sample_index, 30);
+        assert_eq!(reports[3].samples, samples(30, 10));
+        assert_eq!(
+            parser.statistics(),
+            LinkStatistics {
+                frames: 5,
+                samples: 50,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn byte_at_a_time() {
+        let bytes = encode_frame_vec(7, 0, &samples(0, 40));
+        let mut parser = FrameParser::new();
+        let mut reports = Vec::new();
+        for &byte in &bytes {
+            reports.extend(parse_all(&mut parser, &[byte]));
+        }
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].samples, samples(0, 40));
+    }
+
+    #[test]
+    fn garbage_between_frames() {
+        let mut bytes = b"boot message\r\nemg_".to_vec();
+        bytes.extend(encode_frame_vec(7, 0, &samples(0, 10)));
+        bytes.extend(b"emg_dat2emg_data\x00\x01");
+        bytes.extend(encode_frame_vec(7, 10, &samples(10, 10)));
+        let mut parser = FrameParser::new();
+        let reports = parse_all(&mut parser, &bytes);
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[1].samples, samples(10, 10));
+        assert_eq!(parser.statistics().dropped_samples, 0);
+        assert!(parser.statistics().skipped_bytes > 0);
+    }
+
+    #[test]
+    fn flipped_bit_is_rejected() {
+        let mut bytes = encode_frame_vec(7, 0, &samples(0, 10));
+        let mut corrupted = encode_frame_vec(7, 10, &samples(10, 10));
+        corrupted[MAX_HEADER_SIZE + 13] ^= 0x10;
+        bytes.extend(corrupted);
+        bytes.extend(encode_frame_vec(7, 20, &samples(20, 10)));
+        let mut parser = FrameParser::new();
+        let reports = parse_all(&mut parser, &bytes);
+        assert_eq!(
+            reports
+                .iter()
+                .map(|r| r.first_sample_index)
+                .collect::<Vec<_>>(),
+            [0, 20]
+        );
+        let statistics = parser.statistics();
+        assert_eq!(statistics.corrupted_frames, 1);
+        assert_eq!(statistics.corrupted_samples, 10);
+        assert_eq!(statistics.dropped_samples, 10);
+    }
+
+    #[test]
+    fn huge_length_is_rejected_without_waiting() {
+        let mut corrupted = encode_frame_vec(7, 0, &samples(0, 10));
+        corrupted[24..26].copy_from_slice(&u16::MAX.to_le_bytes());
+        let mut bytes = corrupted;
+        bytes.extend(encode_frame_vec(7, 10, &samples(10, 10)));
+        let mut parser = FrameParser::new();
+        let reports = parse_all(&mut parser, &bytes);
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].first_sample_index, 10);
+        assert_eq!(parser.statistics().corrupted_frames, 1);
+        assert_eq!(parser.statistics().dropped_samples, 0);
+    }
+
+    #[test]
+    fn overstated_length_does_not_swallow_following_frames() {
+        // claims to be longer than it is, so its "crc" lands inside the following frames
+        let mut corrupted = encode_frame_vec(7, 0, &samples(0, 10));
+        corrupted[24..26].copy_from_slice(&30u16.to_le_bytes());
+        let mut bytes = corrupted;
+        for frame in 1..4 {
+            bytes.extend(encode_frame_vec(
+                7,
+                frame * 10,
+                &samples(frame as u16 * 10, 10),
+            ));
+        }
+        let mut parser = FrameParser::new();
+        let reports = parse_all(&mut parser, &bytes);
+        assert_eq!(
+            reports
+                .iter()
+                .map(|r| r.first_sample_index)
+                .collect::<Vec<_>>(),
+            [10, 20, 30]
+        );
+        assert_eq!(parser.statistics().corrupted_frames, 1);
+    }
+
+    #[test]
+    fn truncated_frame_then_resync() {
+        let mut bytes = encode_frame_vec(7, 0, &samples(0, 10));
+        bytes.truncate(40);
+        bytes.extend(encode_frame_vec(7, 10, &samples(10, 10)));
+        bytes.extend(encode_frame_vec(7, 20, &samples(20, 10)));
+        let mut parser = FrameParser::new();
+        let reports = parse_all(&mut parser, &bytes);
+        assert_eq!(
+            reports
+                .iter()
+                .map(|r| r.first_sample_index)
+                .collect::<Vec<_>>(),
+            [10, 20]
+        );
+        assert_eq!(parser.statistics().corrupted_frames, 1);
+        assert_eq!(parser.statistics().dropped_samples, 0);
+    }
+
+    #[test]
+    fn gaps_and_restarts() {
+        let mut bytes = Vec::new();
+        bytes.extend(encode_frame_vec(7, 0, &samples(0, 10)));
+        bytes.extend(encode_frame_vec(7, 25, &samples(25, 10)));
+        // a duplicate isn't a gap
+        bytes.extend(encode_frame_vec(7, 25, &samples(25, 10)));
+        // nor is a restart
+        bytes.extend(encode_frame_vec(8, 0, &samples(0, 10)));
+        bytes.extend(encode_frame_vec(8, 12, &samples(12, 10)));
+        let mut parser = FrameParser::new();
+        let reports = parse_all(&mut parser, &bytes);
+        assert_eq!(reports.len(), 5);
+        assert_eq!(parser.statistics().dropped_samples, 15 + 2);
+    }
+
+    #[test]
+    fn legacy_frames() {
+        let mut bytes = Vec::new();
+        for frame in 0..3u64 {
+            let header = FrameHeader {
+                version: FrameVersion::Legacy,
+                server_run_id: 3,
+                first_sample_index: frame * 5,
+                num_samples: 5,
+                num_channels: 4,
+            };
+            let mut header_bytes = [0; 26];
+            header.encode(&mut header_bytes);
+            bytes.extend_from_slice(&header_bytes);
+            bytes.extend(pack_samples(&samples(frame as u16 * 5, 5)));
+        }
+        let mut parser = FrameParser::new();
+        let reports = parse_all(&mut parser, &bytes);
+        assert_eq!(reports.len(), 3);
+        assert_eq!(reports[2].samples, samples(10, 5));
+    }
+
+    #[test]
+    fn channel_count_comes_from_each_frame() {
+        let mut bytes = Vec::new();
+        for (frame, num_channels) in [1, 3, 8, 16].into_iter().enumerate() {
+            bytes.extend(encode_frame_vec(
+                7,
+                frame as u64 * 10,
+                &samples_with_channels(frame as u16 * 10, 10, num_channels),
+            ));
+        }
+        let mut parser = FrameParser::new();
+        let reports = parse_all(&mut parser, &bytes);
+        assert_eq!(reports.len(), 4);
+        assert_eq!(reports[0].samples, samples_with_channels(0, 10, 1));
+        assert_eq!(reports[1].samples, samples_with_channels(10, 10, 3));
+        assert_eq!(reports[2].samples, samples_with_channels(20, 10, 8));
+        assert_eq!(reports[3].samples, samples_with_channels(30, 10, 16));
+        assert_eq!(parser.statistics().dropped_samples, 0);
+    }
+
+    #[test]
+    fn random_corruption_never_yields_bad_samples() {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let mut parser = FrameParser::new();
+        let mut sent = 0;
+        let mut received = 0;
+        for frame in 0..2000u64 {
+            let count = rng.gen_range(1..=MAX_SAMPLES_PER_FRAME);
+            let expected = samples(frame as u16, count);
+            let mut bytes = encode_frame_vec(1, frame * 100, &expected);
+            sent += 1;
+            match rng.gen_range(0..4) {
+                0 => {
+                    let index = rng.gen_range(0..bytes.len());
+                    bytes[index] ^= 1 << rng.gen_range(0..8);
+                }
+                1 => bytes.truncate(rng.gen_range(0..bytes.len())),
+                _ => {}
+            }
+            for report in parse_all(&mut parser, &bytes) {
+                assert_eq!(report.server_run_id, 1);
+                let frame = report.first_sample_index / 100;
+                assert_eq!(report.samples, samples(frame as u16, report.samples.len()));
+                received += 1;
+            }
+        }
+        let statistics = parser.statistics();
+        assert!(received > sent / 3);
+        assert_eq!(statistics.frames, received);
+        assert!(statistics.corrupted_frames > 0);
+    }
+}
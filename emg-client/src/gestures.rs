@@ -0,0 +1,230 @@
+use crate::follower::{Button, KeyPress};
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/*
+
+Which channels do what. A `GestureMapping` is a list of rules, each of which performs an action
+while its condition holds. It can be loaded from a JSON file with `--gestures`, and edited in the GUI.
+The default mapping looks like this:
+
+{
+  "rules": [
+    { "when": { "active": [2] }, "action": { "Drag": { "button": "Left" } } },
+    { "when": { "active": [0], "inactive": [1] }, "action": { "Scroll": { "y": 1 } } },
+    { "when": { "active": [1], "inactive": [0] }, "action": { "Scroll": { "y": -1 } } },
+    { "when": { "active": [4], "inactive": [5] }, "action": { "Scroll": { "x": -1 } } },
+    { "when": { "active": [5], "inactive": [4] }, "action": { "Scroll": { "x": 1 } } }
+  ]
+}
+
+Other actions look like this:
+
+{ "Click": { "button": "Right" } }
+{ "Scroll": { "x": -1 } }
+{ "Key": { "key": { "Char": "c" }, "modifiers": ["Control"] } }
+{ "Key": { "key": "Escape" } }
+{ "Type": { "text": "Hello!" } }
+{ "MovePointer": { "x": 0, "y": -100 } }
+{ "MovePointerTo": { "x": 500, "y": 500 } }
+
+Channels are numbered across all servers in order, so with two 4-channel servers, channel 5 is the
+second channel of the second server. Rules don't know about each other, so if one rule's channels
+are a subset of another's, the smaller rule should list the other channels in `inactive`.
+
+A condition can also wait for a tap, double tap or hold on one channel (see `taps.rs`):
+
+{ "when": { "gesture": { "Hold": 2 } }, "action": { "Click": { "button": "Right" } } }
+{ "when": { "gesture": { "DoubleTap": 2 } }, "toggle": true, "action": { "Drag": {} } }
+
+A hold lasts until the channel is released, like an ordinary condition, but taps and double taps
+are instantaneous, so their actions are done all at once: a drag or key is pressed and released
+immediately, and a scroll makes one step. With `toggle`, each time the condition starts holding
+switches the action on or off, so the second rule above is a drag lock.
+
+*/
+
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct GestureMapping {
+    pub rules: Vec<GestureRule>,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct GestureRule {
+    pub when: Condition,
+    pub action: Action,
+    #[serde(default)]
+    pub toggle: bool,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Condition {
+    /// all of these channels have to be active at once
+    pub active: Vec<usize>,
+    /// and none of these
+    pub inactive: Vec<usize>,
+    /// for at least this many seconds, before the action starts
+    pub held_for: f64,
+    /// and this has to be happening too
+    pub gesture: Option<ChannelGesture>,
+}
+
+/// Something recognized on the channel with the given number.
+#[derive(Copy, Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub enum ChannelGesture {
+    Tap(usize),
+    DoubleTap(usize),
+    Hold(usize),
+}
+
+impl ChannelGesture {
+    pub fn channel(&self) -> usize {
+        match *self {
+            ChannelGesture::Tap(channel)
+            | ChannelGesture::DoubleTap(channel)
+            | ChannelGesture::Hold(channel) => channel,
+        }
+    }
+
+    /// Taps and double taps happen at an instant, rather than lasting a while.
+    pub fn instantaneous(&self) -> bool {
+        !matches!(self, ChannelGesture::Hold(_))
+    }
+}
+
+impl Condition {
+    /// The channels whose activity makes this condition hold.
+    pub fn driving_channels(&self) -> impl Iterator<Item = usize> + '_ {
+        self.active
+            .iter()
+            .copied()
+            .chain(self.gesture.map(|gesture| gesture.channel()))
+    }
+
+    pub fn instantaneous(&self) -> bool {
+        self.gesture.is_some_and(|gesture| gesture.instantaneous())
+    }
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub enum Action {
+    /// press and release a mouse button, once, when the condition starts holding
+    Click {
+        #[serde(default)]
+        button: Button,
+    },
+    /// hold a mouse button down for as long as the condition holds
+    Drag {
+        #[serde(default)]
+        button: Button,
+    },
+    /// scroll faster and faster for as long as the condition holds;
+    /// `x` and `y` are the size and direction of each step
+    Scroll {
+        #[serde(default)]
+        x: i32,
+        #[serde(default)]
+        y: i32,
+    },
+    /// hold a key (and modifiers) down for as long as the condition holds
+    Key(KeyPress),
+    /// type some text, once, when the condition starts holding
+    Type { text: String },
+    /// move the mouse pointer by this many pixels, once, when the condition starts holding
+    MovePointer { x: i32, y: i32 },
+    /// move the mouse pointer to this position on the screen, once, when the condition starts holding
+    MovePointerTo { x: i32, y: i32 },
+}
+
+impl Action {
+    /// Mouse button actions only happen when the user is evidently pointing at something:
+    /// the mouse was moved a little while ago, but isn't moving right now.
+    pub fn needs_pointer(&self) -> bool {
+        matches!(self, Action::Click { .. } | Action::Drag { .. })
+    }
+}
+
+impl Default for GestureMapping {
+    fn default() -> Self {
+        let rule = |active: Vec<usize>, inactive: Vec<usize>, action| GestureRule {
+            when: Condition {
+                active,
+                inactive,
+                held_for: 0.0,
+                gesture: None,
+            },
+            action,
+            toggle: false,
+        };
+        GestureMapping {
+            rules: vec![
+                rule(
+                    vec![2],
+                    vec![],
+                    Action::Drag {
+                        button: Button::Left,
+                    },
+                ),
+                rule(vec![0], vec![1], Action::Scroll { x: 0, y: 1 }),
+                rule(vec![1], vec![0], Action::Scroll { x: 0, y: -1 }),
+                // only with more than 4 channels, e.g. from a second device
+                rule(vec![4], vec![5], Action::Scroll { x: -1, y: 0 }),
+                rule(vec![5], vec![4], Action::Scroll { x: 1, y: 0 }),
+            ],
+        }
+    }
+}
+
+impl GestureMapping {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<GestureMapping> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read gesture mapping {}", path.display()))?;
+        let mapping: GestureMapping = serde_json::from_str(&text)
+            .with_context(|| format!("failed to parse gesture mapping {}", path.display()))?;
+        mapping.validate()?;
+        Ok(mapping)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        std::fs::write(path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("failed to write gesture mapping {}", path.display()))
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for (index, GestureRule { when, .. }) in self.rules.iter().enumerate() {
+            if when.active.is_empty() && when.gesture.is_none() {
+                bail!(
+                    "rule {} doesn't need any channels to be active, or any gesture",
+                    index
+                );
+            }
+            if let Some(channel) = when.active.iter().find(|c| when.inactive.contains(c)) {
+                bail!(
+                    "rule {} needs channel {} to be both active and inactive",
+                    index,
+                    channel
+                );
+            }
+            if !(when.held_for >= 0.0 && when.held_for.is_finite()) {
+                bail!(
+                    "rule {} has held_for {}, which isn't a number of seconds",
+                    index,
+                    when.held_for
+                );
+            }
+            if when.instantaneous() && when.held_for != 0.0 {
+                bail!(
+                    "rule {} waits for a tap, which can't be held for {} seconds",
+                    index,
+                    when.held_for
+                );
+            }
+        }
+        Ok(())
+    }
+}
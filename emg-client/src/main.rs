@@ -6,22 +6,57 @@
     array_methods
 )]
 
-#[cfg(feature = "bluetooth")]
+mod activity_detectors;
+mod artifacts;
 mod bluetooth;
+mod calibration;
+mod crosstalk;
+mod decisions;
 mod follower;
+mod frame_parser;
+mod gestures;
+mod nudft;
+mod profiles;
+mod recording;
 mod remote_time_estimator;
-#[cfg(not(feature = "bluetooth"))]
+mod replay;
+mod sample_source;
 mod serial_port_communication;
 mod signal;
+mod simulator;
 mod supervisor;
+mod taps;
+mod timeline;
 mod utils;
+mod variables;
 mod webserver;
 mod webserver_glue;
+mod weight_learning;
+mod window;
 
 use crate::follower::LocalFollower;
+use crate::gestures::GestureMapping;
+use crate::simulator::{SimulatorOutput, SimulatorScript};
 use crate::supervisor::{Supervisor, SupervisorOptions};
+use anyhow::Context;
 use clap::{App, AppSettings, Arg, SubCommand};
 use rodio::OutputStream;
+use std::path::PathBuf;
+
+fn load_gestures(path: Option<&str>) -> anyhow::Result<GestureMapping> {
+    match path {
+        Some(path) => GestureMapping::load(path),
+        None => Ok(GestureMapping::default()),
+    }
+}
+
+fn mains_frequency_arg() -> Arg<'static, 'static> {
+    Arg::with_name("mains-frequency")
+        .long("mains-frequency")
+        .takes_value(true)
+        .default_value("auto")
+        .help("Frequency of the local mains in Hz, for filtering line noise, or \"auto\" to detect 50 or 60 Hz while idle")
+}
 
 #[actix_web::main]
 async fn main() -> anyhow::Result<()> {
@@ -35,10 +70,13 @@ async fn main() -> anyhow::Result<()> {
             SubCommand::with_name("supervisor")
                 .long_about("Listens for EMG input and does stuff with it")
                 .arg(
-                    Arg::with_name("server-address")
-                        .long("server-address")
-                        .required(true)
-                        .takes_value(true),
+                    Arg::with_name("source")
+                        .long("source")
+                        .takes_value(true)
+                        .default_value("serial")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Where to get samples from (repeat for several devices): serial[:path], serial:vid=..,pid=.., ble[:name], tcp:host:port, replay:path or simulate[:script.json]"),
                 )
                 .arg(
                     Arg::with_name("gui-port")
@@ -51,6 +89,26 @@ async fn main() -> anyhow::Result<()> {
                         .long("follower-port")
                         .required(true)
                         .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("record")
+                        .long("record")
+                        .takes_value(true)
+                        .help("Record every report from the server to this file"),
+                )
+                .arg(mains_frequency_arg())
+                .arg(
+                    Arg::with_name("gestures")
+                        .long("gestures")
+                        .takes_value(true)
+                        .help("JSON file saying which channels do what (edits in the GUI are saved back to it)"),
+                )
+                .arg(
+                    Arg::with_name("profile")
+                        .long("profile")
+                        .takes_value(true)
+                        .default_value(crate::profiles::DEFAULT_PROFILE)
+                        .help("Which saved set of variables to start with (they're in the profiles directory)"),
                 ),
         )
         .subcommand(
@@ -75,12 +133,88 @@ async fn main() -> anyhow::Result<()> {
                         .takes_value(true),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("replay")
+                .long_about("Runs a recorded session through the detector and prints what it would have done")
+                .arg(Arg::with_name("recording").required(true).index(1))
+                .arg(
+                    Arg::with_name("default-variables")
+                        .long("default-variables")
+                        .help("Use the current default variables instead of the ones saved in the recording"),
+                )
+                .arg(
+                    Arg::with_name("detector")
+                        .long("detector")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("[<channel>:]<detector> to measure activity with, for every channel or just that one: dft-bank, rms, teager-kaiser or filter-bank (repeat for more channels)"),
+                )
+                .arg(mains_frequency_arg())
+                .arg(
+                    Arg::with_name("gestures")
+                        .long("gestures")
+                        .takes_value(true)
+                        .help("JSON file saying which channels do what"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("learn-weights")
+                .long_about("Learns how much each frequency should count towards channels' activity levels, from recordings of resting and contracting, and saves the weights in a profile")
+                .arg(
+                    Arg::with_name("rest")
+                        .long("rest")
+                        .required(true)
+                        .takes_value(true)
+                        .help("Recording of every muscle at rest"),
+                )
+                .arg(
+                    Arg::with_name("contraction")
+                        .long("contraction")
+                        .required(true)
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("<channel>:<recording> of that channel's muscle contracted throughout (repeat for more channels or recordings)"),
+                )
+                .arg(
+                    Arg::with_name("profile")
+                        .long("profile")
+                        .takes_value(true)
+                        .default_value(crate::profiles::DEFAULT_PROFILE)
+                        .help("Which profile to save the weights in; its thresholds are the ones the weights are scaled to"),
+                )
+                .arg(mains_frequency_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("simulate")
+                .long_about("Pretends to be an EMG device, sending the same byte stream as emg_server.c")
+                .arg(
+                    Arg::with_name("script")
+                        .index(1)
+                        .help("JSON file describing what the simulated muscles do"),
+                )
+                .arg(
+                    Arg::with_name("tcp-port")
+                        .long("tcp-port")
+                        .takes_value(true)
+                        .help("Serve over TCP instead of a pseudo-terminal (which only exists on Unix)"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("list-serial-ports")
+                .long_about("Lists serial ports, with the USB IDs that can be used to select them in --source"),
+        )
         .get_matches();
 
     match matches.subcommand() {
         ("supervisor", Some(matches)) => {
             Supervisor::run(SupervisorOptions {
-                server_address: matches.value_of("server-address").unwrap().to_string(),
+                sources: matches
+                    .values_of("source")
+                    .unwrap()
+                    .map(str::parse)
+                    .collect::<anyhow::Result<_>>()?,
                 gui_port: matches
                     .value_of("gui-port")
                     .unwrap()
@@ -91,6 +225,11 @@ async fn main() -> anyhow::Result<()> {
                     .unwrap()
                     .parse::<u16>()
                     .unwrap(),
+                record_path: matches.value_of("record").map(PathBuf::from),
+                mains_frequency: matches.value_of("mains-frequency").unwrap().parse()?,
+                gestures: load_gestures(matches.value_of("gestures"))?,
+                gestures_path: matches.value_of("gestures").map(PathBuf::from),
+                profile: matches.value_of("profile").unwrap().to_string(),
             })
             .await
         }
@@ -105,6 +244,62 @@ async fn main() -> anyhow::Result<()> {
                 )
                 .await?
         }
+        ("replay", Some(matches)) => {
+            let detectors = matches
+                .values_of("detector")
+                .into_iter()
+                .flatten()
+                .map(|value| match value.split_once(':') {
+                    Some((channel, detector)) => Ok((Some(channel.parse()?), detector.parse()?)),
+                    None => Ok((None, value.parse()?)),
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            crate::replay::replay(
+                matches.value_of("recording").unwrap(),
+                !matches.is_present("default-variables"),
+                &detectors,
+                matches.value_of("mains-frequency").unwrap().parse()?,
+                load_gestures(matches.value_of("gestures"))?,
+                &mut std::io::stdout().lock(),
+            )
+        }
+        ("learn-weights", Some(matches)) => {
+            let contractions = matches
+                .values_of("contraction")
+                .unwrap()
+                .map(|value| {
+                    let (channel, path) = value.split_once(':').with_context(|| {
+                        format!("--contraction {:?} isn't <channel>:<recording>", value)
+                    })?;
+                    Ok((channel.parse()?, PathBuf::from(path)))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            crate::weight_learning::learn_weights(
+                matches.value_of("rest").unwrap(),
+                &contractions,
+                matches.value_of("profile").unwrap(),
+                matches.value_of("mains-frequency").unwrap().parse()?,
+            )
+        }
+        ("simulate", Some(matches)) => {
+            let script = match matches.value_of("script") {
+                Some(path) => SimulatorScript::load(path)?,
+                None => SimulatorScript::default(),
+            };
+            let output = match matches.value_of("tcp-port") {
+                Some(port) => SimulatorOutput::Tcp {
+                    port: port.parse::<u16>()?,
+                },
+                None => SimulatorOutput::PseudoTerminal,
+            };
+            crate::simulator::simulate(script, output).await
+        }
+        ("list-serial-ports", Some(_)) => {
+            for description in crate::serial_port_communication::describe_available_ports()? {
+                println!("{}", description);
+            }
+            Ok(())
+        }
         _ => {
             unreachable!()
         }
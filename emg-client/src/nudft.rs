@@ -0,0 +1,168 @@
+use num_complex::Complex;
+use std::collections::VecDeque;
+use std::f64::consts::TAU;
+
+/*
+
+The DFTs that `Signal` watches for activity: for each frequency, over the last `window_size`
+samples, the mean of the samples times their directions (`cis(time * TAU * frequency)`), corrected
+for the mean of the samples, so that a constant offset doesn't show up at any frequency. The
+samples don't have to be evenly spaced in time, hence "non-uniform".
+
+Doing that for every frequency separately meant keeping windows of the samples, directions and
+summands for each one, and a sine and cosine per frequency per sample. Instead, this keeps one
+window of the samples, and running sums for each frequency, which each sample adds to and the sample
+that leaves the window subtracts from, like a sliding DFT. The frequencies are all multiples of the
+lowest one, so every direction is a power of that one's, and a sample only needs one sine and cosine
+to get all of them (and another for the sample that leaves).
+
+Repeatedly adding and subtracting would make the sums drift, so like `Window`, they're remade from
+scratch every `window_size` samples.
+
+`benches/nudft_bank.rs` compares this with the separate windows.
+
+*/
+
+pub struct NudftBank {
+    base_frequency: f64,
+    window_size: usize,
+    num_samples_seen: u64,
+    // (time, value)
+    samples: VecDeque<(f64, f64)>,
+    value_sum: f64,
+    next_value_sum: f64,
+    // these are indexed by frequency
+    direction_sums: Vec<Complex<f64>>,
+    summand_sums: Vec<Complex<f64>>,
+    next_direction_sums: Vec<Complex<f64>>,
+    next_summand_sums: Vec<Complex<f64>>,
+    norms: Vec<f64>,
+}
+
+impl NudftBank {
+    /// Watches `num_frequencies` frequencies: `base_frequency`, twice that, and so on.
+    pub fn new(base_frequency: f64, num_frequencies: usize, window_size: usize) -> NudftBank {
+        NudftBank {
+            base_frequency,
+            window_size,
+            num_samples_seen: 0,
+            samples: VecDeque::with_capacity(window_size + 1),
+            value_sum: 0.0,
+            next_value_sum: 0.0,
+            direction_sums: vec![Complex::default(); num_frequencies],
+            summand_sums: vec![Complex::default(); num_frequencies],
+            next_direction_sums: vec![Complex::default(); num_frequencies],
+            next_summand_sums: vec![Complex::default(); num_frequencies],
+            norms: vec![0.0; num_frequencies],
+        }
+    }
+
+    pub fn push(&mut self, time: f64, value: f64) {
+        self.num_samples_seen += 1;
+        self.samples.push_back((time, value));
+        let dropped =
+            (self.samples.len() > self.window_size).then(|| self.samples.pop_front().unwrap());
+
+        self.value_sum += value;
+        self.next_value_sum += value;
+        let base_direction = Complex::cis(time * TAU * self.base_frequency);
+        let mut direction = base_direction;
+        for ((direction_sum, summand_sum), (next_direction_sum, next_summand_sum)) in self
+            .direction_sums
+            .iter_mut()
+            .zip(&mut self.summand_sums)
+            .zip(
+                self.next_direction_sums
+                    .iter_mut()
+                    .zip(&mut self.next_summand_sums),
+            )
+        {
+            *direction_sum += direction;
+            *summand_sum += direction * value;
+            *next_direction_sum += direction;
+            *next_summand_sum += direction * value;
+            direction *= base_direction;
+        }
+        if let Some((dropped_time, dropped_value)) = dropped {
+            self.value_sum -= dropped_value;
+            let base_direction = Complex::cis(dropped_time * TAU * self.base_frequency);
+            let mut direction = base_direction;
+            for (direction_sum, summand_sum) in
+                self.direction_sums.iter_mut().zip(&mut self.summand_sums)
+            {
+                *direction_sum -= direction;
+                *summand_sum -= direction * dropped_value;
+                direction *= base_direction;
+            }
+        }
+        if self.num_samples_seen.is_multiple_of(self.window_size as u64) {
+            self.value_sum = std::mem::take(&mut self.next_value_sum);
+            std::mem::swap(&mut self.direction_sums, &mut self.next_direction_sums);
+            std::mem::swap(&mut self.summand_sums, &mut self.next_summand_sums);
+            self.next_direction_sums.fill(Complex::default());
+            self.next_summand_sums.fill(Complex::default());
+        }
+
+        let scale = 1.0 / self.samples.len() as f64;
+        let value_mean = self.value_sum * scale;
+        for ((norm, direction_sum), summand_sum) in self
+            .norms
+            .iter_mut()
+            .zip(&self.direction_sums)
+            .zip(&self.summand_sums)
+        {
+            // `norm()` uses `hypot`, which is most of the cost, and the values are nowhere near overflowing
+            *norm = ((summand_sum - direction_sum * value_mean) * scale)
+                .norm_sqr()
+                .sqrt();
+        }
+    }
+
+    /// The corrected DFT norm at each frequency, as of the latest sample.
+    pub fn norms(&self) -> &[f64] {
+        &self.norms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_direct_computation() {
+        let (base_frequency, num_frequencies, window_size) = (10.2, 49, 50);
+        let mut bank = NudftBank::new(base_frequency, num_frequencies, window_size);
+        let mut samples = VecDeque::new();
+        for index in 0..2000 {
+            // unevenly spaced, and a long way from time 0
+            let time = 5000.0 + index as f64 / 1020.0 + (index % 7) as f64 * 1e-5;
+            let value = (index as f64 * 0.37).sin() * 3.0 + (index % 13) as f64 * 0.1 + 100.0;
+            bank.push(time, value);
+            samples.push_back((time, value));
+            if samples.len() > window_size {
+                samples.pop_front();
+            }
+            let count = samples.len() as f64;
+            let value_mean = samples.iter().map(|&(_, v)| v).sum::<f64>() / count;
+            for (frequency_index, &norm) in bank.norms().iter().enumerate() {
+                let frequency = base_frequency * (frequency_index + 1) as f64;
+                let (direction_sum, summand_sum) = samples.iter().fold(
+                    (Complex::default(), Complex::default()),
+                    |(directions, summands): (Complex<f64>, Complex<f64>), &(t, v)| {
+                        let direction = Complex::cis(t * TAU * frequency);
+                        (directions + direction, summands + direction * v)
+                    },
+                );
+                let expected = (summand_sum / count - direction_sum / count * value_mean).norm();
+                assert!(
+                    (norm - expected).abs() <= 1e-9 * (1.0 + expected),
+                    "sample {}, frequency {}: {} != {}",
+                    index,
+                    frequency,
+                    norm,
+                    expected
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,299 @@
+use crate::crosstalk::CrossTalk;
+use crate::variables::{ChannelVariables, FrequencyWeights};
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/*
+
+Named sets of variables, e.g. one per electrode placement, so that they don't have to be retuned
+after every restart. Each profile is a JSON file in the profiles directory:
+
+{
+  "variables": { "activity_threshold": 55, "tap_max_duration": 0.25 },
+  "channel_variables": { "2": { "activity_threshold": 80 } },
+  "frequency_weights": { "2": [0.2, 0.5, 1.8, ...] },
+  "crosstalk": { "3": { "2": 0.3 } }
+}
+
+Variables that a profile doesn't mention keep their defaults, and ones it mentions that don't exist
+(any more) are ignored.
+
+Changes are autosaved to a separate file (`<name>.autosave.json`) about once a second, so
+(almost) nothing is lost by restarting, but the profile itself only changes when it's saved; reverting goes back to it.
+
+*/
+
+pub const PROFILES_DIRECTORY: &str = "profiles";
+pub const DEFAULT_PROFILE: &str = "default";
+const AUTOSAVE_SUFFIX: &str = ".autosave";
+
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Profile {
+    pub variables: HashMap<String, f64>,
+    /// overrides for individual channels, by channel number
+    pub channel_variables: ChannelVariables,
+    /// from calibrating, by channel number
+    pub frequency_weights: FrequencyWeights,
+    /// also from calibrating; see `crosstalk.rs`
+    pub crosstalk: CrossTalk,
+}
+
+pub fn check_profile_name(name: &str) -> anyhow::Result<()> {
+    if name.is_empty()
+        || name.ends_with(AUTOSAVE_SUFFIX)
+        || !name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == ' ')
+    {
+        bail!(
+            "{:?} can't be a profile name; use letters, numbers, spaces, _ and -",
+            name
+        );
+    }
+    Ok(())
+}
+
+fn profile_path(directory: &Path, name: &str) -> PathBuf {
+    directory.join(format!("{}.json", name))
+}
+
+fn autosave_path(directory: &Path, name: &str) -> PathBuf {
+    directory.join(format!("{}{}.json", name, AUTOSAVE_SUFFIX))
+}
+
+impl Profile {
+    fn read(path: &Path) -> anyhow::Result<Option<Profile>> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+        };
+        let profile = serde_json::from_str(&text)
+            .with_context(|| format!("failed to parse profile {}", path.display()))?;
+        Ok(Some(profile))
+    }
+
+    fn write(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).context("failed to create profiles directory")?;
+        }
+        std::fs::write(path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("failed to write {}", path.display()))
+    }
+
+    /// Loads the profile as it was last saved, or `None` if it has never been saved.
+    pub fn load_saved(directory: impl AsRef<Path>, name: &str) -> anyhow::Result<Option<Profile>> {
+        check_profile_name(name)?;
+        Profile::read(&profile_path(directory.as_ref(), name))
+    }
+
+    /// Loads the profile including any unsaved changes, which are reported by the `bool`.
+    pub fn load_latest(
+        directory: impl AsRef<Path>,
+        name: &str,
+    ) -> anyhow::Result<(Option<Profile>, bool)> {
+        check_profile_name(name)?;
+        let directory = directory.as_ref();
+        if let Some(autosaved) = Profile::read(&autosave_path(directory, name))? {
+            return Ok((Some(autosaved), true));
+        }
+        Ok((Profile::read(&profile_path(directory, name))?, false))
+    }
+
+    /// Saves the profile, replacing any unsaved changes.
+    pub fn save(&self, directory: impl AsRef<Path>, name: &str) -> anyhow::Result<()> {
+        check_profile_name(name)?;
+        let directory = directory.as_ref();
+        self.write(&profile_path(directory, name))?;
+        discard_unsaved_changes(directory, name)
+    }
+
+    pub fn autosave(&self, directory: impl AsRef<Path>, name: &str) -> anyhow::Result<()> {
+        check_profile_name(name)?;
+        self.write(&autosave_path(directory.as_ref(), name))
+    }
+
+    /// Makes the same change to the saved profile (creating it if it has never been saved) and to
+    /// its unsaved changes, if it has any, which stay unsaved.
+    pub fn update(
+        directory: impl AsRef<Path>,
+        name: &str,
+        change: impl Fn(&mut Profile),
+    ) -> anyhow::Result<()> {
+        check_profile_name(name)?;
+        let directory = directory.as_ref();
+        let path = profile_path(directory, name);
+        let mut saved = Profile::read(&path)?.unwrap_or_default();
+        change(&mut saved);
+        saved.write(&path)?;
+        let path = autosave_path(directory, name);
+        if let Some(mut autosaved) = Profile::read(&path)? {
+            change(&mut autosaved);
+            autosaved.write(&path)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn discard_unsaved_changes(directory: impl AsRef<Path>, name: &str) -> anyhow::Result<()> {
+    let path = autosave_path(directory.as_ref(), name);
+    match std::fs::remove_file(&path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("failed to remove {}", path.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The names of all profiles that have been saved or autosaved, in alphabetical order.
+pub fn list_profiles(directory: impl AsRef<Path>) -> Vec<String> {
+    let mut names: Vec<String> = std::fs::read_dir(directory)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let file_name = entry.file_name().into_string().ok()?;
+            let name = file_name.strip_suffix(".json")?;
+            let name = name.strip_suffix(AUTOSAVE_SUFFIX).unwrap_or(name);
+            check_profile_name(name).ok()?;
+            Some(name.to_string())
+        })
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn test_directory(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("emg_profiles_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        directory
+    }
+
+    fn profile(activity_threshold: f64) -> Profile {
+        Profile {
+            variables: HashMap::from([("activity_threshold".to_string(), activity_threshold)]),
+            channel_variables: BTreeMap::from([(
+                2,
+                HashMap::from([("activity_threshold".to_string(), 80.0)]),
+            )]),
+            frequency_weights: BTreeMap::from([(2, vec![0.5, 1.0, 1.5])]),
+            crosstalk: BTreeMap::from([(3, BTreeMap::from([(2, 0.3)]))]),
+        }
+    }
+
+    #[test]
+    fn saved_profiles_load_again() {
+        let directory = test_directory("save");
+        assert_eq!(Profile::load_saved(&directory, "arm").unwrap(), None);
+        assert_eq!(
+            Profile::load_latest(&directory, "arm").unwrap(),
+            (None, false)
+        );
+
+        profile(55.0).save(&directory, "arm").unwrap();
+        assert_eq!(
+            Profile::load_saved(&directory, "arm").unwrap(),
+            Some(profile(55.0))
+        );
+        assert_eq!(
+            Profile::load_latest(&directory, "arm").unwrap(),
+            (Some(profile(55.0)), false)
+        );
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn autosaves_are_latest_until_saved_or_discarded() {
+        let directory = test_directory("autosave");
+        profile(55.0).save(&directory, "arm").unwrap();
+        profile(60.0).autosave(&directory, "arm").unwrap();
+        assert_eq!(
+            Profile::load_saved(&directory, "arm").unwrap(),
+            Some(profile(55.0))
+        );
+        assert_eq!(
+            Profile::load_latest(&directory, "arm").unwrap(),
+            (Some(profile(60.0)), true)
+        );
+
+        discard_unsaved_changes(&directory, "arm").unwrap();
+        assert_eq!(
+            Profile::load_latest(&directory, "arm").unwrap(),
+            (Some(profile(55.0)), false)
+        );
+
+        profile(65.0).autosave(&directory, "arm").unwrap();
+        profile(65.0).save(&directory, "arm").unwrap();
+        assert_eq!(
+            Profile::load_latest(&directory, "arm").unwrap(),
+            (Some(profile(65.0)), false)
+        );
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn updates_change_saved_and_unsaved_profiles() {
+        let directory = test_directory("update");
+        let set_weights = |profile: &mut Profile| {
+            profile.frequency_weights.insert(3, vec![2.0, 0.0]);
+        };
+        let updated = |mut profile: Profile| {
+            set_weights(&mut profile);
+            profile
+        };
+
+        Profile::update(&directory, "new", set_weights).unwrap();
+        assert_eq!(
+            Profile::load_latest(&directory, "new").unwrap(),
+            (Some(updated(Profile::default())), false)
+        );
+
+        profile(55.0).save(&directory, "arm").unwrap();
+        profile(60.0).autosave(&directory, "arm").unwrap();
+        Profile::update(&directory, "arm", set_weights).unwrap();
+        assert_eq!(
+            Profile::load_saved(&directory, "arm").unwrap(),
+            Some(updated(profile(55.0)))
+        );
+        assert_eq!(
+            Profile::load_latest(&directory, "arm").unwrap(),
+            (Some(updated(profile(60.0))), true)
+        );
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn profiles_are_listed_once_each() {
+        let directory = test_directory("list");
+        assert!(list_profiles(&directory).is_empty());
+        profile(55.0).save(&directory, "right arm").unwrap();
+        profile(55.0).save(&directory, "left arm").unwrap();
+        profile(60.0).autosave(&directory, "left arm").unwrap();
+        profile(60.0).autosave(&directory, "unsaved").unwrap();
+        std::fs::write(directory.join("notes.txt"), "").unwrap();
+        assert_eq!(
+            list_profiles(&directory),
+            vec!["left arm", "right arm", "unsaved"]
+        );
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn bad_names_are_rejected() {
+        for name in ["", "../arm", "arm.autosave", "arm/left"] {
+            assert!(check_profile_name(name).is_err(), "{:?}", name);
+            assert!(profile(55.0).save(std::env::temp_dir(), name).is_err());
+        }
+        check_profile_name("left arm_2-b").unwrap();
+    }
+}
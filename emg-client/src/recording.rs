@@ -0,0 +1,384 @@
+use crate::crosstalk::CrossTalk;
+use crate::sample_source::ReportFromServer;
+use crate::variables::{ChannelVariables, FrequencyWeights};
+use anyhow::{bail, Context};
+use emg_mouse_shared::codec;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/**
+
+On-disk format for raw EMG sessions.
+
+A recording file is the 8-byte `RECORDING_MAGIC`, then the format version as a little-endian u32,
+then a bincode-serialized `RecordingHeader`, then any number of bincode-serialized
+`RecordedReport`s until the end of the file. Samples are stored in the same 12-bit packed
+form the server sends them in, so a recording is barely bigger than the original byte stream.
+
+The header says how many channels each server has and how fast it samples, as far as we knew when
+the recording started. A report only says so again if it's different from the server's previous
+report (or the header), e.g. because the server restarted with other settings or hadn't connected yet.
+
+If the format ever changes, bump `RECORDING_FORMAT_VERSION` rather than changing the meaning
+of old files.
+
+*/
+pub const RECORDING_MAGIC: [u8; 8] = *b"emg_rec\0";
+pub const RECORDING_FORMAT_VERSION: u32 = 1;
+
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct RecordingHeader {
+    /// by server index
+    pub servers: Vec<RecordedServer>,
+    pub variables: HashMap<String, f64>,
+    pub channel_variables: ChannelVariables,
+    pub frequency_weights: FrequencyWeights,
+    pub crosstalk: CrossTalk,
+}
+
+#[derive(Copy, Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct RecordedServer {
+    /// 0 if it hadn't said yet
+    pub num_channels: u8,
+    /// in Hz
+    pub nominal_sample_rate: f64,
+}
+
+// what's stored for each report
+#[derive(Serialize, Deserialize)]
+struct StoredReport {
+    server_index: u32,
+    server_run_id: u64,
+    first_sample_index: u64,
+    /// in the server's packing (see `pack_samples`)
+    packed_samples: Vec<u8>,
+    micros_since_recording_start: u64,
+    /// only if it's different from before
+    changed_server: Option<RecordedServer>,
+}
+
+/// A report as it's read back, with its server's channel count and sample rate filled in.
+#[derive(Clone, PartialEq, Debug)]
+pub struct RecordedReport {
+    pub server_index: u32,
+    pub server_run_id: u64,
+    pub first_sample_index: u64,
+    pub num_channels: u8,
+    /// in the server's packing (see `pack_samples`)
+    pub packed_samples: Vec<u8>,
+    /// when the report was received, relative to the start of the recording
+    pub micros_since_recording_start: u64,
+    /// in Hz
+    pub sample_rate: f64,
+}
+
+pub struct Recorder {
+    path: PathBuf,
+    start_time: Instant,
+    writer: BufWriter<File>,
+    // what the recording says about each server so far
+    servers: Vec<RecordedServer>,
+}
+
+pub struct RecordingReader {
+    header: RecordingHeader,
+    reader: BufReader<File>,
+    // what the recording has said about each server so far
+    servers: Vec<RecordedServer>,
+}
+
+/// Panics if the samples don't all have the same number of channels.
+pub fn pack_samples(samples: &[Vec<u16>]) -> Vec<u8> {
+    let Some(num_channels) = samples.first().map(Vec::len) else {
+        return Vec::new();
+    };
+    let bytes_per_sample = codec::bytes_per_sample(num_channels);
+    let mut result = vec![0; samples.len() * bytes_per_sample];
+    for (chunk, sample) in result.chunks_exact_mut(bytes_per_sample).zip(samples) {
+        codec::pack_sample(sample, chunk);
+    }
+    result
+}
+
+pub fn unpack_samples(packed: &[u8], num_channels: usize) -> Vec<Vec<u16>> {
+    codec::unpack_samples(packed, num_channels)
+        .map(Iterator::collect)
+        .collect()
+}
+
+/// A fresh path in `directory`, named after the current time, for recordings that weren't given an explicit path.
+pub fn default_recording_path(directory: impl AsRef<Path>) -> PathBuf {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    directory
+        .as_ref()
+        .join(format!("session_{}.emgrec", seconds))
+}
+
+impl Recorder {
+    pub fn create(path: impl Into<PathBuf>, header: &RecordingHeader) -> anyhow::Result<Recorder> {
+        let path = path.into();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).context("failed to create recording directory")?;
+        }
+        if path.exists() {
+            bail!(
+                "refusing to overwrite existing recording {}",
+                path.display()
+            );
+        }
+        let mut writer = BufWriter::new(
+            File::create(&path)
+                .with_context(|| format!("failed to create recording {}", path.display()))?,
+        );
+        writer.write_all(&RECORDING_MAGIC)?;
+        writer.write_all(&RECORDING_FORMAT_VERSION.to_le_bytes())?;
+        bincode::serialize_into(&mut writer, header)?;
+        Ok(Recorder {
+            path,
+            start_time: Instant::now(),
+            writer,
+            servers: header.servers.clone(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn record(
+        &mut self,
+        server_index: usize,
+        local_time_received: Instant,
+        report: &ReportFromServer,
+        sample_rate: f64,
+    ) -> anyhow::Result<()> {
+        let server = RecordedServer {
+            num_channels: report.num_channels().try_into()?,
+            nominal_sample_rate: sample_rate,
+        };
+        if self.servers.len() <= server_index {
+            self.servers.resize(
+                server_index + 1,
+                RecordedServer {
+                    num_channels: 0,
+                    nominal_sample_rate: 0.0,
+                },
+            );
+        }
+        let changed_server = (self.servers[server_index] != server).then_some(server);
+        self.servers[server_index] = server;
+        let entry = StoredReport {
+            server_index: server_index.try_into()?,
+            server_run_id: report.server_run_id,
+            first_sample_index: report.first_sample_index,
+            packed_samples: pack_samples(&report.samples),
+            micros_since_recording_start: local_time_received
+                .saturating_duration_since(self.start_time)
+                .as_micros()
+                .try_into()?,
+            changed_server,
+        };
+        bincode::serialize_into(&mut self.writer, &entry)?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> anyhow::Result<PathBuf> {
+        self.writer.flush()?;
+        Ok(self.path)
+    }
+}
+
+impl RecordedReport {
+    pub fn to_report(&self) -> ReportFromServer {
+        ReportFromServer {
+            server_run_id: self.server_run_id,
+            first_sample_index: self.first_sample_index,
+            samples: unpack_samples(&self.packed_samples, self.num_channels as usize),
+        }
+    }
+}
+
+impl RecordingReader {
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<RecordingReader> {
+        let path = path.as_ref();
+        let mut reader = BufReader::new(
+            File::open(path)
+                .with_context(|| format!("failed to open recording {}", path.display()))?,
+        );
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if magic != RECORDING_MAGIC {
+            bail!("{} is not an EMG recording", path.display());
+        }
+        let mut version = [0; 4];
+        reader.read_exact(&mut version)?;
+        let version = u32::from_le_bytes(version);
+        if version != RECORDING_FORMAT_VERSION {
+            bail!(
+                "{} has recording format version {}, but we only understand version {}",
+                path.display(),
+                version,
+                RECORDING_FORMAT_VERSION
+            );
+        }
+        let header: RecordingHeader = bincode::deserialize_from(&mut reader)?;
+        Ok(RecordingReader {
+            servers: header.servers.clone(),
+            header,
+            reader,
+        })
+    }
+
+    pub fn header(&self) -> &RecordingHeader {
+        &self.header
+    }
+
+    /// The next report, or None at the end of the file.
+    pub fn next_report(&mut self) -> anyhow::Result<Option<RecordedReport>> {
+        if self.reader.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+        let stored: StoredReport = bincode::deserialize_from(&mut self.reader)?;
+        let server_index = stored.server_index as usize;
+        if let Some(server) = stored.changed_server {
+            if self.servers.len() <= server_index {
+                self.servers.resize(server_index + 1, server);
+            }
+            self.servers[server_index] = server;
+        }
+        let Some(&server) = self.servers.get(server_index) else {
+            bail!(
+                "the recording never says how many channels server {} has",
+                server_index
+            );
+        };
+        Ok(Some(RecordedReport {
+            server_index: stored.server_index,
+            server_run_id: stored.server_run_id,
+            first_sample_index: stored.first_sample_index,
+            num_channels: server.num_channels,
+            packed_samples: stored.packed_samples,
+            micros_since_recording_start: stored.micros_since_recording_start,
+            sample_rate: server.nominal_sample_rate,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    fn report(server_run_id: u64, first_sample_index: u64, num_channels: u16) -> ReportFromServer {
+        ReportFromServer {
+            server_run_id,
+            first_sample_index,
+            samples: (0..10)
+                .map(|index| {
+                    (0..num_channels)
+                        .map(|channel| (index * 409 + channel * 37) % 4096)
+                        .collect()
+                })
+                .collect(),
+        }
+    }
+
+    fn server(num_channels: u8, nominal_sample_rate: f64) -> RecordedServer {
+        RecordedServer {
+            num_channels,
+            nominal_sample_rate,
+        }
+    }
+
+    #[test]
+    fn recordings_read_back_as_written() {
+        let path =
+            std::env::temp_dir().join(format!("emg_recording_{}.emgrec", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let header = RecordingHeader {
+            servers: vec![server(4, 1020.0), server(8, 500.0)],
+            variables: HashMap::from([("activity_threshold".to_string(), 55.0)]),
+            channel_variables: BTreeMap::from([(
+                2,
+                HashMap::from([("activity_threshold".to_string(), 80.0)]),
+            )]),
+            frequency_weights: BTreeMap::from([(2, vec![0.5, 1.0, 1.5])]),
+            crosstalk: BTreeMap::from([(3, BTreeMap::from([(2, 0.3)]))]),
+        };
+        let reports = [
+            (0, report(7, 0, 4), 1020.0),
+            (1, report(9, 100, 8), 500.0),
+            (0, report(7, 10, 4), 1020.0),
+            // server 1 restarts with other settings, and server 2 connects
+            (1, report(10, 0, 6), 250.0),
+            (2, report(3, 0, 2), 1000.0),
+            (1, report(10, 10, 6), 250.0),
+        ];
+        let mut recorder = Recorder::create(&path, &header).unwrap();
+        assert!(Recorder::create(&path, &header).is_err());
+        let start = Instant::now();
+        for (index, (server_index, report, sample_rate)) in reports.iter().enumerate() {
+            let received = start + Duration::from_millis(index as u64 * 10);
+            recorder
+                .record(*server_index, received, report, *sample_rate)
+                .unwrap();
+        }
+        assert_eq!(recorder.finish().unwrap(), path);
+
+        let mut reader = RecordingReader::open(&path).unwrap();
+        assert_eq!(reader.header(), &header);
+        for (index, (server_index, report, sample_rate)) in reports.iter().enumerate() {
+            let recorded = reader.next_report().unwrap().unwrap();
+            assert!(recorded.micros_since_recording_start >= index as u64 * 10_000);
+            assert_eq!(recorded.server_index as usize, *server_index);
+            assert_eq!(recorded.sample_rate, *sample_rate);
+            let unpacked = recorded.to_report();
+            assert_eq!(unpacked.server_run_id, report.server_run_id);
+            assert_eq!(unpacked.first_sample_index, report.first_sample_index);
+            assert_eq!(unpacked.samples, report.samples);
+        }
+        assert_eq!(reader.next_report().unwrap(), None);
+
+        // the channel counts and sample rates are only stored again when they change
+        let mut file = BufReader::new(File::open(&path).unwrap());
+        file.read_exact(&mut [0; 12]).unwrap();
+        let _: RecordingHeader = bincode::deserialize_from(&mut file).unwrap();
+        let mut changes = Vec::new();
+        while !file.fill_buf().unwrap().is_empty() {
+            let stored: StoredReport = bincode::deserialize_from(&mut file).unwrap();
+            changes.push(stored.changed_server);
+        }
+        assert_eq!(
+            changes,
+            vec![
+                None,
+                None,
+                None,
+                Some(server(6, 250.0)),
+                Some(server(2, 1000.0)),
+                None
+            ]
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn other_files_are_rejected() {
+        let path = std::env::temp_dir().join(format!("emg_not_a_recording_{}", std::process::id()));
+        std::fs::write(&path, b"not a recording at all").unwrap();
+        assert!(RecordingReader::open(&path).is_err());
+
+        let mut future = RECORDING_MAGIC.to_vec();
+        future.extend((RECORDING_FORMAT_VERSION + 1).to_le_bytes());
+        std::fs::write(&path, future).unwrap();
+        assert!(RecordingReader::open(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,363 @@
+use crate::activity_detectors::DetectorKind;
+use crate::artifacts::Artifact;
+use crate::decisions::{DecisionContext, DecisionMaker, DecisionObserver};
+use crate::follower::MessageToFollower;
+use crate::gestures::GestureMapping;
+use crate::recording::RecordingReader;
+use crate::signal::{MainsFrequency, Signal};
+use crate::taps::TapEvent;
+use crate::timeline::{SampleGap, Timeline, TimelineSample};
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/*
+
+Runs a recorded session through the same signal processing and decision logic as the supervisor,
+and prints what it would have done. Nothing here touches the mouse, audio or network,
+and the output only depends on the recording and the variables, so two runs can be diffed.
+
+Samples from different servers are put in order using the times they were received at,
+just like the supervisor does.
+
+Since there is no real mouse during a replay, we pretend that input is enabled and that the mouse
+was last moved a little while ago – the situation in which the supervisor acts on everything it detects.
+
+*/
+
+#[derive(Default)]
+struct ReplayedServer {
+    server_run_id: u64,
+    sample_rate: f64,
+    signals: Vec<Signal>,
+}
+
+struct ReplayPrinter<'a, W: Write> {
+    out: &'a mut W,
+    server_index: usize,
+    sample_index: u64,
+    time: f64,
+    activations: usize,
+    suppressed_activations: usize,
+    clicks: usize,
+    scroll_events: usize,
+}
+
+impl<'a, W: Write> ReplayPrinter<'a, W> {
+    fn new(out: &'a mut W) -> Self {
+        ReplayPrinter {
+            out,
+            server_index: 0,
+            sample_index: 0,
+            time: 0.0,
+            activations: 0,
+            suppressed_activations: 0,
+            clicks: 0,
+            scroll_events: 0,
+        }
+    }
+
+    fn print_event(&mut self, description: std::fmt::Arguments) {
+        // like `println!`, which this used to be
+        writeln!(
+            self.out,
+            "{:12.4}s  server {} sample {:>10}  {}",
+            self.time, self.server_index, self.sample_index, description
+        )
+        .expect("failed to write replay output");
+    }
+}
+
+impl<W: Write> DecisionObserver for ReplayPrinter<'_, W> {
+    fn activity_changed(&mut self, _server_index: usize, channel: usize, active: bool) {
+        if active {
+            self.activations += 1;
+            self.print_event(format_args!("channel {} active", channel));
+        } else {
+            self.print_event(format_args!("channel {} inactive", channel));
+        }
+    }
+
+    fn activation_suppressed(&mut self, _server_index: usize, channel: usize, artifact: Artifact) {
+        self.suppressed_activations += 1;
+        self.print_event(format_args!(
+            "channel {} not activated: {}",
+            channel, artifact
+        ));
+    }
+
+    fn tap_event(&mut self, _server_index: usize, channel: usize, event: TapEvent) {
+        self.print_event(format_args!("channel {} {:?}", channel, event));
+    }
+
+    fn samples_dropped(&mut self, _server_index: usize, gap: SampleGap) {
+        self.print_event(format_args!(
+            "{} samples missing ({:?})",
+            gap.num_missing, gap.handling
+        ));
+    }
+
+    fn mains_frequency_changed(
+        &mut self,
+        _server_index: usize,
+        channel: usize,
+        frequency: f64,
+        detected: bool,
+    ) {
+        if detected {
+            self.print_event(format_args!(
+                "channel {} detected {} Hz mains",
+                channel, frequency
+            ));
+        }
+    }
+
+    fn follower_message(&mut self, message: MessageToFollower) {
+        match message {
+            MessageToFollower::ButtonDown(_) => self.clicks += 1,
+            MessageToFollower::ScrollX(_) | MessageToFollower::ScrollY(_) => {
+                self.scroll_events += 1
+            }
+            _ => {}
+        }
+        self.print_event(format_args!("{:?}", message));
+    }
+}
+
+/// `detectors` override the activity detector of a channel, or with no channel, of all of them.
+/// Everything is written to `out`.
+pub fn replay(
+    path: impl AsRef<Path>,
+    use_recorded_variables: bool,
+    detectors: &[(Option<usize>, DetectorKind)],
+    mains_frequency: MainsFrequency,
+    gestures: GestureMapping,
+    out: &mut impl Write,
+) -> anyhow::Result<()> {
+    let mut reader = RecordingReader::open(path)?;
+    let header = reader.header().clone();
+    if use_recorded_variables {
+        for e in crate::variables::reset_variables(&header.variables, &header.channel_variables) {
+            writeln!(out, "# ignoring recorded variable: {:#}", e)?;
+        }
+        let mut variables: Vec<_> = crate::variables::get_variables()
+            .into_iter()
+            .filter(|(key, value)| header.variables.get(key) == Some(value))
+            .collect();
+        variables.sort_by(|a, b| a.0.cmp(&b.0));
+        for (key, value) in variables {
+            writeln!(out, "# {} = {}", key, value)?;
+        }
+        for (channel, values) in crate::variables::get_channel_variables() {
+            let mut values: Vec<_> = values.into_iter().collect();
+            values.sort_by(|a, b| a.0.cmp(&b.0));
+            for (key, value) in values {
+                writeln!(out, "# channel {} {} = {}", channel, key, value)?;
+            }
+        }
+        for e in crate::variables::reset_frequency_weights(&header.frequency_weights) {
+            writeln!(out, "# ignoring recorded frequency weights: {:#}", e)?;
+        }
+        for channel in crate::variables::get_frequency_weights().keys() {
+            writeln!(
+                out,
+                "# channel {} has calibrated frequency weights",
+                channel
+            )?;
+        }
+    }
+    for &(channel, detector) in detectors {
+        let value = detector.variable_value();
+        match channel {
+            Some(channel) => {
+                crate::variables::set_channel_variable(channel, "activity_detector", Some(value))?;
+                writeln!(
+                    out,
+                    "# channel {} activity_detector = {} ({})",
+                    channel, value, detector
+                )?;
+            }
+            None => {
+                crate::variables::set_variable("activity_detector", value)?;
+                // including the channels that it was overridden for in the recording
+                for &channel in header.channel_variables.keys() {
+                    crate::variables::set_channel_variable(channel, "activity_detector", None)?;
+                }
+                writeln!(out, "# activity_detector = {} ({})", value, detector)?;
+            }
+        }
+    }
+
+    let context = DecisionContext {
+        enabled: true,
+        recently_moved: false,
+        anywhere_near_recently_moved: true,
+    };
+    let mut servers: Vec<ReplayedServer> = Vec::new();
+    // the timeline needs an Instant for everything, so pretend the recording started now
+    let start_time = Instant::now();
+    let mut timeline = Timeline::new(start_time, 0);
+    let mut decision_maker = DecisionMaker::new(gestures);
+    if use_recorded_variables {
+        match decision_maker.set_crosstalk(header.crosstalk.clone()) {
+            Ok(()) => {
+                for (channel, sources) in &header.crosstalk {
+                    for (source, fraction) in sources {
+                        writeln!(
+                            out,
+                            "# channel {} picks up {} of channel {}",
+                            channel, fraction, source
+                        )?;
+                    }
+                }
+            }
+            Err(e) => writeln!(out, "# ignoring recorded cross-talk: {:#}", e)?,
+        }
+    }
+    let mut printer = ReplayPrinter::new(out);
+    let mut process = |sample: TimelineSample,
+                       servers: &mut Vec<ReplayedServer>,
+                       printer: &mut ReplayPrinter<_>| {
+        printer.server_index = sample.server_index;
+        printer.sample_index = sample.sample_index;
+        printer.time = sample.local_time;
+        let first_channel = servers[..sample.server_index]
+            .iter()
+            .map(|server| server.signals.len())
+            .sum();
+        let time = sample.sample_index as f64 / servers[sample.server_index].sample_rate;
+        decision_maker.process_sample(
+            &mut servers[sample.server_index].signals,
+            first_channel,
+            &sample,
+            time,
+            context,
+            printer,
+        );
+    };
+    while let Some(recorded) = reader.next_report()? {
+        let server_index = recorded.server_index as usize;
+        let received = start_time + Duration::from_micros(recorded.micros_since_recording_start);
+        let report = recorded.to_report();
+        if servers.len() <= server_index {
+            servers.resize_with(server_index + 1, Default::default);
+            timeline = timeline.with_num_servers(server_index + 1);
+        }
+        let server = &mut servers[server_index];
+        if server.server_run_id != report.server_run_id
+            || server.signals.len() != report.num_channels()
+            || server.sample_rate != recorded.sample_rate
+        {
+            server.server_run_id = report.server_run_id;
+            server.sample_rate = recorded.sample_rate;
+            server.signals = (0..report.num_channels())
+                .map(|_| Signal::new(recorded.sample_rate, mains_frequency))
+                .collect();
+            timeline.restart_server(server_index);
+            writeln!(
+                printer.out,
+                "# server {} run id {}, {} channels at {} Hz",
+                server_index,
+                report.server_run_id,
+                report.num_channels(),
+                recorded.sample_rate
+            )?;
+        }
+        timeline.add_report(server_index, &report, received);
+        while let Some(sample) = timeline.pop_ready(received) {
+            process(sample, &mut servers, &mut printer);
+        }
+    }
+    while let Some(sample) = timeline.pop_any() {
+        process(sample, &mut servers, &mut printer);
+    }
+    writeln!(
+        printer.out,
+        "# {} activations ({} frames held back by artifacts), {} clicks, {} scroll events",
+        printer.activations, printer.suppressed_activations, printer.clicks, printer.scroll_events
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recording::{RecordedServer, Recorder, RecordingHeader};
+    use crate::sample_source::ReportFromServer;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::f64::consts::TAU;
+
+    /// A recording of 4 channels at rest, with two contractions of channel 2.
+    fn write_recording(path: &Path) {
+        let sample_rate = 1020.0;
+        let header = RecordingHeader {
+            servers: vec![RecordedServer {
+                num_channels: 4,
+                nominal_sample_rate: sample_rate,
+            }],
+            variables: Default::default(),
+            channel_variables: Default::default(),
+            frequency_weights: Default::default(),
+            crosstalk: Default::default(),
+        };
+        let _ = std::fs::remove_file(path);
+        let mut recorder = Recorder::create(path, &header).unwrap();
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut noise = || rng.gen_range(-0.5..0.5);
+        let samples: Vec<Vec<u16>> = (0..(sample_rate * 16.0) as usize)
+            .map(|index| {
+                let time = index as f64 / sample_rate;
+                let contracting = (8.0..9.0).contains(&time) || (12.0..13.0).contains(&time);
+                (0..4)
+                    .map(|channel| {
+                        let mut value = 2048.0 + noise() * 16.0;
+                        if channel == 2 && contracting {
+                            for frequency in [80.0, 110.0, 170.0] {
+                                value += (time * TAU * frequency).sin() * 200.0;
+                            }
+                        }
+                        value.round() as u16
+                    })
+                    .collect()
+            })
+            .collect();
+        for (index, chunk) in samples.chunks(10).enumerate() {
+            let report = ReportFromServer {
+                server_run_id: 7,
+                first_sample_index: index as u64 * 10,
+                samples: chunk.to_vec(),
+            };
+            recorder
+                .record(0, Instant::now(), &report, sample_rate)
+                .unwrap();
+        }
+        recorder.finish().unwrap();
+    }
+
+    #[test]
+    fn replaying_twice_gives_the_same_decisions() {
+        let path = std::env::temp_dir().join(format!(
+            "emg_client_replay_test_{}.emgrec",
+            std::process::id()
+        ));
+        write_recording(&path);
+        let run = || {
+            let mut out = Vec::new();
+            replay(
+                &path,
+                false,
+                &[],
+                MainsFrequency::Fixed(60.0),
+                GestureMapping::default(),
+                &mut out,
+            )
+            .unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let (first, second) = (run(), run());
+        std::fs::remove_file(&path).unwrap();
+        assert!(first.contains("channel 2 active"), "{}", first);
+        assert_eq!(first, second);
+    }
+}
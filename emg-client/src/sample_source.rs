@@ -0,0 +1,312 @@
+use crate::frame_parser::{FrameParser, LinkStatistics};
+use crate::recording::RecordingReader;
+use crate::serial_port_communication::{parse_usb_id, read_messages, SerialPortSelector};
+use crate::simulator::{run_simulator, SimulatorScript};
+use anyhow::{anyhow, bail};
+use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use futures::stream::BoxStream;
+use futures::{SinkExt, StreamExt};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::task;
+use tokio::time::{timeout, Instant};
+
+#[derive(Debug)]
+pub struct ReportFromServer {
+    pub server_run_id: u64,
+    pub first_sample_index: u64,
+    /// each has one value per channel, and they all have the same number of channels
+    pub samples: Vec<Vec<u16>>,
+}
+
+impl ReportFromServer {
+    pub fn num_channels(&self) -> usize {
+        self.samples.first().map_or(0, Vec::len)
+    }
+}
+
+/// What we know about a server's samples, apart from the samples themselves.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct SessionInfo {
+    /// in Hz
+    pub sample_rate: f64,
+}
+
+/// What `emg_server.c` samples at.
+pub const DEFAULT_SAMPLE_RATE: f64 = 1020.0;
+
+#[derive(Debug)]
+pub enum SourceEvent {
+    /// always sent before the first report, and again if anything changes
+    SessionInfo(SessionInfo),
+    Report(ReportFromServer),
+    /// the connection was (re)established, so whatever comes next doesn't continue what came before
+    Reconnected,
+    /// sent every so often by sources that parse frames themselves
+    LinkStatistics(LinkStatistics),
+}
+
+/**
+
+Where the samples for one server come from.
+
+Parsed from a command-line spec of the form `kind[:target][,key=value...]`:
+
+* `serial[:path][,baud=115200][,rate=1020]` – the server's UART, e.g. `serial:/dev/ttyUSB0`;
+  or `serial:vid=10c4,pid=ea60[,serial_number=...]` to find it by USB IDs (see `emg_client list-serial-ports`)
+* `ble[:name][,rate=1020]` – BLE notifications from a server advertising the given name
+* `tcp:host:port[,rate=1020]` – the same byte stream as the UART, over TCP (e.g. from `emg_client simulate --tcp-port`)
+* `replay:path[,server=0]` – a recording made by the supervisor, played back in real time
+* `simulate[:script.json]` – an in-process `Simulator`
+
+Devices don't say what rate they sample at, so `rate` (in Hz) has to match how the device was built.
+Recordings and simulator scripts know their own rates.
+
+Every kind of source yields the same `ReportFromServer`s. Sources that can lose their connection
+keep trying to reconnect, and send `SourceEvent::Reconnected` when they do.
+
+*/
+#[derive(Clone, Debug)]
+pub enum SampleSource {
+    Serial {
+        selector: SerialPortSelector,
+        baud_rate: u32,
+        sample_rate: f64,
+    },
+    Bluetooth {
+        name: String,
+        sample_rate: f64,
+    },
+    Tcp {
+        address: String,
+        sample_rate: f64,
+    },
+    Replay {
+        path: PathBuf,
+        server_index: u32,
+    },
+    Simulator {
+        script: Option<PathBuf>,
+    },
+}
+
+impl FromStr for SampleSource {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> anyhow::Result<SampleSource> {
+        let (kind, rest) = spec.split_once(':').unwrap_or((spec, ""));
+        let mut target = None;
+        let mut options = Vec::new();
+        // a tcp target contains a colon, but never a comma, so splitting on commas is safe
+        for part in rest.split(',').filter(|p| !p.is_empty()) {
+            match part.split_once('=') {
+                Some((key, value)) => options.push((key, value)),
+                None if target.is_none() => target = Some(part),
+                None => bail!("source {:?} has more than one target", spec),
+            }
+        }
+        let mut take_option = |key: &str| -> Option<&str> {
+            let index = options.iter().position(|&(k, _)| k == key)?;
+            Some(options.remove(index).1)
+        };
+        let result = match kind {
+            "serial" => {
+                let vid = take_option("vid").map(parse_usb_id).transpose()?;
+                let pid = take_option("pid").map(parse_usb_id).transpose()?;
+                let serial_number = take_option("serial_number").map(str::to_string);
+                let selector = match target {
+                    Some(path) => SerialPortSelector::Path(path.to_string()),
+                    None if vid.is_some() || pid.is_some() || serial_number.is_some() => {
+                        SerialPortSelector::Usb {
+                            vid,
+                            pid,
+                            serial_number,
+                        }
+                    }
+                    None => SerialPortSelector::Path("/dev/ttyUSB0".to_string()),
+                };
+                SampleSource::Serial {
+                    selector,
+                    baud_rate: take_option("baud").unwrap_or("115200").parse()?,
+                    sample_rate: parse_sample_rate(take_option("rate"))?,
+                }
+            }
+            "ble" => SampleSource::Bluetooth {
+                name: target.unwrap_or("ELI_EMG_SERVER").to_string(),
+                sample_rate: parse_sample_rate(take_option("rate"))?,
+            },
+            "tcp" => SampleSource::Tcp {
+                address: target
+                    .ok_or_else(|| anyhow!("tcp source needs an address"))?
+                    .to_string(),
+                sample_rate: parse_sample_rate(take_option("rate"))?,
+            },
+            "replay" => SampleSource::Replay {
+                path: target
+                    .ok_or_else(|| anyhow!("replay source needs a path"))?
+                    .into(),
+                server_index: take_option("server").unwrap_or("0").parse()?,
+            },
+            "simulate" => SampleSource::Simulator {
+                script: target.map(PathBuf::from),
+            },
+            _ => bail!("unknown sample source kind {:?}", kind),
+        };
+        if let Some((key, _)) = options.first() {
+            bail!("unknown option {:?} for {} source", key, kind);
+        }
+        Ok(result)
+    }
+}
+
+fn parse_sample_rate(rate: Option<&str>) -> anyhow::Result<f64> {
+    let sample_rate = match rate {
+        Some(rate) => rate.parse()?,
+        None => DEFAULT_SAMPLE_RATE,
+    };
+    if !(sample_rate > 0.0 && sample_rate.is_finite()) {
+        bail!("sample rate must be positive, not {}", sample_rate);
+    }
+    Ok(sample_rate)
+}
+
+impl SampleSource {
+    pub fn events(self) -> BoxStream<'static, SourceEvent> {
+        // for devices, the sample rate comes from the spec, so it can be sent right away
+        let with_sample_rate = |sample_rate, events: UnboundedReceiver<SourceEvent>| {
+            futures::stream::once(
+                async move { SourceEvent::SessionInfo(SessionInfo { sample_rate }) },
+            )
+            .chain(events)
+            .boxed()
+        };
+        match self {
+            SampleSource::Serial {
+                selector,
+                baud_rate,
+                sample_rate,
+            } => with_sample_rate(
+                sample_rate,
+                crate::serial_port_communication::messages_from_server(selector, baud_rate),
+            ),
+            SampleSource::Bluetooth { name, sample_rate } => {
+                with_sample_rate(sample_rate, crate::bluetooth::messages_from_server(name))
+            }
+            SampleSource::Tcp {
+                address,
+                sample_rate,
+            } => with_sample_rate(sample_rate, messages_from_tcp(address)),
+            SampleSource::Replay { path, server_index } => {
+                messages_from_recording(path, server_index).boxed()
+            }
+            SampleSource::Simulator { script } => messages_from_simulator(script).boxed(),
+        }
+    }
+}
+
+fn messages_from_tcp(address: String) -> UnboundedReceiver<SourceEvent> {
+    let (mut sender, receiver) = futures::channel::mpsc::unbounded();
+    task::spawn(async move {
+        let mut parser = FrameParser::new();
+        while !sender.is_closed() {
+            match timeout(Duration::from_secs(2), TcpStream::connect(&address)).await {
+                Ok(Ok(stream)) => {
+                    if sender.send(SourceEvent::Reconnected).await.is_err() {
+                        break;
+                    }
+                    parser.discard_partial_frame();
+                    match read_messages(stream, &mut parser, sender.clone()).await {
+                        Ok(n) => match n {},
+                        Err(e) => println!("Connection to {} closed due to error: {}", address, e),
+                    }
+                }
+                Ok(Err(e)) => println!("Error connecting to {}: {}", address, e),
+                Err(_) => println!("Timed out connecting to {}", address),
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    });
+    receiver
+}
+
+fn messages_from_recording(path: PathBuf, server_index: u32) -> UnboundedReceiver<SourceEvent> {
+    async fn play(
+        path: &PathBuf,
+        server_index: u32,
+        sender: &mut UnboundedSender<SourceEvent>,
+    ) -> anyhow::Result<()> {
+        let mut reader = RecordingReader::open(path)?;
+        let start = Instant::now();
+        let mut sample_rate = None;
+        while let Some(recorded) = reader.next_report()? {
+            if recorded.server_index != server_index {
+                continue;
+            }
+            if sample_rate != Some(recorded.sample_rate) {
+                sample_rate = Some(recorded.sample_rate);
+                sender
+                    .send(SourceEvent::SessionInfo(SessionInfo {
+                        sample_rate: recorded.sample_rate,
+                    }))
+                    .await?;
+            }
+            tokio::time::sleep_until(
+                start + Duration::from_micros(recorded.micros_since_recording_start),
+            )
+            .await;
+            sender
+                .send(SourceEvent::Report(recorded.to_report()))
+                .await?;
+        }
+        Ok(())
+    }
+
+    let (mut sender, receiver) = futures::channel::mpsc::unbounded();
+    task::spawn(async move {
+        match play(&path, server_index, &mut sender).await {
+            Ok(()) => println!("Replay of {} finished", path.display()),
+            Err(e) => println!("Replay of {} stopped due to error: {}", path.display(), e),
+        }
+    });
+    receiver
+}
+
+fn messages_from_simulator(script: Option<PathBuf>) -> UnboundedReceiver<SourceEvent> {
+    let (mut sender, receiver) = futures::channel::mpsc::unbounded();
+    task::spawn(async move {
+        let script = match script {
+            Some(path) => match SimulatorScript::load(&path) {
+                Ok(script) => script,
+                Err(e) => {
+                    println!("Couldn't start simulator: {:?}", e);
+                    return;
+                }
+            },
+            None => SimulatorScript::default(),
+        };
+        let session_info = SessionInfo {
+            sample_rate: script.sample_rate,
+        };
+        if sender
+            .send(SourceEvent::SessionInfo(session_info))
+            .await
+            .is_err()
+        {
+            return;
+        }
+        // run it through the real parser, so the simulator exercises the same code as a real device
+        let (simulator_end, reader_end) = tokio::io::duplex(4096);
+        task::spawn(async move {
+            if let Err(e) = run_simulator(script, simulator_end).await {
+                println!("Simulator stopped: {}", e);
+            }
+        });
+        match read_messages(reader_end, &mut FrameParser::new(), sender).await {
+            Ok(n) => match n {},
+            Err(e) => println!("Simulator source closed: {}", e),
+        }
+    });
+    receiver
+}
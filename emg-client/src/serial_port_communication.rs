@@ -1,69 +1,293 @@
-use arrayvec::ArrayVec;
-use futures::channel::mpsc::UnboundedSender;
-use futures::{SinkExt, Stream};
-use tokio::io::AsyncReadExt;
+use crate::frame_parser::FrameParser;
+use crate::sample_source::SourceEvent;
+use anyhow::{anyhow, bail};
+use emg_mouse_shared::codec::MAX_FRAME_SIZE;
+use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use futures::SinkExt;
+use std::fmt;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt};
 use tokio::task;
-use tokio_serial::SerialPortBuilderExt;
+use tokio::time::timeout;
+use tokio_serial::{SerialPortBuilderExt, SerialPortInfo, SerialPortType};
 
-#[derive(Debug)]
-pub struct ReportFromServer {
-    pub server_run_id: u64,
-    pub first_sample_index: u64,
-    pub samples: Vec<[u16; 4]>,
+// the server sends something every few ms, so this much silence means the connection is dead,
+// even if the OS hasn't told us so
+const READ_TIMEOUT: Duration = Duration::from_secs(2);
+const MIN_REOPEN_DELAY: Duration = Duration::from_millis(250);
+const MAX_REOPEN_DELAY: Duration = Duration::from_secs(8);
+pub const LINK_STATISTICS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Which serial port to read from. USB ports can be picked by their IDs, so that it doesn't matter which path the OS gave them this time.
+#[derive(Clone, Debug)]
+pub enum SerialPortSelector {
+    Path(String),
+    Usb {
+        vid: Option<u16>,
+        pid: Option<u16>,
+        serial_number: Option<String>,
+    },
 }
 
-const MAX_SEND_SIZE: usize = 16 + 82 * 6;
+impl fmt::Display for SerialPortSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SerialPortSelector::Path(path) => write!(f, "{}", path),
+            SerialPortSelector::Usb {
+                vid,
+                pid,
+                serial_number,
+            } => {
+                write!(f, "USB device")?;
+                if let Some(vid) = vid {
+                    write!(f, " vid={:04x}", vid)?;
+                }
+                if let Some(pid) = pid {
+                    write!(f, " pid={:04x}", pid)?;
+                }
+                if let Some(serial_number) = serial_number {
+                    write!(f, " serial_number={}", serial_number)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
 
-async fn read_messages(mut sender: UnboundedSender<ReportFromServer>) -> Result<!, anyhow::Error> {
-    let mut stream = tokio_serial::new("/dev/ttyUSB0", 115200)
-        .open_native_async()
-        .unwrap();
-    let mut buffer = [0; MAX_SEND_SIZE];
-    let mut recent = ArrayVec::<_, 8>::new();
-    loop {
-        let val = stream.read_u8().await?;
-        if recent.len() >= 8 {
-            recent.remove(0);
+impl SerialPortSelector {
+    fn matches(&self, port: &SerialPortInfo) -> bool {
+        match self {
+            SerialPortSelector::Path(path) => &port.port_name == path,
+            SerialPortSelector::Usb {
+                vid,
+                pid,
+                serial_number,
+            } => match &port.port_type {
+                SerialPortType::UsbPort(info) => {
+                    vid.is_none_or(|vid| vid == info.vid)
+                        && pid.is_none_or(|pid| pid == info.pid)
+                        && serial_number
+                            .as_ref()
+                            .is_none_or(|s| info.serial_number.as_ref() == Some(s))
+                }
+                _ => false,
+            },
         }
-        recent.push(val);
-        if &recent == "emg_data".as_bytes() {
-            recent.clear();
-            let server_run_id = stream.read_u64_le().await?;
-            let first_sample_index = stream.read_u64_le().await?;
-            let num_samples = stream.read_u16_le().await?;
-            let sample_data = &mut buffer[..(num_samples as usize) * 6];
-            stream.read_exact(sample_data).await?;
-
-            let samples = sample_data
-                .chunks_exact(6)
-                .map(|chunk| {
-                    [
-                        (u16::from(chunk[0]) << 4) + (u16::from(chunk[4]) >> 4),
-                        (u16::from(chunk[1]) << 4) + (u16::from(chunk[4]) & 15),
-                        (u16::from(chunk[2]) << 4) + (u16::from(chunk[5]) >> 4),
-                        (u16::from(chunk[3]) << 4) + (u16::from(chunk[5]) & 15),
-                    ]
-                })
-                .collect::<Vec<_>>();
-            let report = ReportFromServer {
-                server_run_id,
-                first_sample_index,
-                samples,
-            };
+    }
 
-            sender.send(report).await?;
+    /// The path to open right now, which may change between reconnections.
+    pub fn find_path(&self) -> anyhow::Result<String> {
+        match self {
+            // don't require the port to be enumerable, e.g. pseudo-terminals from the simulator aren't
+            SerialPortSelector::Path(path) => Ok(path.clone()),
+            SerialPortSelector::Usb { .. } => tokio_serial::available_ports()?
+                .into_iter()
+                .find(|port| self.matches(port))
+                .map(|port| port.port_name)
+                .ok_or_else(|| anyhow!("no serial port matching {}", self)),
         }
     }
 }
 
-pub fn messages_from_server() -> impl Stream<Item = ReportFromServer> {
-    let (sender, receiver) = futures::channel::mpsc::unbounded();
-    task::spawn(async move {
-        match read_messages(sender).await {
-            Ok(n) => match n {},
-            Err(e) => println!("Server receive thread closed due to error: {}", e),
+pub fn parse_usb_id(id: &str) -> anyhow::Result<u16> {
+    Ok(u16::from_str_radix(id.trim_start_matches("0x"), 16)?)
+}
+
+pub fn describe_available_ports() -> anyhow::Result<Vec<String>> {
+    Ok(tokio_serial::available_ports()?
+        .into_iter()
+        .map(|port| match port.port_type {
+            SerialPortType::UsbPort(info) => format!(
+                "{}: USB vid={:04x} pid={:04x} serial_number={} ({} {})",
+                port.port_name,
+                info.vid,
+                info.pid,
+                info.serial_number.as_deref().unwrap_or("?"),
+                info.manufacturer.as_deref().unwrap_or(""),
+                info.product.as_deref().unwrap_or(""),
+            ),
+            other => format!("{}: {:?}", port.port_name, other),
+        })
+        .collect())
+}
+
+/// Reads the framing that `emg_server.c` writes (see `frame_parser`), from a serial port or anything else that carries the same bytes.
+pub async fn read_messages(
+    mut stream: impl AsyncRead + Unpin,
+    parser: &mut FrameParser,
+    mut sender: UnboundedSender<SourceEvent>,
+) -> Result<!, anyhow::Error> {
+    let mut buffer = [0; MAX_FRAME_SIZE];
+    let mut last_statistics_sent = Instant::now();
+    loop {
+        let Ok(num_bytes) = timeout(READ_TIMEOUT, stream.read(&mut buffer)).await else {
+            bail!("no data for {:?}", READ_TIMEOUT);
+        };
+        let num_bytes = num_bytes?;
+        if num_bytes == 0 {
+            bail!("end of stream");
+        }
+        parser.push_bytes(&buffer[..num_bytes]);
+        while let Some(report) = parser.next_report() {
+            sender.send(SourceEvent::Report(report)).await?;
         }
-    });
+        if last_statistics_sent.elapsed() >= LINK_STATISTICS_INTERVAL {
+            last_statistics_sent = Instant::now();
+            sender
+                .send(SourceEvent::LinkStatistics(parser.statistics()))
+                .await?;
+        }
+    }
+}
+
+/// Reads from a serial port until the receiver is dropped, reopening the port whenever it fails.
+pub fn messages_from_server(
+    selector: SerialPortSelector,
+    baud_rate: u32,
+) -> UnboundedReceiver<SourceEvent> {
+    let (sender, receiver) = futures::channel::mpsc::unbounded();
+    let name = selector.to_string();
+    task::spawn(read_messages_reopening(
+        name,
+        move || {
+            let path = selector.find_path()?;
+            let stream = tokio_serial::new(&path, baud_rate).open_native_async()?;
+            Ok((path, stream))
+        },
+        sender,
+    ));
 
     receiver
 }
+
+/// Calls `open` and reads from the stream it returns, until `sender` is closed. Whenever that fails,
+/// calls it again after a growing delay, and sends `SourceEvent::Reconnected` once it succeeds.
+async fn read_messages_reopening<S: AsyncRead + Unpin>(
+    name: String,
+    mut open: impl FnMut() -> anyhow::Result<(String, S)>,
+    mut sender: UnboundedSender<SourceEvent>,
+) {
+    let mut reopen_delay = MIN_REOPEN_DELAY;
+    // shared between connections, so the statistics cover the whole session
+    let mut parser = FrameParser::new();
+    while !sender.is_closed() {
+        match open() {
+            Ok((path, stream)) => {
+                println!("Opened serial port {}", path);
+                reopen_delay = MIN_REOPEN_DELAY;
+                if sender.send(SourceEvent::Reconnected).await.is_err() {
+                    break;
+                }
+                parser.discard_partial_frame();
+                match read_messages(stream, &mut parser, sender.clone()).await {
+                    Ok(n) => match n {},
+                    Err(e) => println!("Serial port {} closed due to error: {}", path, e),
+                }
+            }
+            Err(e) => println!("Couldn't open serial port {}: {}", name, e),
+        }
+        tokio::time::sleep(reopen_delay).await;
+        reopen_delay = (reopen_delay * 2).min(MAX_REOPEN_DELAY);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame_parser::encode_frame_vec;
+    use futures::StreamExt;
+    use std::collections::VecDeque;
+    use std::io::Cursor;
+    use tokio_serial::UsbPortInfo;
+
+    fn usb_port(vid: u16, pid: u16, serial_number: Option<&str>) -> SerialPortInfo {
+        SerialPortInfo {
+            port_name: "/dev/ttyUSB3".to_string(),
+            port_type: SerialPortType::UsbPort(UsbPortInfo {
+                vid,
+                pid,
+                serial_number: serial_number.map(str::to_string),
+                manufacturer: None,
+                product: None,
+            }),
+        }
+    }
+
+    #[test]
+    fn usb_ids_are_hexadecimal() {
+        assert_eq!(parse_usb_id("10c4").unwrap(), 0x10c4);
+        assert_eq!(parse_usb_id("0xEA60").unwrap(), 0xea60);
+        assert!(parse_usb_id("").is_err());
+        assert!(parse_usb_id("usb").is_err());
+        assert!(parse_usb_id("10000").is_err());
+    }
+
+    #[test]
+    fn selectors_match_by_path_or_usb_ids() {
+        let port = usb_port(0x10c4, 0xea60, Some("0001"));
+        let usb = |vid, pid, serial_number: Option<&str>| SerialPortSelector::Usb {
+            vid,
+            pid,
+            serial_number: serial_number.map(str::to_string),
+        };
+        assert!(SerialPortSelector::Path("/dev/ttyUSB3".to_string()).matches(&port));
+        assert!(!SerialPortSelector::Path("/dev/ttyUSB0".to_string()).matches(&port));
+        assert!(usb(None, None, None).matches(&port));
+        assert!(usb(Some(0x10c4), Some(0xea60), Some("0001")).matches(&port));
+        assert!(usb(Some(0x10c4), None, None).matches(&port));
+        assert!(!usb(Some(0x10c4), Some(0xea61), None).matches(&port));
+        assert!(!usb(None, None, Some("0002")).matches(&port));
+        assert!(!usb(None, None, Some("0001")).matches(&usb_port(0x10c4, 0xea60, None)));
+        let not_usb = SerialPortInfo {
+            port_name: "/dev/ttyS0".to_string(),
+            port_type: SerialPortType::Unknown,
+        };
+        assert!(!usb(None, None, None).matches(&not_usb));
+    }
+
+    #[test]
+    fn end_of_stream_reopens_and_resumes() {
+        let samples = |first: u16| -> Vec<Vec<u16>> {
+            (first..first + 10).map(|v| vec![v, 4095 - v]).collect()
+        };
+        let mut first_connection = encode_frame_vec(7, 0, &samples(0));
+        // cut off by the disconnection, so it has to be thrown away
+        let interrupted = encode_frame_vec(7, 10, &samples(10));
+        first_connection.extend_from_slice(&interrupted[..interrupted.len() / 2]);
+        let second_connection = encode_frame_vec(7, 20, &samples(20));
+        let mut connections = VecDeque::from([first_connection, second_connection]);
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let events: Vec<SourceEvent> = runtime.block_on(async {
+            let (sender, receiver) = futures::channel::mpsc::unbounded();
+            task::spawn(read_messages_reopening(
+                "test".to_string(),
+                move || match connections.pop_front() {
+                    Some(bytes) => Ok(("test".to_string(), Cursor::new(bytes))),
+                    None => bail!("unplugged"),
+                },
+                sender,
+            ));
+            receiver
+                .filter(|event| {
+                    futures::future::ready(!matches!(event, SourceEvent::LinkStatistics(_)))
+                })
+                .take(4)
+                .collect()
+                .await
+        });
+        assert!(matches!(events[0], SourceEvent::Reconnected));
+        assert!(matches!(events[2], SourceEvent::Reconnected));
+        for (event, first) in [(&events[1], 0), (&events[3], 20)] {
+            let SourceEvent::Report(report) = event else {
+                panic!("expected a report, got {:?}", event);
+            };
+            assert_eq!(report.server_run_id, 7);
+            assert_eq!(report.first_sample_index, first as u64);
+            assert_eq!(report.samples, samples(first));
+        }
+    }
+}
@@ -1,14 +1,19 @@
+use crate::activity_detectors::{new_detector, ActivityDetector, DetectorKind};
+use crate::artifacts::{Artifact, ArtifactDetector};
+use crate::nudft::NudftBank;
 use crate::webserver::{FrequenciesFrame, HistoryFrame};
+use crate::window::Window;
 // use rustfft::num_complex::Complex;
 // use rustfft::FftPlanner;
-use crate::utils::get_variable;
+use crate::variables::{get_channel_variable, get_variable, variables_generation};
+use anyhow::bail;
 use arrayvec::ArrayVec;
 use num_complex::Complex;
 use ordered_float::OrderedFloat;
 use statrs::statistics::Statistics;
 use std::collections::VecDeque;
 use std::f64::consts::TAU;
-use std::ops::{AddAssign, Div, SubAssign};
+use std::str::FromStr;
 //use std::time::Duration;
 
 // struct Signals {
@@ -25,78 +30,213 @@ pub enum ActiveState {
     },
 }
 
-#[derive(Default)]
-pub struct Window<T, const SIZE: usize> {
-    num_values_seen: u64,
-    values: VecDeque<T>,
-    // invariant: equal to the sum of `values`, except maybe floating-point rounding error.
-    cached_sum: T,
-    // keeping a running sum of floating-point numbers by repeatedly adding and subtracting, may drift over time.
-    // therefore, occasionally remake the sum from scratch.
-    next_sum: T,
+/*
+
+Threshold-setting rule:
+
+When we judge that the signal is idle for 3 seconds, we update the activity threshold based on some statistics about the signal over those 3 seconds.
+
+Caveat: to be judged idle, the signal must not merely "not be explicitly judged active" for exactly those 3 seconds, because if it gets activated immediately after the 3 seconds, then the very end of the 3 seconds might be part of the onset. So we wait a little while (ACTIVITY_ONSET_LEEWAY) before applying the judgment.
+
+*/
+const NUMBER_OF_CHUNKS_OVER_WHICH_MAXIMA_ARE_TAKEN: usize = 30;
+const NUMBER_OF_LINE_NOISE_BLOCKS: usize = 12;
+// the sizes below were tuned by counting samples at this rate
+const TUNED_SAMPLE_RATE: f64 = 1020.0;
+// what we assume until we've detected otherwise
+const DEFAULT_MAINS_FREQUENCY: f64 = 60.0;
+const CANDIDATE_MAINS_FREQUENCIES: [f64; 2] = [50.0, 60.0];
+// how many idle seconds in a row have to agree before we switch
+const MAINS_DETECTION_BLOCKS: usize = 3;
+// how much stronger one candidate has to be than the other for a second to count
+const MAINS_DETECTION_RATIO: f64 = 2.0;
+
+/// Sizes of windows etc., in samples, so that they cover the same amount of time whatever the sample rate is.
+#[derive(Copy, Clone, Debug)]
+pub struct SignalParameters {
+    pub sample_rate: f64,
+    pub fft_window: usize,
+    size_of_chunk_over_which_maximum_is_taken: usize,
+    activity_onset_leeway: usize,
+    recent_raw_inputs_size: usize,
+    pub samples_per_frequencies_frame: usize,
+    artifact_short_window: usize,
+    artifact_long_window: usize,
 }
 
-impl<T: Default + Copy + AddAssign + SubAssign, const SIZE: usize> Window<T, SIZE> {
-    pub fn push(&mut self, value: T) -> Option<T> {
-        self.num_values_seen += 1;
-        let mut result = None;
-        if self.values.len() >= SIZE {
-            let dropped = self.values.pop_front().unwrap();
-            self.cached_sum -= dropped;
-            result = Some(dropped);
+impl SignalParameters {
+    pub fn new(sample_rate: f64) -> SignalParameters {
+        let scaled = |samples_at_tuned_rate: f64| {
+            ((samples_at_tuned_rate * sample_rate / TUNED_SAMPLE_RATE).round() as usize).max(1)
+        };
+        SignalParameters {
+            sample_rate,
+            fft_window: scaled(50.0).max(2),
+            size_of_chunk_over_which_maximum_is_taken: scaled(100.0),
+            activity_onset_leeway: scaled(1000.0),
+            recent_raw_inputs_size: scaled(1000.0),
+            samples_per_frequencies_frame: scaled(10.0),
+            artifact_short_window: scaled(50.0),
+            artifact_long_window: scaled(1000.0),
         }
-        self.values.push_back(value);
-        self.cached_sum += value;
-        self.next_sum += value;
-        if self.num_values_seen % SIZE as u64 == 0 {
-            self.cached_sum = self.next_sum;
-            self.next_sum = T::default();
-        }
-        result
-    }
-    // pub fn sum(&self) -> T {
-    //     self.cached_sum
-    // }
-    pub fn values(&self) -> impl Iterator<Item = &T> + '_ {
-        self.values.iter()
     }
-    pub fn num_values_seen(&self) -> u64 {
-        self.num_values_seen
+
+    /// The frequencies that we watch for activity are multiples of this, half the frequency resolution of the window, up to the Nyquist frequency.
+    fn base_frequency(&self) -> f64 {
+        self.sample_rate / (self.fft_window as f64 * 2.0)
     }
-    pub fn last(&self) -> Option<T> {
-        self.values.back().copied()
+    pub fn num_frequencies(&self) -> usize {
+        self.fft_window - 1
     }
-    pub fn is_full(&self) -> bool {
-        self.values.len() == SIZE
+    pub fn new_nudft_bank(&self) -> NudftBank {
+        NudftBank::new(
+            self.base_frequency(),
+            self.num_frequencies(),
+            self.fft_window,
+        )
     }
 }
 
-impl<T: Copy + Div<f64>, const SIZE: usize> Window<T, SIZE> {
-    pub fn mean(&self) -> T::Output {
-        self.cached_sum / self.values.len() as f64
+/// The frequency of the local mains, which the line noise filter has to know.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum MainsFrequency {
+    /// detected from the signal while it's idle
+    Auto,
+    /// in Hz
+    Fixed(f64),
+}
+
+impl FromStr for MainsFrequency {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> anyhow::Result<MainsFrequency> {
+        if text == "auto" {
+            return Ok(MainsFrequency::Auto);
+        }
+        let frequency: f64 = text.parse()?;
+        if !(frequency > 0.0 && frequency.is_finite()) {
+            bail!("mains frequency must be positive, not {}", frequency);
+        }
+        Ok(MainsFrequency::Fixed(frequency))
     }
 }
 
-/*
+/// Measures how much of each candidate mains frequency there is while the signal is idle, one second at a time.
+struct MainsDetector {
+    raw_sum: f64,
+    direction_sums: [Complex<f64>; 2],
+    summand_sums: [Complex<f64>; 2],
+    samples_in_block: usize,
+    block_was_idle: bool,
+    // indices into CANDIDATE_MAINS_FREQUENCIES
+    recent_winners: VecDeque<usize>,
+}
 
-Threshold-setting rule:
+impl Default for MainsDetector {
+    fn default() -> Self {
+        MainsDetector {
+            raw_sum: 0.0,
+            direction_sums: Default::default(),
+            summand_sums: Default::default(),
+            samples_in_block: 0,
+            block_was_idle: true,
+            recent_winners: VecDeque::new(),
+        }
+    }
+}
 
-When we judge that the signal is idle for 3 seconds, we update the activity threshold based on some statistics about the signal over those 3 seconds.
+impl MainsDetector {
+    /// Returns the mains frequency whenever a second gives a clear answer that agrees with the seconds before it.
+    fn observe(&mut self, raw_value: f64, time: f64, idle: bool, sample_rate: f64) -> Option<f64> {
+        self.block_was_idle &= idle;
+        self.raw_sum += raw_value;
+        for ((frequency, direction_sum), summand_sum) in CANDIDATE_MAINS_FREQUENCIES
+            .iter()
+            .zip(&mut self.direction_sums)
+            .zip(&mut self.summand_sums)
+        {
+            let direction = Complex::cis(time * TAU * frequency);
+            *direction_sum += direction;
+            *summand_sum += direction * raw_value;
+        }
+        self.samples_in_block += 1;
+        if (self.samples_in_block as f64) < sample_rate {
+            return None;
+        }
 
-Caveat: to be judged idle, the signal must not merely "not be explicitly judged active" for exactly those 3 seconds, because if it gets activated immediately after the 3 seconds, then the very end of the 3 seconds might be part of the onset. So we wait a little while (ACTIVITY_ONSET_LEEWAY) before applying the judgment.
+        let count = self.samples_in_block as f64;
+        let mean = self.raw_sum / count;
+        let strengths: [f64; 2] = std::array::from_fn(|index| {
+            ((self.summand_sums[index] - self.direction_sums[index] * mean) / count).norm()
+        });
+        let block_was_idle = self.block_was_idle;
+        *self = MainsDetector {
+            recent_winners: std::mem::take(&mut self.recent_winners),
+            ..Default::default()
+        };
+        // activity tells us nothing about the mains, but doesn't contradict what we saw before it either
+        if !block_was_idle {
+            return None;
+        }
+        let winner = if strengths[0] > strengths[1] * MAINS_DETECTION_RATIO {
+            0
+        } else if strengths[1] > strengths[0] * MAINS_DETECTION_RATIO {
+            1
+        } else {
+            self.recent_winners.clear();
+            return None;
+        };
+        self.recent_winners.push_back(winner);
+        if self.recent_winners.len() > MAINS_DETECTION_BLOCKS {
+            self.recent_winners.pop_front();
+        }
+        (self.recent_winners.len() == MAINS_DETECTION_BLOCKS
+            && self.recent_winners.iter().all(|&w| w == winner))
+        .then_some(CANDIDATE_MAINS_FREQUENCIES[winner])
+    }
+}
 
-*/
-const FFT_WINDOW: usize = 50;
-const SIZE_OF_CHUNK_OVER_WHICH_MAXIMUM_IS_TAKEN: usize = 100;
-const ACTIVITY_ONSET_LEEWAY: usize = 1000;
-const NUMBER_OF_CHUNKS_OVER_WHICH_MAXIMA_ARE_TAKEN: usize = 30;
-const FFT_HISTORY_SIZE: usize = 3000;
-const LINE_NOISE_BLOCK_SIZE: usize = 1020 / 60;
+/// Where a frequency's idle noise ends, and how much above that counts as one unit of activity.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct ActivityThresholdStats {
+    pub threshold: f64,
+    pub increment: f64,
+}
+
+impl ActivityThresholdStats {
+    /// From the maxima of consecutive chunks of idle DFT norms: the mean of a few of the highest
+    /// (not next to each other, so one spike doesn't count several times), plus their spread.
+    pub fn from_chunk_maxima(maxima: &[f64]) -> Option<ActivityThresholdStats> {
+        let mut top_nonadjacent_maxima: ArrayVec<f64, 5> = ArrayVec::new();
+        let mut maxima = maxima.to_vec();
+        while !maxima.is_empty() && !top_nonadjacent_maxima.is_full() {
+            let (argmax, &max) = maxima
+                .iter()
+                .enumerate()
+                .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
+                .unwrap();
+            top_nonadjacent_maxima.push(max);
+            maxima.drain(argmax.saturating_sub(1)..maxima.len().min(argmax + 2));
+        }
+        if top_nonadjacent_maxima.len() < 3 {
+            return None;
+        }
+        //let threshold = top_nonadjacent_maxima[0];
+        let increment = top_nonadjacent_maxima
+            .iter()
+            .copied()
+            .std_dev()
+            .max(0.0000001);
+        let threshold = top_nonadjacent_maxima.iter().copied().mean() + increment;
+        Some(ActivityThresholdStats {
+            threshold,
+            increment,
+        })
+    }
 
-#[derive(Clone)]
-struct ActivityThresholdStats {
-    threshold: f64,
-    increment: f64,
+    pub fn activity_level(&self, nudft_norm: f64, max_contribution: f64) -> f64 {
+        ((nudft_norm - self.threshold) / self.increment).clamp(0.0, max_contribution)
+    }
 }
 
 impl Default for ActivityThresholdStats {
@@ -108,18 +248,16 @@ impl Default for ActivityThresholdStats {
     }
 }
 
-#[derive(Default)]
+/// Learns the idle threshold of one feature of the signal, such as its DFT norm at one frequency,
+/// and measures how far above it the feature is.
 pub struct SingleFrequencyState {
-    frequency: f64,
-
-    raw_signal_values: Window<f64, FFT_WINDOW>,
-    directions: Window<Complex<f64>, FFT_WINDOW>,
-    nudft_summands: Window<Complex<f64>, FFT_WINDOW>,
+    parameters: SignalParameters,
 
-    corrected_nudft_norms: Window<f64, FFT_HISTORY_SIZE>,
+    latest_value: f64,
+    num_values_seen: u64,
     // for the running stddev:
-    // corrected_nudft_norm_squares: Window<f64, FFT_HISTORY_SIZE>,
-    chunk_maxima: Window<f64, NUMBER_OF_CHUNKS_OVER_WHICH_MAXIMA_ARE_TAKEN>,
+    // corrected_nudft_norm_squares: Window<f64>,
+    chunk_maxima: Window<f64>,
     running_max_of_current_chunk: f64,
 
     activity_threshold_stats: ActivityThresholdStats,
@@ -127,65 +265,34 @@ pub struct SingleFrequencyState {
 }
 
 impl SingleFrequencyState {
-    pub fn new(frequency: f64) -> SingleFrequencyState {
+    pub fn new(parameters: SignalParameters) -> SingleFrequencyState {
         SingleFrequencyState {
-            frequency,
-            ..Default::default()
+            parameters,
+            latest_value: 0.0,
+            num_values_seen: 0,
+            chunk_maxima: Window::new(NUMBER_OF_CHUNKS_OVER_WHICH_MAXIMA_ARE_TAKEN),
+            running_max_of_current_chunk: 0.0,
+            activity_threshold_stats: ActivityThresholdStats::default(),
+            activity_threshold_stats_candidates: VecDeque::new(),
         }
     }
-    pub fn observe_raw_signal_value(
-        &mut self,
-        raw_signal_value: f64,
-        time: f64,
-        signal_idle: bool,
-    ) {
-        let direction = Complex::cis(time * TAU * self.frequency);
-        self.raw_signal_values.push(raw_signal_value);
-        self.directions.push(direction);
-        self.nudft_summands.push(direction * raw_signal_value);
-
-        let corrected_nudft =
-            self.nudft_summands.mean() - self.raw_signal_values.mean() * self.directions.mean();
-        let corrected_nudft_norm = corrected_nudft.norm();
-
-        self.corrected_nudft_norms.push(corrected_nudft_norm);
-        self.running_max_of_current_chunk =
-            self.running_max_of_current_chunk.max(corrected_nudft_norm);
-        let chunk_phase = self.corrected_nudft_norms.num_values_seen()
-            % SIZE_OF_CHUNK_OVER_WHICH_MAXIMUM_IS_TAKEN as u64;
+    pub fn observe_value(&mut self, value: f64, signal_idle: bool) {
+        self.latest_value = value;
+        self.num_values_seen += 1;
+        self.running_max_of_current_chunk = self.running_max_of_current_chunk.max(value);
+        let chunk_phase =
+            self.num_values_seen % self.parameters.size_of_chunk_over_which_maximum_is_taken as u64;
         if chunk_phase == 0 {
             self.chunk_maxima.push(self.running_max_of_current_chunk);
             self.running_max_of_current_chunk = 0.0;
-            let mut top_nonadjacent_maxima: ArrayVec<f64, 5> = ArrayVec::new();
-            let mut maxima: ArrayVec<f64, NUMBER_OF_CHUNKS_OVER_WHICH_MAXIMA_ARE_TAKEN> =
+            let maxima: ArrayVec<f64, NUMBER_OF_CHUNKS_OVER_WHICH_MAXIMA_ARE_TAKEN> =
                 self.chunk_maxima.values().copied().collect();
-            while !maxima.is_empty() && !top_nonadjacent_maxima.is_full() {
-                let (argmax, &max) = maxima
-                    .iter()
-                    .enumerate()
-                    .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
-                    .unwrap();
-                top_nonadjacent_maxima.push(max);
-                maxima.drain(argmax.saturating_sub(1)..maxima.len().min(argmax + 2));
-            }
-            if top_nonadjacent_maxima.len() >= 3 {
-                //let threshold = top_nonadjacent_maxima[0];
-                let increment = top_nonadjacent_maxima
-                    .iter()
-                    .copied()
-                    .std_dev()
-                    .max(0.0000001);
-                let threshold = top_nonadjacent_maxima.iter().copied().mean() + increment;
-                self.activity_threshold_stats_candidates.push_back((
-                    self.corrected_nudft_norms.num_values_seen(),
-                    ActivityThresholdStats {
-                        threshold,
-                        increment,
-                    },
-                ));
+            if let Some(stats) = ActivityThresholdStats::from_chunk_maxima(&maxima) {
+                self.activity_threshold_stats_candidates
+                    .push_back((self.num_values_seen, stats));
             }
         }
-        while matches!(self.activity_threshold_stats_candidates.front(), Some((i, _)) if self.corrected_nudft_norms.num_values_seen() > i + ACTIVITY_ONSET_LEEWAY as u64)
+        while matches!(self.activity_threshold_stats_candidates.front(), Some((i, _)) if self.num_values_seen > i + self.parameters.activity_onset_leeway as u64)
         {
             let (_i, stats) = self
                 .activity_threshold_stats_candidates
@@ -199,28 +306,60 @@ impl SingleFrequencyState {
         //     .push(corrected_nudft_norm.powi(2));
     }
 
+    /// Forget everything that assumes the samples are contiguous, but keep the learned threshold.
+    pub fn reset_windows(&mut self) {
+        *self = SingleFrequencyState {
+            activity_threshold_stats: self.activity_threshold_stats,
+            ..SingleFrequencyState::new(self.parameters)
+        };
+    }
+
     // pub fn corrected_nudft_norm_stddev(&self) -> f64 {
     //     return (self.corrected_nudft_norm_squares.mean()
     //         - self.corrected_nudft_norms.mean().powi(2))
     //     .sqrt();
     // }
 
-    pub fn latest_activity_level(&self) -> f64 {
-        ((self.corrected_nudft_norms.last().unwrap() - self.activity_threshold_stats.threshold)
-            / self.activity_threshold_stats.increment)
-            .clamp(0.0, get_variable("max_activity_contribution_per_frequency"))
+    pub fn latest_value(&self) -> f64 {
+        self.latest_value
+    }
+
+    pub fn latest_activity_level(&self, max_contribution: f64) -> f64 {
+        self.activity_threshold_stats
+            .activity_level(self.latest_value(), max_contribution)
+    }
+
+    pub fn activity_threshold_stats(&self) -> ActivityThresholdStats {
+        self.activity_threshold_stats
+    }
+
+    /// Replaces the learned threshold, e.g. with one from a calibration, until a new one is learned.
+    pub fn set_activity_threshold_stats(&mut self, stats: ActivityThresholdStats) {
+        self.activity_threshold_stats = stats;
+        self.activity_threshold_stats_candidates.clear();
     }
 }
 
-#[derive(Default)]
 pub struct Signal {
+    pub parameters: SignalParameters,
     pub total_inputs: usize,
     pub recent_raw_inputs: VecDeque<f64>,
     pub history: VecDeque<HistoryFrame>,
-    pub frequency_states: Vec<SingleFrequencyState>,
+    detector: Box<dyn ActivityDetector>,
+    // the `variables_generation` when we last checked which detector the channel should use
+    detector_checked_at: Option<u64>,
     pub aggregate_activity_level: f64,
-    pub frequencies_history: [VecDeque<Vec<f64>>; 3],
-    pub line_noise_blocks: [Window<f64, LINE_NOISE_BLOCK_SIZE>; 12],
+    // after subtracting cross-talk from other channels
+    pub compensated_activity_level: f64,
+    // each one cycle of the mains
+    pub line_noise_blocks: [Window<f64>; NUMBER_OF_LINE_NOISE_BLOCKS],
+    mains_setting: MainsFrequency,
+    mains_frequency: f64,
+    mains_frequency_detected: bool,
+    mains_detector: MainsDetector,
+    artifact_detector: ArtifactDetector,
+    // the most recent sample that was part of an artifact, and when it was
+    last_artifact: Option<(Artifact, f64, i64)>,
     pub active_state: ActiveState,
 }
 
@@ -233,27 +372,99 @@ impl Default for ActiveState {
     }
 }
 
+fn new_line_noise_blocks(
+    parameters: &SignalParameters,
+    mains_frequency: f64,
+) -> [Window<f64>; NUMBER_OF_LINE_NOISE_BLOCKS] {
+    let block_size = ((parameters.sample_rate / mains_frequency).floor() as usize).max(1);
+    std::array::from_fn(|_| Window::new(block_size))
+}
+
 impl Signal {
-    // pub fn new() -> Signal {
-    //     Signal::default()
-    // }
+    pub fn new(sample_rate: f64, mains_setting: MainsFrequency) -> Signal {
+        let parameters = SignalParameters::new(sample_rate);
+        let mains_frequency = match mains_setting {
+            MainsFrequency::Auto => DEFAULT_MAINS_FREQUENCY,
+            MainsFrequency::Fixed(frequency) => frequency,
+        };
+        Signal {
+            parameters,
+            total_inputs: 0,
+            recent_raw_inputs: VecDeque::new(),
+            history: VecDeque::new(),
+            detector: new_detector(DetectorKind::Nudft, parameters),
+            detector_checked_at: None,
+            aggregate_activity_level: 0.0,
+            compensated_activity_level: 0.0,
+            line_noise_blocks: new_line_noise_blocks(&parameters, mains_frequency),
+            mains_setting,
+            mains_frequency,
+            mains_frequency_detected: false,
+            mains_detector: MainsDetector::default(),
+            artifact_detector: ArtifactDetector::new(
+                parameters.artifact_short_window,
+                parameters.artifact_long_window,
+            ),
+            last_artifact: None,
+            active_state: ActiveState::default(),
+        }
+    }
+    /// The latest DFT norm at each of the frequencies we watch, or nothing if the channel uses
+    /// a detector other than the DFT bank.
+    pub fn nudft_norms(&self) -> impl Iterator<Item = f64> + '_ {
+        self.detector.nudft_norms().iter().copied()
+    }
+    /// One per frequency, like `nudft_norms`.
+    pub fn set_activity_threshold_stats(&mut self, stats: &[ActivityThresholdStats]) {
+        self.detector.set_activity_threshold_stats(stats);
+    }
     pub fn is_active(&self) -> bool {
         matches!(self.active_state, ActiveState::Active { .. })
     }
+    /// The mains frequency that the line noise filter is currently tuned to, and whether it was
+    /// detected (rather than set, or assumed because we haven't detected it yet).
+    pub fn mains_frequency(&self) -> (f64, bool) {
+        (self.mains_frequency, self.mains_frequency_detected)
+    }
+    /// The motion artifact that the signal is in, or was in less than `artifact_hold_time` ago.
+    pub fn recent_artifact(&self, time: f64) -> Option<Artifact> {
+        let (artifact, artifact_time, _) = self.last_artifact?;
+        (time <= artifact_time + get_variable("artifact_hold_time")).then_some(artifact)
+    }
+    /// After samples went missing, start over as if the signal had just been connected, except for
+    /// the learned thresholds. It also counts as having just been deactivated, so it can't be
+    /// activated (or judged idle) until there's enough new data.
+    pub fn reset_after_gap(&mut self, time: f64) {
+        self.line_noise_blocks = new_line_noise_blocks(&self.parameters, self.mains_frequency);
+        self.mains_detector = MainsDetector::default();
+        self.artifact_detector = ArtifactDetector::new(
+            self.parameters.artifact_short_window,
+            self.parameters.artifact_long_window,
+        );
+        self.recent_raw_inputs.clear();
+        self.detector.reset_windows();
+        self.aggregate_activity_level = 0.0;
+        self.compensated_activity_level = 0.0;
+        self.active_state = ActiveState::Inactive {
+            last_deactivated_time: time,
+            last_deactivated_sample: i64::try_from(self.total_inputs).unwrap(),
+        };
+    }
     // pub fn aggregate_activity_level(&self) -> f64 {
     //     self.aggregate_activity_level
     // }
+    /// `channel` is this signal's number across all servers, which its variables can be overridden by.
     pub fn receive_raw(
         &mut self,
         mut raw_value: f64,
         time: f64,
-        force_idle: bool,
-        report_frame: impl FnOnce(HistoryFrame),
+        channel: usize,
         report_frequency_frame: impl FnOnce(FrequenciesFrame),
     ) {
         self.total_inputs += 1;
+        let unfiltered_value = raw_value;
 
-        // To filter line noise, we keep track of some recent (1/60)s blocks. Within each of these blocks, the phase-matched sample will differ from the average of the block by a certain amount; we subtract the median of these amounts from the current sample. This should avoid some of the common cases of incorrectly correcting for values that are from the actual signal
+        // To filter line noise, we keep track of some recent blocks, each one cycle of the mains. Within each of these blocks, the phase-matched sample will differ from the average of the block by a certain amount; we subtract the median of these amounts from the current sample. This should avoid some of the common cases of incorrectly correcting for values that are from the actual signal
         let mut submitted = Some(raw_value);
         for block in &mut self.line_noise_blocks {
             if let Some(s) = submitted {
@@ -272,59 +483,72 @@ impl Signal {
         }
 
         self.recent_raw_inputs.push_back(raw_value / 1500.0);
-        if self.recent_raw_inputs.len() > 1000 {
+        if self.recent_raw_inputs.len() > self.parameters.recent_raw_inputs_size {
             self.recent_raw_inputs.pop_front();
         }
 
-        if self.frequency_states.is_empty() {
-            //self.frequency_states = (1..FFT_WINDOW)
-            // .map(|f| f as f64 / (FFT_WINDOW as f64 / 1000.0))
-            // .map(|f| 500.0 * 0.895_f64.powi(f as i32))
-            // self.frequency_states = (0..FFT_WINDOW)
-            //     .map(|f| 20.0 * (500.0f64 / 20.0).powf(f as f64 / (FFT_WINDOW - 1) as f64))
-            self.frequency_states = (1..FFT_WINDOW)
-                .map(|f| f as f64 / (FFT_WINDOW as f64 * 2.0 / 1000.0))
-                .map(SingleFrequencyState::new)
-                .collect();
-        }
-        let signal_idle = force_idle
-            || match self.active_state {
-                ActiveState::Inactive {
-                    last_deactivated_sample,
-                    ..
-                } => {
-                    last_deactivated_sample
-                        + i64::try_from(
-                            SIZE_OF_CHUNK_OVER_WHICH_MAXIMUM_IS_TAKEN
-                                * NUMBER_OF_CHUNKS_OVER_WHICH_MAXIMA_ARE_TAKEN
-                                + ACTIVITY_ONSET_LEEWAY,
-                        )
-                        .unwrap()
-                        < i64::try_from(self.total_inputs).unwrap()
+        // artifacts spoil the idle thresholds just like activity does
+        let signal_idle = match self.active_state {
+            ActiveState::Inactive {
+                last_deactivated_sample,
+                ..
+            } => {
+                let last_disturbance = match self.last_artifact {
+                    Some((_, _, artifact_sample)) => last_deactivated_sample.max(artifact_sample),
+                    None => last_deactivated_sample,
+                };
+                last_disturbance
+                    + i64::try_from(
+                        self.parameters.size_of_chunk_over_which_maximum_is_taken
+                            * NUMBER_OF_CHUNKS_OVER_WHICH_MAXIMA_ARE_TAKEN
+                            + self.parameters.activity_onset_leeway,
+                    )
+                    .unwrap()
+                    < i64::try_from(self.total_inputs).unwrap()
+            }
+            _ => false,
+        };
+        if self.mains_setting == MainsFrequency::Auto {
+            if let Some(detected) = self.mains_detector.observe(
+                unfiltered_value,
+                time,
+                signal_idle,
+                self.parameters.sample_rate,
+            ) {
+                if detected != self.mains_frequency {
+                    self.mains_frequency = detected;
+                    self.line_noise_blocks = new_line_noise_blocks(&self.parameters, detected);
                 }
-                _ => false,
-            };
-        for state in &mut self.frequency_states {
-            state.observe_raw_signal_value(raw_value / 1500.0, time, signal_idle);
+                self.mains_frequency_detected = true;
+            }
+        }
+        let generation = variables_generation();
+        if self.detector_checked_at != Some(generation) {
+            self.detector_checked_at = Some(generation);
+            let kind =
+                DetectorKind::from_variable(get_channel_variable(channel, "activity_detector"));
+            if kind != self.detector.kind() {
+                // the new detector's levels mean something else, so start over
+                self.detector = new_detector(kind, self.parameters);
+                self.active_state = ActiveState::Inactive {
+                    last_deactivated_time: time,
+                    last_deactivated_sample: i64::try_from(self.total_inputs).unwrap(),
+                };
+            }
+        }
+        self.aggregate_activity_level =
+            self.detector
+                .observe(raw_value / 1500.0, time, channel, signal_idle);
+        if let Some(artifact) = self.artifact_detector.observe(
+            raw_value / 1500.0,
+            self.detector.spectrum(),
+            self.detector.fraction_above_idle(),
+            channel,
+        ) {
+            self.last_artifact = Some((artifact, time, i64::try_from(self.total_inputs).unwrap()));
         }
-        self.aggregate_activity_level = self
-            .frequency_states
-            .iter()
-            .map(SingleFrequencyState::latest_activity_level)
-            .sum::<f64>();
-        let values: Vec<f64> = self
-            .frequency_states
-            .iter()
-            .map(|state| state.corrected_nudft_norms.last().unwrap())
-            .collect();
-        let thresholds: Vec<f64> = self
-            .frequency_states
-            .iter()
-            .map(|state| state.activity_threshold_stats.threshold)
-            .collect();
 
-        const FRAMES_PER_FFT: usize = 10;
-        if self.recent_raw_inputs.len() >= FFT_WINDOW && self.total_inputs % FRAMES_PER_FFT == 0 {
+        if self.frame_due() {
             // let fft = fft_planner.plan_fft_forward(FFT_WINDOW);
             //
             // let mut buffer: Vec<_> = self
@@ -337,34 +561,14 @@ impl Signal {
             // fft.process(&mut buffer);
             // let values: Vec<f64> = buffer.into_iter().skip(1).map(|c| c.norm()).collect();
             //let scale = 1.0 / values.iter().max_by_key(|&&f| OrderedFloat(f)).unwrap();
-            let scale = 50.0;
+            // let scale = 50.0;
             // for value in &mut values {
             //     *value *= scale;
             // }
-            self.frequencies_history[0].push_back(values.iter().map(|v| v * scale).collect());
-            self.frequencies_history[1].push_back(thresholds.iter().map(|v| v * scale).collect());
-            let max_activity_contribution_per_frequency =
-                get_variable("max_activity_contribution_per_frequency");
-            self.frequencies_history[2].push_back(
-                self.frequency_states
-                    .iter()
-                    .map(SingleFrequencyState::latest_activity_level)
-                    .map(|a| a / max_activity_contribution_per_frequency)
-                    .collect(),
-            );
             report_frequency_frame(FrequenciesFrame {
                 time,
-                values: self
-                    .frequencies_history
-                    .iter()
-                    .map(|h| h.back().unwrap().clone())
-                    .collect(),
+                values: self.detector.debug_frame(channel),
             });
-            for h in &mut self.frequencies_history {
-                while h.len() > 800 / FRAMES_PER_FFT {
-                    h.pop_front();
-                }
-            }
 
             // let fft = fft_planner.plan_fft_forward(VALUE_WINDOW);
             // let mut buffer: Vec<_> = self
@@ -458,46 +662,198 @@ impl Signal {
             // let recent_max = recent_values
             //     .max_by_key(|&v| OrderedFloat(v))
             //     .unwrap_or(1.0);
+        }
+    }
+    fn frame_due(&self) -> bool {
+        self.recent_raw_inputs.len() >= self.parameters.fft_window
+            && self
+                .total_inputs
+                .is_multiple_of(self.parameters.samples_per_frequencies_frame)
+    }
+    /// After `receive_raw`, decides whether the signal is active, from its activity level minus
+    /// `crosstalk`: how much of it is presumably picked up from other channels' muscles.
+    /// Whenever it would have become active during a motion artifact, reports the artifact instead.
+    pub fn update_activity(
+        &mut self,
+        crosstalk: f64,
+        time: f64,
+        channel: usize,
+        report_frame: impl FnOnce(HistoryFrame),
+        report_suppressed_activation: impl FnOnce(Artifact),
+    ) {
+        self.compensated_activity_level = (self.aggregate_activity_level - crosstalk).max(0.0);
+        if !self.frame_due() {
+            return;
+        }
+        let value = self.compensated_activity_level;
+        let activity_threshold = get_channel_variable(channel, "activity_threshold");
+        self.history.push_back(HistoryFrame {
+            time,
+            value: value / activity_threshold,
+            crosstalk: (self.aggregate_activity_level - value) / activity_threshold,
+            activity_threshold: 1.0,
+            too_much_threshold: *self.recent_raw_inputs.back().unwrap(), //2.0,
+        });
+        report_frame(self.history.back().unwrap().clone());
+        while self.history.front().unwrap().time < time - 3.0 {
+            self.history.pop_front();
+        }
 
-            let value = self.aggregate_activity_level;
-            let activity_threshold = get_variable("activity_threshold");
-            self.history.push_back(HistoryFrame {
-                time,
-                value: value / activity_threshold,
-                activity_threshold: 1.0,
-                too_much_threshold: *self.recent_raw_inputs.back().unwrap(), //2.0,
-            });
-            report_frame(self.history.back().unwrap().clone());
-            while self.history.front().unwrap().time < time - 3.0 {
-                self.history.pop_front();
+        match self.active_state {
+            ActiveState::Active {
+                last_sustained_time,
+            } => {
+                if value > activity_threshold {
+                    self.active_state = ActiveState::Active {
+                        last_sustained_time: time,
+                    };
+                } else if time > last_sustained_time + 0.1 {
+                    self.active_state = ActiveState::Inactive {
+                        last_deactivated_time: time,
+                        last_deactivated_sample: i64::try_from(self.total_inputs).unwrap(),
+                    };
+                }
             }
-
-            match self.active_state {
-                ActiveState::Active {
-                    last_sustained_time,
-                } => {
-                    if value > activity_threshold {
+            ActiveState::Inactive {
+                last_deactivated_time,
+                ..
+            } => {
+                if time > last_deactivated_time + 0.4 && value > activity_threshold {
+                    if let Some(artifact) = self.recent_artifact(time) {
+                        report_suppressed_activation(artifact);
+                    } else {
                         self.active_state = ActiveState::Active {
                             last_sustained_time: time,
                         };
-                    } else if time > last_sustained_time + 0.1 {
-                        self.active_state = ActiveState::Inactive {
-                            last_deactivated_time: time,
-                            last_deactivated_sample: i64::try_from(self.total_inputs).unwrap(),
-                        };
                     }
                 }
-                ActiveState::Inactive {
-                    last_deactivated_time,
-                    ..
-                } => {
-                    if time > last_deactivated_time + 0.4 && value > activity_threshold {
-                        self.active_state = ActiveState::Active {
-                            last_sustained_time: time,
-                        };
-                    }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    /// What `MainsDetector` says after each second of a resting signal with interference at `mains_frequency`.
+    fn detect_mains(mains_frequency: f64, idle: bool) -> Vec<Option<f64>> {
+        let sample_rate = 1020.0;
+        let mut detector = MainsDetector::default();
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut noise = || rng.gen_range(-0.5..0.5);
+        let mut results = Vec::new();
+        for index in 0..(sample_rate * 5.0) as usize {
+            let time = index as f64 / sample_rate;
+            let value = 2048.0 + (time * TAU * mains_frequency).sin() * 30.0 + noise() * 16.0;
+            if let Some(result) = detector.observe(value, time, idle, sample_rate) {
+                results.push(Some(result));
+            } else if (index + 1) % sample_rate as usize == 0 {
+                results.push(None);
+            }
+        }
+        results
+    }
+
+    #[test]
+    fn mains_detector_picks_the_interfering_frequency() {
+        for mains_frequency in CANDIDATE_MAINS_FREQUENCIES {
+            let results = detect_mains(mains_frequency, true);
+            // it takes `MAINS_DETECTION_BLOCKS` seconds in a row to be sure
+            assert_eq!(
+                results,
+                vec![
+                    None,
+                    None,
+                    Some(mains_frequency),
+                    Some(mains_frequency),
+                    Some(mains_frequency)
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn mains_detector_ignores_active_seconds() {
+        assert_eq!(detect_mains(50.0, false), vec![None; 5]);
+    }
+
+    #[test]
+    fn artifacts_hold_back_activations_for_a_while() {
+        let sample_rate = 1020.0;
+        let channel = 1400;
+        let mut signal = Signal::new(sample_rate, MainsFrequency::Fixed(60.0));
+        let mut rng = StdRng::seed_from_u64(4);
+        let mut noise = || rng.gen_range(-0.5..0.5);
+        // after resting, the muscle contracts just as an electrode shifts, from 4 seconds on
+        let mut suppressed = Vec::new();
+        let mut activated = None;
+        let mut last_artifact = None;
+        for index in 0..(sample_rate * 7.0) as usize {
+            let time = index as f64 / sample_rate;
+            let mut value = 2048.0 + noise() * 16.0;
+            if time >= 4.0 {
+                value += 1500.0;
+                for frequency in [80.0, 110.0, 170.0] {
+                    value += (time * TAU * frequency).sin() * 200.0;
                 }
             }
+            signal.receive_raw(value, time, channel, |_| {});
+            signal.update_activity(0.0, time, channel, |_| {}, |_| suppressed.push(time));
+            if signal
+                .last_artifact
+                .is_some_and(|(_, artifact_time, _)| artifact_time == time)
+            {
+                last_artifact = Some(time);
+            }
+            if signal.is_active() && activated.is_none() {
+                activated = Some(time);
+            }
         }
+        let last_artifact = last_artifact.unwrap();
+        let activated = activated.unwrap();
+        assert!(last_artifact > 4.0);
+        // every frame that would have activated it is reported
+        assert!(suppressed.len() > 10, "{:?}", suppressed);
+        assert!(suppressed.iter().all(|&time| time <= activated));
+        let hold_time = get_variable("artifact_hold_time");
+        assert!(activated > last_artifact + hold_time);
+        assert!(activated < last_artifact + hold_time + 0.1);
+        assert!(signal
+            .recent_artifact(last_artifact + hold_time - 0.01)
+            .is_some());
+        assert!(signal
+            .recent_artifact(last_artifact + hold_time + 0.01)
+            .is_none());
+    }
+
+    #[test]
+    fn the_detector_follows_its_variable() {
+        let channel = 1401;
+        let mut signal = Signal::new(1020.0, MainsFrequency::Fixed(60.0));
+        let mut kinds = Vec::new();
+        let mut receive = |signal: &mut Signal, time| {
+            signal.receive_raw(2048.0, time, channel, |_| {});
+            kinds.push(signal.detector.kind());
+        };
+        receive(&mut signal, 0.0);
+        let filter_bank = DetectorKind::FilterBank.variable_value();
+        crate::variables::set_channel_variable(channel, "activity_detector", Some(filter_bank))
+            .unwrap();
+        receive(&mut signal, 0.001);
+        receive(&mut signal, 0.002);
+        crate::variables::set_channel_variable(channel, "activity_detector", None).unwrap();
+        receive(&mut signal, 0.003);
+        assert_eq!(
+            kinds,
+            vec![
+                DetectorKind::Nudft,
+                DetectorKind::FilterBank,
+                DetectorKind::FilterBank,
+                DetectorKind::Nudft
+            ]
+        );
     }
 }
@@ -0,0 +1,514 @@
+use crate::frame_parser::encode_frame_vec;
+use crate::sample_source::DEFAULT_SAMPLE_RATE;
+use anyhow::{bail, Context};
+use emg_mouse_shared::codec::{bytes_per_sample, CRC_SIZE, MAX_CHANNELS, MAX_HEADER_SIZE};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::f64::consts::TAU;
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::time::MissedTickBehavior;
+#[cfg(unix)]
+use tokio_serial::SerialPort;
+
+/*
+
+A fake EMG device, which writes exactly the byte stream that `emg_server.c` writes to its UART,
+so that the rest of the system can be exercised without hardware.
+
+What the fake muscles do is described by a `SimulatorScript`, which can be loaded from a JSON file.
+For example:
+
+{
+  "mains_amplitude": 30,
+  "segments": [
+    { "duration": 3 },
+    { "duration": 0.4, "bursts": [{ "channel": 2, "amplitude": 300, "frequencies": [60, 110, 170] }] },
+    { "duration": 1, "drop_probability": 0.1 },
+    { "duration": 0.2, "saturate_channels": [0] },
+    { "duration": 2, "restart": true }
+  ]
+}
+
+*/
+
+const ADC_MAX: f64 = 4095.0;
+// the real server's message buffer is this big
+const MAX_MESSAGE_SIZE: usize = 16 + 80 * 6;
+
+fn max_samples_per_frame(num_channels: usize) -> usize {
+    ((MAX_MESSAGE_SIZE - MAX_HEADER_SIZE - CRC_SIZE) / bytes_per_sample(num_channels)).max(1)
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct SimulatorScript {
+    pub sample_rate: f64,
+    /// from 1 to `MAX_CHANNELS`
+    pub num_channels: usize,
+    /// the resting ADC value of every channel
+    pub baseline: f64,
+    /// amplitude of the white noise present on every channel
+    pub noise_amplitude: f64,
+    pub mains_frequency: f64,
+    pub mains_amplitude: f64,
+    /// how often frames are sent, like the server's `vTaskDelay(1)`
+    pub frame_interval_millis: u64,
+    /// go back to the first segment after the last one, instead of stopping
+    pub repeat: bool,
+    /// if set, the simulation is the same every time
+    pub seed: Option<u64>,
+    pub segments: Vec<Segment>,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Segment {
+    /// in seconds
+    pub duration: f64,
+    pub bursts: Vec<Burst>,
+    /// chance that each frame during this segment never gets sent
+    pub drop_probability: f64,
+    /// channels that are pinned at the maximum ADC value during this segment
+    pub saturate_channels: Vec<usize>,
+    /// the device restarts at the beginning of this segment, with a new run id and sample indices starting from 0
+    pub restart: bool,
+}
+
+/// A muscle contraction on one channel, simulated as a sum of sinusoids with randomly drifting phases.
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Burst {
+    pub channel: usize,
+    pub amplitude: f64,
+    /// in Hz
+    pub frequencies: Vec<f64>,
+}
+
+impl Default for SimulatorScript {
+    fn default() -> Self {
+        let burst = |channel, amplitude| Segment {
+            duration: 0.4,
+            bursts: vec![Burst {
+                channel,
+                amplitude,
+                frequencies: vec![50.0, 80.0, 110.0, 170.0, 230.0],
+            }],
+            ..Default::default()
+        };
+        let rest = |duration| Segment {
+            duration,
+            ..Default::default()
+        };
+        SimulatorScript {
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            num_channels: 4,
+            baseline: 2048.0,
+            noise_amplitude: 8.0,
+            mains_frequency: 60.0,
+            mains_amplitude: 20.0,
+            frame_interval_millis: 1,
+            repeat: true,
+            seed: None,
+            segments: vec![
+                rest(5.0),
+                burst(2, 300.0),
+                rest(2.0),
+                burst(0, 200.0),
+                rest(2.0),
+                burst(1, 200.0),
+                rest(2.0),
+            ],
+        }
+    }
+}
+
+impl SimulatorScript {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<SimulatorScript> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read simulator script {}", path.display()))?;
+        let script: SimulatorScript = serde_json::from_str(&text)?;
+        if !(1..=MAX_CHANNELS).contains(&script.num_channels) {
+            bail!(
+                "num_channels must be from 1 to {}, not {}",
+                MAX_CHANNELS,
+                script.num_channels
+            );
+        }
+        if !(script.sample_rate > 0.0 && script.sample_rate.is_finite()) {
+            bail!("sample_rate must be positive, not {}", script.sample_rate);
+        }
+        Ok(script)
+    }
+}
+
+pub struct Simulator {
+    script: SimulatorScript,
+    rng: StdRng,
+    server_run_id: u64,
+    next_sample_index: u64,
+    segment_index: usize,
+    samples_into_segment: u64,
+    // one per frequency per burst in the current segment
+    burst_phases: Vec<Vec<f64>>,
+    finished: bool,
+}
+
+impl Simulator {
+    pub fn new(script: SimulatorScript) -> Simulator {
+        let mut rng = match script.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        let server_run_id = rng.gen();
+        let mut result = Simulator {
+            script,
+            rng,
+            server_run_id,
+            next_sample_index: 0,
+            segment_index: 0,
+            samples_into_segment: 0,
+            burst_phases: Vec::new(),
+            finished: false,
+        };
+        result.start_segment();
+        result
+    }
+
+    pub fn finished(&self) -> bool {
+        self.finished
+    }
+
+    fn current_segment(&self) -> Option<&Segment> {
+        self.script.segments.get(self.segment_index)
+    }
+
+    fn start_segment(&mut self) {
+        self.samples_into_segment = 0;
+        let Some(segment) = self.script.segments.get(self.segment_index) else {
+            self.finished = true;
+            return;
+        };
+        if segment.restart {
+            self.server_run_id = self.rng.gen();
+            self.next_sample_index = 0;
+        }
+        let rng = &mut self.rng;
+        self.burst_phases = segment
+            .bursts
+            .iter()
+            .map(|burst| {
+                burst
+                    .frequencies
+                    .iter()
+                    .map(|_| rng.gen_range(0.0..TAU))
+                    .collect()
+            })
+            .collect();
+    }
+
+    fn advance_segment_if_done(&mut self) {
+        let total_duration: f64 = self.script.segments.iter().map(|s| s.duration).sum();
+        if total_duration <= 0.0 {
+            // otherwise, a repeating script would go around forever without producing samples
+            self.finished = true;
+            return;
+        }
+        while let Some(segment) = self.current_segment() {
+            if (self.samples_into_segment as f64) < segment.duration * self.script.sample_rate {
+                return;
+            }
+            self.segment_index += 1;
+            if self.segment_index >= self.script.segments.len() && self.script.repeat {
+                self.segment_index = 0;
+            }
+            self.start_segment();
+        }
+    }
+
+    fn next_sample(&mut self) -> Vec<u16> {
+        let script = &self.script;
+        let segment = &script.segments[self.segment_index];
+        let time = self.next_sample_index as f64 / script.sample_rate;
+        let mut values = vec![script.baseline; script.num_channels];
+        for (channel, value) in values.iter_mut().enumerate() {
+            *value += self.rng.gen_range(-1.0..1.0) * script.noise_amplitude;
+            // each channel picks up the mains with a slightly different phase
+            *value += (time * TAU * script.mains_frequency + channel as f64).sin()
+                * script.mains_amplitude;
+        }
+        for (burst, phases) in segment.bursts.iter().zip(&mut self.burst_phases) {
+            let Some(value) = values.get_mut(burst.channel) else {
+                continue;
+            };
+            let scale = burst.amplitude / (burst.frequencies.len().max(1) as f64).sqrt();
+            for (&frequency, phase) in burst.frequencies.iter().zip(phases) {
+                *phase += self.rng.gen_range(-0.3..0.3);
+                *value += (time * TAU * frequency + *phase).sin() * scale;
+            }
+        }
+        for &channel in &segment.saturate_channels {
+            if let Some(value) = values.get_mut(channel) {
+                *value = ADC_MAX;
+            }
+        }
+        self.next_sample_index += 1;
+        self.samples_into_segment += 1;
+        values
+            .into_iter()
+            .map(|v| v.round().clamp(0.0, ADC_MAX) as u16)
+            .collect()
+    }
+
+    /// Simulates the next `num_samples` samples, and returns them encoded the way the server
+    /// would send them; or None if this frame was "dropped" (the samples still advance).
+    pub fn next_frame(&mut self, num_samples: usize) -> Option<Vec<u8>> {
+        self.advance_segment_if_done();
+        if self.finished {
+            return None;
+        }
+        let dropped = self.rng.gen_bool(
+            self.current_segment()
+                .map_or(0.0, |s| s.drop_probability)
+                .clamp(0.0, 1.0),
+        );
+        let server_run_id = self.server_run_id;
+        let first_sample_index = self.next_sample_index;
+        let mut samples = Vec::with_capacity(num_samples);
+        for _ in 0..num_samples.min(max_samples_per_frame(self.script.num_channels)) {
+            self.advance_segment_if_done();
+            // don't let a restart happen in the middle of a frame
+            if self.finished || self.server_run_id != server_run_id {
+                break;
+            }
+            samples.push(self.next_sample());
+        }
+        if dropped || samples.is_empty() {
+            return None;
+        }
+        Some(encode_frame_vec(
+            server_run_id,
+            first_sample_index,
+            &samples,
+        ))
+    }
+}
+
+/// Runs a simulator in real time, writing frames to `output` until the script ends or the output is closed.
+pub async fn run_simulator(
+    script: SimulatorScript,
+    mut output: impl AsyncWrite + Unpin,
+) -> anyhow::Result<()> {
+    let frame_interval = Duration::from_millis(script.frame_interval_millis.max(1));
+    let samples_per_frame = script.sample_rate * frame_interval.as_secs_f64();
+    let mut simulator = Simulator::new(script);
+    let mut interval = tokio::time::interval(frame_interval);
+    // if the reader stalls, act like the device kept going rather than sending a huge burst afterwards
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let mut samples_owed = 0.0;
+    while !simulator.finished() {
+        interval.tick().await;
+        samples_owed += samples_per_frame;
+        let num_samples = samples_owed.floor();
+        samples_owed -= num_samples;
+        if let Some(frame) = simulator.next_frame(num_samples as usize) {
+            output.write_all(&frame).await?;
+        }
+    }
+    Ok(())
+}
+
+pub enum SimulatorOutput {
+    PseudoTerminal,
+    Tcp { port: u16 },
+}
+
+#[cfg(unix)]
+async fn simulate_on_pseudo_terminal(script: SimulatorScript) -> anyhow::Result<()> {
+    let (master, slave) = tokio_serial::SerialStream::pair()?;
+    println!(
+        "Simulating EMG device on {}",
+        slave.name().unwrap_or_else(|| "<unnamed pty>".to_string())
+    );
+    run_simulator(script, master).await
+}
+
+#[cfg(not(unix))]
+async fn simulate_on_pseudo_terminal(_script: SimulatorScript) -> anyhow::Result<()> {
+    bail!("there are no pseudo-terminals on this platform; use --tcp-port, or --source simulate in the supervisor")
+}
+
+pub async fn simulate(script: SimulatorScript, output: SimulatorOutput) -> anyhow::Result<()> {
+    match output {
+        SimulatorOutput::PseudoTerminal => simulate_on_pseudo_terminal(script).await,
+        SimulatorOutput::Tcp { port } => {
+            let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+            println!("Simulating EMG device on TCP port {}", port);
+            loop {
+                let (stream, address) = listener.accept().await?;
+                println!("Simulator connected to {}", address);
+                // each connection is like plugging in a freshly started device
+                if let Err(e) = run_simulator(script.clone(), stream).await {
+                    println!("Simulator connection closed: {}", e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame_parser::{FrameParser, LinkStatistics};
+    use crate::sample_source::ReportFromServer;
+
+    fn script(segments: Vec<Segment>) -> SimulatorScript {
+        SimulatorScript {
+            sample_rate: 1000.0,
+            num_channels: 3,
+            repeat: false,
+            seed: Some(1),
+            segments,
+            ..Default::default()
+        }
+    }
+
+    fn segment(duration: f64) -> Segment {
+        Segment {
+            duration,
+            ..Default::default()
+        }
+    }
+
+    fn run(script: SimulatorScript) -> (Vec<ReportFromServer>, LinkStatistics) {
+        let mut simulator = Simulator::new(script);
+        let mut parser = FrameParser::new();
+        let mut reports = Vec::new();
+        while !simulator.finished() {
+            if let Some(frame) = simulator.next_frame(7) {
+                parser.push_bytes(&frame);
+            }
+            reports.extend(std::iter::from_fn(|| parser.next_report()));
+        }
+        (reports, parser.statistics())
+    }
+
+    #[test]
+    fn samples_survive_encoding() {
+        let script = SimulatorScript {
+            noise_amplitude: 0.0,
+            mains_amplitude: 100.0,
+            ..script(vec![segment(0.1)])
+        };
+        let (reports, statistics) = run(script.clone());
+        let samples: Vec<_> = reports.iter().flat_map(|r| r.samples.clone()).collect();
+        assert_eq!(samples.len(), 100);
+        let mut next_index = 0;
+        for report in &reports {
+            assert_eq!(report.first_sample_index, next_index);
+            next_index += report.samples.len() as u64;
+        }
+        for (index, sample) in samples.iter().enumerate() {
+            let time = index as f64 / script.sample_rate;
+            let expected: Vec<u16> = (0..script.num_channels)
+                .map(|channel| {
+                    (script.baseline
+                        + (time * TAU * script.mains_frequency + channel as f64).sin()
+                            * script.mains_amplitude)
+                        .round() as u16
+                })
+                .collect();
+            assert_eq!(sample, &expected, "sample {}", index);
+        }
+        assert_eq!(
+            statistics,
+            LinkStatistics {
+                frames: reports.len() as u64,
+                samples: 100,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn dropped_frames_show_up_as_dropped_samples() {
+        let mut simulator = Simulator::new(script(vec![Segment {
+            drop_probability: 0.3,
+            ..segment(1.0)
+        }]));
+        let mut parser = FrameParser::new();
+        let mut lost = 0;
+        let mut expected_dropped = 0;
+        while !simulator.finished() {
+            let first_sample_index = simulator.next_sample_index;
+            match simulator.next_frame(7) {
+                Some(frame) => {
+                    parser.push_bytes(&frame);
+                    assert!(parser.next_report().is_some());
+                    // the parser can only notice a gap once a later frame arrives
+                    expected_dropped += lost;
+                    lost = 0;
+                }
+                None => lost += simulator.next_sample_index - first_sample_index,
+            }
+        }
+        assert!(expected_dropped > 0);
+        assert_eq!(parser.statistics().dropped_samples, expected_dropped);
+    }
+
+    #[test]
+    fn restarts_change_the_run_id_and_start_the_indices_over() {
+        let (reports, statistics) = run(script(vec![
+            segment(0.05),
+            Segment {
+                restart: true,
+                ..segment(0.05)
+            },
+        ]));
+        let first_run_id = reports[0].server_run_id;
+        let restart = reports
+            .iter()
+            .position(|r| r.server_run_id != first_run_id)
+            .unwrap();
+        let (before, after) = reports.split_at(restart);
+        assert_eq!(before.iter().map(|r| r.samples.len()).sum::<usize>(), 50);
+        assert_eq!(after.iter().map(|r| r.samples.len()).sum::<usize>(), 50);
+        assert_eq!(after[0].first_sample_index, 0);
+        assert!(after
+            .iter()
+            .all(|r| r.server_run_id == after[0].server_run_id));
+        assert_eq!(statistics.dropped_samples, 0);
+    }
+
+    #[test]
+    fn saturated_channels_are_pinned_at_the_maximum() {
+        let (reports, _) = run(script(vec![Segment {
+            // channels the device doesn't have are ignored
+            saturate_channels: vec![1, 7],
+            ..segment(0.05)
+        }]));
+        let samples: Vec<_> = reports.iter().flat_map(|r| r.samples.clone()).collect();
+        assert_eq!(samples.len(), 50);
+        for sample in samples {
+            assert_eq!(sample[1], 4095);
+            assert_ne!(sample[0], 4095);
+            assert_ne!(sample[2], 4095);
+        }
+    }
+
+    #[test]
+    fn scripts_without_duration_finish() {
+        for segments in [vec![], vec![segment(0.0), segment(0.0)]] {
+            let mut simulator = Simulator::new(SimulatorScript {
+                repeat: true,
+                ..script(segments)
+            });
+            assert_eq!(simulator.next_frame(7), None);
+            assert!(simulator.finished());
+        }
+    }
+}
@@ -1,26 +1,32 @@
-#[cfg(feature = "bluetooth")]
-use crate::bluetooth::{messages_from_server, ReportFromServer};
+use crate::artifacts::Artifact;
+use crate::calibration::{Calibration, CalibrationProgress, CalibrationResults, CalibrationState};
+use crate::crosstalk::CrossTalk;
+use crate::decisions::{DecisionContext, DecisionMaker, DecisionObserver};
 use crate::follower::{
-    FollowerIntroduction, LocalFollower, MessageFromFollower, RemoteFollower, SupervisedFollower,
-    SupervisedFollowerMut,
+    FollowerIntroduction, LocalFollower, MessageFromFollower, MessageToFollower, RemoteFollower,
+    SupervisedFollower, SupervisedFollowerMut,
 };
-use crate::remote_time_estimator::RemoteTimeEstimator;
-#[cfg(not(feature = "bluetooth"))]
-use crate::serial_port_communication::{messages_from_server, ReportFromServer};
-use crate::signal::Signal;
+use crate::frame_parser::LinkStatistics;
+use crate::gestures::GestureMapping;
+use crate::profiles::{
+    check_profile_name, discard_unsaved_changes, list_profiles, Profile, PROFILES_DIRECTORY,
+};
+use crate::recording::{default_recording_path, RecordedServer, Recorder, RecordingHeader};
+use crate::sample_source::{
+    ReportFromServer, SampleSource, SessionInfo, SourceEvent, DEFAULT_SAMPLE_RATE,
+};
+use crate::signal::{MainsFrequency, Signal};
+use crate::timeline::{GapHandling, SampleGap, Timeline};
 use crate::utils::{DatagramsExt, IncomingUniStreamsExt};
-use crate::webserver::{MessageFromFrontend, MessageToFrontend};
-use actix::{Actor, Addr, Context, Handler, Message};
+use crate::webserver::{FrequenciesFrame, HistoryFrame, MessageFromFrontend, MessageToFrontend};
+use actix::{Actor, Addr, AsyncContext, Context, Handler, Message};
 use anyhow::{bail, Context as _};
 use log::info;
 use rodio::OutputStream;
 //use rustfft::FftPlanner;
 use crate::webserver_glue::FrontendSession;
 use async_bincode::{AsyncBincodeReader, AsyncBincodeWriter};
-use itertools::multizip;
-use statrs::statistics::Statistics;
 use std::collections::HashMap;
-use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
@@ -30,11 +36,23 @@ use tokio::task;
 use tokio_stream::StreamExt;
 
 pub struct SupervisorOptions {
-    pub server_address: String,
+    pub sources: Vec<SampleSource>,
     pub gui_port: u16,
     pub follower_port: u16,
+    pub record_path: Option<PathBuf>,
+    pub mains_frequency: MainsFrequency,
+    pub gestures: GestureMapping,
+    // where edits to the gesture mapping get saved, if anywhere
+    pub gestures_path: Option<PathBuf>,
+    pub profile: String,
 }
 
+const RECORDINGS_DIRECTORY: &str = "recordings";
+// how often to check for samples that have waited long enough for a quiet server
+const TIMELINE_FLUSH_INTERVAL: Duration = Duration::from_millis(20);
+// how often to autosave changed variables, so that dragging a slider doesn't write a file per step
+const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(1);
+
 enum FollowerId {
     Local,
     Remote(String),
@@ -42,8 +60,11 @@ enum FollowerId {
 
 pub struct SupervisedServer {
     server_run_id: u64,
-    remote_time_estimator: RemoteTimeEstimator,
-    signals: [Signal; 4],
+    sample_rate: f64,
+    mains_frequency: MainsFrequency,
+    // one per channel, as many as the server said it has
+    signals: Vec<Signal>,
+    link_statistics: LinkStatistics,
 }
 
 pub struct Supervisor {
@@ -55,17 +76,37 @@ pub struct Supervisor {
     active_follower_id: FollowerId,
 
     servers: Vec<SupervisedServer>,
+    timeline: Timeline,
 
     frontend_session: Option<Addr<FrontendSession>>,
+    recorder: Option<Recorder>,
 
     enabled: bool,
-    mouse_pressed: bool,
-    inputs_since_scroll_start: usize,
+    decision_maker: DecisionMaker,
+    gestures_path: Option<PathBuf>,
+    profile_name: String,
+    // the variables have been changed since the profile was last saved
+    profile_unsaved: bool,
+    // ... and since they were last autosaved
+    autosave_pending: bool,
+    // while calibrating, gestures don't do anything
+    calibration: Option<Calibration>,
+    // waiting for the user to apply them or not
+    calibration_results: Option<CalibrationResults>,
     //fft_planner: FftPlanner<f64>,
 }
 
 impl Actor for Supervisor {
     type Context = Context<Self>;
+
+    fn started(&mut self, context: &mut Self::Context) {
+        context.run_interval(TIMELINE_FLUSH_INTERVAL, |supervisor, _context| {
+            supervisor.process_ready_samples();
+        });
+        context.run_interval(AUTOSAVE_INTERVAL, |supervisor, _context| {
+            supervisor.autosave_profile();
+        });
+    }
 }
 
 #[derive(Debug, Message)]
@@ -82,6 +123,20 @@ pub struct ServerReconnected {
     server_index: usize,
 }
 
+#[derive(Debug, Message)]
+#[rtype(result = "()")]
+pub struct SessionInfoReceived {
+    server_index: usize,
+    info: SessionInfo,
+}
+
+#[derive(Debug, Message)]
+#[rtype(result = "()")]
+pub struct LinkStatisticsUpdated {
+    server_index: usize,
+    statistics: LinkStatistics,
+}
+
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct NewFollower {
@@ -102,6 +157,68 @@ pub struct MessageFromIdentifiedFollower {
     message: MessageFromFollower,
 }
 
+/// Collects decisions while the servers' signals are borrowed, so they can be applied afterwards.
+#[derive(Default)]
+struct CollectedDecisions {
+    // indexed by server, then channel
+    history_frames: Vec<Vec<Vec<HistoryFrame>>>,
+    frequencies_frames: Vec<Vec<Vec<FrequenciesFrame>>>,
+    follower_messages: Vec<MessageToFollower>,
+    samples_dropped: Vec<(usize, SampleGap)>,
+    mains_frequencies: Vec<MessageToFrontend>,
+}
+
+fn per_channel<T>(
+    frames: &mut Vec<Vec<Vec<T>>>,
+    server_index: usize,
+    channel: usize,
+) -> &mut Vec<T> {
+    if frames.len() <= server_index {
+        frames.resize_with(server_index + 1, Vec::new);
+    }
+    let server = &mut frames[server_index];
+    if server.len() <= channel {
+        server.resize_with(channel + 1, Vec::new);
+    }
+    &mut server[channel]
+}
+
+impl DecisionObserver for CollectedDecisions {
+    fn history_frame(&mut self, server_index: usize, channel: usize, frame: HistoryFrame) {
+        per_channel(&mut self.history_frames, server_index, channel).push(frame);
+    }
+    fn frequencies_frame(&mut self, server_index: usize, channel: usize, frame: FrequenciesFrame) {
+        per_channel(&mut self.frequencies_frames, server_index, channel).push(frame);
+    }
+    fn follower_message(&mut self, message: MessageToFollower) {
+        self.follower_messages.push(message);
+    }
+    fn samples_dropped(&mut self, server_index: usize, gap: SampleGap) {
+        self.samples_dropped.push((server_index, gap));
+    }
+    fn activation_suppressed(&mut self, server_index: usize, channel: usize, artifact: Artifact) {
+        info!(
+            "server {} channel {} didn't activate because of a {}",
+            server_index, channel, artifact
+        );
+    }
+    fn mains_frequency_changed(
+        &mut self,
+        server_index: usize,
+        channel: usize,
+        frequency: f64,
+        detected: bool,
+    ) {
+        self.mains_frequencies
+            .push(MessageToFrontend::UpdateMainsFrequency {
+                server_index,
+                channel,
+                frequency,
+                detected,
+            });
+    }
+}
+
 trait NotifyOptionExt {
     fn notify(&mut self, message: MessageToFrontend);
 }
@@ -115,9 +232,14 @@ impl NotifyOptionExt for Option<Addr<FrontendSession>> {
 }
 
 impl SupervisedServer {
+    /// Start the signals over, e.g. because the server restarted. The number of channels stays the same
+    /// until the server says otherwise, so that the numbering of later servers' channels doesn't change.
     fn reconnected(&mut self) {
-        self.signals = Default::default();
-        self.remote_time_estimator = RemoteTimeEstimator::default();
+        self.signals = self
+            .signals
+            .iter()
+            .map(|_| Signal::new(self.sample_rate, self.mains_frequency))
+            .collect();
     }
 }
 
@@ -131,7 +253,7 @@ impl Supervisor {
         }
     }
     fn update_active_follower(&mut self) {
-        if self.mouse_pressed {
+        if self.decision_maker.holding_anything() {
             return;
         }
         let earliest_remote = self
@@ -149,6 +271,250 @@ impl Supervisor {
             self.active_follower_id = FollowerId::Local
         }
     }
+    fn start_recording(&mut self, path: Option<PathBuf>) {
+        self.stop_recording();
+        let path = path.unwrap_or_else(|| default_recording_path(RECORDINGS_DIRECTORY));
+        let header = RecordingHeader {
+            servers: self
+                .servers
+                .iter()
+                .map(|server| RecordedServer {
+                    // a server can't have more channels than fit in a frame
+                    num_channels: server.signals.len() as u8,
+                    nominal_sample_rate: server.sample_rate,
+                })
+                .collect(),
+            variables: crate::variables::get_variables(),
+            channel_variables: crate::variables::get_channel_variables(),
+            frequency_weights: crate::variables::get_frequency_weights(),
+            crosstalk: self.decision_maker.crosstalk().clone(),
+        };
+        match Recorder::create(&path, &header) {
+            Ok(recorder) => {
+                info!("recording to {}", path.display());
+                self.recorder = Some(recorder);
+            }
+            Err(e) => self.report_error("Failed to start recording", e),
+        }
+        self.notify_recording_state();
+    }
+    fn stop_recording(&mut self) {
+        if let Some(recorder) = self.recorder.take() {
+            match recorder.finish() {
+                Ok(path) => info!("finished recording {}", path.display()),
+                Err(e) => eprintln!("Error finishing recording: {:?}", e),
+            }
+        }
+        self.notify_recording_state();
+    }
+    fn notify_recording_state(&mut self) {
+        let path = self
+            .recorder
+            .as_ref()
+            .map(|recorder| recorder.path().display().to_string());
+        self.frontend_session
+            .notify(MessageToFrontend::UpdateRecording { path });
+    }
+    fn set_gesture_mapping(&mut self, mapping: GestureMapping) {
+        if let Err(e) = mapping.validate() {
+            eprintln!("Rejected gesture mapping: {:?}", e);
+            self.notify_gesture_mapping(Some(e.to_string()));
+        } else {
+            let mut decisions = CollectedDecisions::default();
+            self.decision_maker.set_mapping(mapping, &mut decisions);
+            for message in decisions.follower_messages {
+                self.active_follower().handle_message(message);
+            }
+            if let Some(path) = &self.gestures_path {
+                match self.decision_maker.mapping().save(path) {
+                    Ok(()) => info!("saved gesture mapping to {}", path.display()),
+                    Err(e) => self.report_error("Error saving gesture mapping", e),
+                }
+            }
+            self.notify_gesture_mapping(None);
+        }
+    }
+    fn switch_profile(&mut self, name: &str) -> anyhow::Result<()> {
+        // so that the profile we're leaving keeps its latest changes
+        self.autosave_profile();
+        let (profile, unsaved) = Profile::load_latest(PROFILES_DIRECTORY, name)?;
+        if profile.is_none() {
+            info!(
+                "profile {:?} hasn't been saved yet, so starting from the defaults",
+                name
+            );
+        }
+        self.apply_profile(profile.unwrap_or_default());
+        self.profile_name = name.to_string();
+        self.profile_unsaved = unsaved;
+        self.notify_profile();
+        Ok(())
+    }
+    fn apply_profile(&mut self, profile: Profile) {
+        for e in crate::variables::reset_variables(&profile.variables, &profile.channel_variables) {
+            self.report_error("Ignoring variable in profile", e);
+        }
+        for e in crate::variables::reset_frequency_weights(&profile.frequency_weights) {
+            self.report_error("Ignoring frequency weights in profile", e);
+        }
+        if let Err(e) = self.decision_maker.set_crosstalk(profile.crosstalk) {
+            self.report_error("Ignoring cross-talk in profile", e);
+            self.decision_maker.set_crosstalk(CrossTalk::new()).unwrap();
+        }
+        self.notify_variables();
+        self.notify_crosstalk();
+    }
+    fn current_profile(&self) -> Profile {
+        Profile {
+            variables: crate::variables::get_variables(),
+            channel_variables: crate::variables::get_channel_variables(),
+            frequency_weights: crate::variables::get_frequency_weights(),
+            crosstalk: self.decision_maker.crosstalk().clone(),
+        }
+    }
+    fn save_profile_as(&mut self, name: &str) -> anyhow::Result<()> {
+        check_profile_name(name)?;
+        self.current_profile().save(PROFILES_DIRECTORY, name)?;
+        if name != self.profile_name {
+            // the unsaved changes went into the new profile, so the old one stays as it was saved
+            discard_unsaved_changes(PROFILES_DIRECTORY, &self.profile_name)?;
+            self.profile_name = name.to_string();
+        }
+        info!("saved profile {:?}", name);
+        self.profile_unsaved = false;
+        self.autosave_pending = false;
+        self.notify_profile();
+        Ok(())
+    }
+    fn revert_profile(&mut self) -> anyhow::Result<()> {
+        let profile = Profile::load_saved(PROFILES_DIRECTORY, &self.profile_name)?;
+        discard_unsaved_changes(PROFILES_DIRECTORY, &self.profile_name)?;
+        self.apply_profile(profile.unwrap_or_default());
+        self.profile_unsaved = false;
+        self.autosave_pending = false;
+        self.notify_profile();
+        Ok(())
+    }
+    fn variable_changed(&mut self) {
+        self.autosave_pending = true;
+        if !self.profile_unsaved {
+            self.profile_unsaved = true;
+            self.notify_profile();
+        }
+    }
+    fn autosave_profile(&mut self) {
+        if !self.autosave_pending {
+            return;
+        }
+        self.autosave_pending = false;
+        if let Err(e) = self
+            .current_profile()
+            .autosave(PROFILES_DIRECTORY, &self.profile_name)
+        {
+            self.report_error("Error autosaving profile", e);
+        }
+    }
+    /// Lets go of anything the gestures were holding, like a mouse button.
+    fn release_all(&mut self) {
+        let mut decisions = CollectedDecisions::default();
+        self.decision_maker.release_all(&mut decisions);
+        for message in decisions.follower_messages {
+            self.active_follower().handle_message(message);
+        }
+    }
+    fn start_calibration(&mut self) -> anyhow::Result<()> {
+        let num_channels = self.servers.iter().map(|server| server.signals.len()).sum();
+        self.calibration = Some(Calibration::new(
+            num_channels,
+            self.decision_maker.mapping(),
+        )?);
+        self.calibration_results = None;
+        self.release_all();
+        self.notify_calibration();
+        Ok(())
+    }
+    fn calibration_progressed(&mut self, progress: CalibrationProgress) {
+        if let CalibrationProgress::Finished = progress {
+            let calibration = self.calibration.take().unwrap();
+            self.calibration_results = Some(calibration.results());
+        }
+        self.notify_calibration();
+    }
+    fn apply_calibration(&mut self) -> anyhow::Result<()> {
+        let Some(results) = self.calibration_results.take() else {
+            bail!("there's no finished calibration to apply");
+        };
+        for calibration in &results.channels {
+            let channel = calibration.channel;
+            crate::variables::set_frequency_weights(
+                channel,
+                Some(calibration.frequency_weights.clone()),
+            )?;
+            if let Some(threshold) = calibration.activity_threshold {
+                crate::variables::set_channel_variable(
+                    channel,
+                    "activity_threshold",
+                    Some(threshold),
+                )?;
+            }
+            if let Some(signal) = self
+                .servers
+                .iter_mut()
+                .flat_map(|server| &mut server.signals)
+                .nth(channel)
+            {
+                signal.set_activity_threshold_stats(&calibration.threshold_stats);
+            }
+        }
+        self.decision_maker.set_crosstalk(results.crosstalk)?;
+        info!("applied calibration of {} channels", results.channels.len());
+        self.variable_changed();
+        self.notify_variables();
+        self.notify_crosstalk();
+        self.notify_calibration();
+        Ok(())
+    }
+    fn notify_calibration(&mut self) {
+        let state = match (&self.calibration, &self.calibration_results) {
+            (Some(calibration), _) => calibration.state(),
+            (None, Some(results)) => CalibrationState::Finished(results.clone()),
+            (None, None) => CalibrationState::Idle,
+        };
+        self.frontend_session
+            .notify(MessageToFrontend::UpdateCalibration(state));
+    }
+    fn notify_variables(&mut self) {
+        self.frontend_session
+            .notify(MessageToFrontend::UpdateVariables {
+                variables: crate::variables::get_variables(),
+                channel_variables: crate::variables::get_channel_variables(),
+            });
+    }
+    fn notify_crosstalk(&mut self) {
+        let crosstalk = self.decision_maker.crosstalk().clone();
+        self.frontend_session
+            .notify(MessageToFrontend::UpdateCrossTalk { crosstalk });
+    }
+    fn notify_profile(&mut self) {
+        self.frontend_session
+            .notify(MessageToFrontend::UpdateProfile {
+                name: self.profile_name.clone(),
+                unsaved_changes: self.profile_unsaved,
+                profiles: list_profiles(PROFILES_DIRECTORY),
+            });
+    }
+    /// For errors caused by something the user did in the GUI, so they can see what went wrong.
+    fn report_error(&mut self, context: &str, error: anyhow::Error) {
+        eprintln!("{}: {:?}", context, error);
+        self.frontend_session.notify(MessageToFrontend::Error {
+            message: format!("{}: {:#}", context, error),
+        });
+    }
+    fn notify_gesture_mapping(&mut self, error: Option<String>) {
+        let mapping = self.decision_maker.mapping().clone();
+        self.frontend_session
+            .notify(MessageToFrontend::UpdateGestureMapping { mapping, error });
+    }
     // fn update_frontend(&mut self) {
     //     let start_time = self.start_time;
     //     let latest_time = self.servers[0].signals[0]
@@ -190,9 +556,33 @@ impl Handler<NewFrontendSession> for Supervisor {
     ) -> Self::Result {
         message.session.do_send(MessageToFrontend::Initialize {
             enabled: self.enabled,
-            variables: crate::utils::get_variables(),
+            variables: crate::variables::get_variables(),
+            channel_variables: crate::variables::get_channel_variables(),
+            variable_info: crate::variables::variable_info().to_vec(),
         });
         self.frontend_session = Some(message.session);
+        self.notify_recording_state();
+        self.notify_profile();
+        self.notify_gesture_mapping(None);
+        self.notify_calibration();
+        self.notify_crosstalk();
+        for (server_index, server) in self.servers.iter().enumerate() {
+            self.frontend_session
+                .notify(MessageToFrontend::UpdateLinkStatistics {
+                    server_index,
+                    statistics: server.link_statistics,
+                });
+            for (channel, signal) in server.signals.iter().enumerate() {
+                let (frequency, detected) = signal.mains_frequency();
+                self.frontend_session
+                    .notify(MessageToFrontend::UpdateMainsFrequency {
+                        server_index,
+                        channel,
+                        frequency,
+                        detected,
+                    });
+            }
+        }
     }
 }
 
@@ -206,13 +596,69 @@ impl Handler<MessageFromFrontend> for Supervisor {
     ) -> Self::Result {
         match message {
             MessageFromFrontend::SetEnabled(new_enabled) => {
-                if self.mouse_pressed && !new_enabled {
-                    self.active_follower().mouse_up();
-                    self.mouse_pressed = false;
+                if !new_enabled {
+                    self.release_all();
                 }
                 self.enabled = new_enabled;
             }
-            MessageFromFrontend::SetVariable(key, value) => crate::utils::set_variable(&key, value),
+            MessageFromFrontend::SetVariable(key, value) => {
+                match crate::variables::set_variable(&key, value) {
+                    Ok(()) => self.variable_changed(),
+                    Err(e) => {
+                        self.report_error("Rejected variable", e);
+                        // put the frontend's controls back how they were
+                        self.notify_variables();
+                    }
+                }
+            }
+            MessageFromFrontend::SetChannelVariable(channel, key, value) => {
+                match crate::variables::set_channel_variable(channel, &key, value) {
+                    Ok(()) => self.variable_changed(),
+                    Err(e) => {
+                        self.report_error("Rejected variable", e);
+                        self.notify_variables();
+                    }
+                }
+            }
+            MessageFromFrontend::StartRecording => self.start_recording(None),
+            MessageFromFrontend::StopRecording => self.stop_recording(),
+            MessageFromFrontend::SetGestureMapping(mapping) => self.set_gesture_mapping(mapping),
+            MessageFromFrontend::SwitchProfile(name) => {
+                if let Err(e) = self.switch_profile(&name) {
+                    self.report_error("Error switching profile", e);
+                }
+            }
+            MessageFromFrontend::SaveProfile => {
+                let name = self.profile_name.clone();
+                if let Err(e) = self.save_profile_as(&name) {
+                    self.report_error("Error saving profile", e);
+                }
+            }
+            MessageFromFrontend::SaveProfileAs(name) => {
+                if let Err(e) = self.save_profile_as(&name) {
+                    self.report_error("Error saving profile", e);
+                }
+            }
+            MessageFromFrontend::StartCalibration => {
+                if let Err(e) = self.start_calibration() {
+                    self.report_error("Can't calibrate", e);
+                }
+            }
+            MessageFromFrontend::CancelCalibration => {
+                self.calibration = None;
+                self.calibration_results = None;
+                self.notify_calibration();
+            }
+            MessageFromFrontend::ApplyCalibration => {
+                if let Err(e) = self.apply_calibration() {
+                    self.report_error("Error applying calibration", e);
+                }
+            }
+            MessageFromFrontend::RevertProfile => {
+                if let Err(e) = self.revert_profile() {
+                    self.report_error("Error reverting profile", e);
+                }
+            }
         }
     }
 }
@@ -250,6 +696,56 @@ impl Handler<ServerReconnected> for Supervisor {
     fn handle(&mut self, message: ServerReconnected, _context: &mut Self::Context) -> Self::Result {
         let ServerReconnected { server_index } = message;
         self.servers[server_index].reconnected();
+        self.timeline.restart_server(server_index);
+    }
+}
+
+impl Handler<SessionInfoReceived> for Supervisor {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        message: SessionInfoReceived,
+        _context: &mut Self::Context,
+    ) -> Self::Result {
+        let SessionInfoReceived { server_index, info } = message;
+        let server = &mut self.servers[server_index];
+        if server.sample_rate != info.sample_rate {
+            info!("server {} samples at {} Hz", server_index, info.sample_rate);
+            server.sample_rate = info.sample_rate;
+            server.reconnected();
+            self.timeline.restart_server(server_index);
+        }
+    }
+}
+
+impl Handler<LinkStatisticsUpdated> for Supervisor {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        message: LinkStatisticsUpdated,
+        _context: &mut Self::Context,
+    ) -> Self::Result {
+        let LinkStatisticsUpdated {
+            server_index,
+            statistics,
+        } = message;
+        let server = &mut self.servers[server_index];
+        if statistics != server.link_statistics {
+            if statistics.corrupted_frames > server.link_statistics.corrupted_frames {
+                eprintln!(
+                    "Server {}: {} corrupted frames so far",
+                    server_index, statistics.corrupted_frames
+                );
+            }
+            server.link_statistics = statistics;
+            self.frontend_session
+                .notify(MessageToFrontend::UpdateLinkStatistics {
+                    server_index,
+                    statistics,
+                });
+        }
     }
 }
 
@@ -262,15 +758,49 @@ impl Handler<MessageFromServer> for Supervisor {
             local_time_received,
             report,
         } = message;
-        if self.servers[server_index].server_run_id != report.server_run_id {
-            self.servers[server_index].server_run_id = report.server_run_id;
-            self.servers[server_index].reconnected();
+        if let Some(recorder) = &mut self.recorder {
+            let sample_rate = self.servers[server_index].sample_rate;
+            if let Err(e) = recorder.record(server_index, local_time_received, &report, sample_rate)
+            {
+                eprintln!("Error writing recording, stopping it: {:?}", e);
+                self.recorder = None;
+                self.notify_recording_state();
+            }
         }
-        self.servers[server_index].remote_time_estimator.observe(
-            (report.first_sample_index + report.samples.len() as u64 - 1) as f64,
-            local_time_received,
-        );
+        let server = &mut self.servers[server_index];
+        if server.server_run_id != report.server_run_id
+            || server.signals.len() != report.num_channels()
+        {
+            if server.signals.len() != report.num_channels() {
+                info!(
+                    "server {} has {} channels",
+                    server_index,
+                    report.num_channels()
+                );
+            }
+            server.server_run_id = report.server_run_id;
+            server.signals = (0..report.num_channels())
+                .map(|_| Signal::new(server.sample_rate, server.mains_frequency))
+                .collect();
+            self.timeline.restart_server(server_index);
+        }
+        self.timeline
+            .add_report(server_index, &report, local_time_received);
+        self.process_ready_samples();
+    }
+}
 
+impl Supervisor {
+    /// Channels are numbered across all servers in order, so this is where `server_index`'s channels start.
+    fn first_channel(&self, server_index: usize) -> usize {
+        self.servers[..server_index]
+            .iter()
+            .map(|server| server.signals.len())
+            .sum()
+    }
+
+    /// Feeds every sample that the timeline is ready to give us through the decision logic, in local time order.
+    fn process_ready_samples(&mut self) {
         self.local_follower.update_most_recent_mouse_move();
         self.frontend_session
             .notify(MessageToFrontend::UpdateFollower {
@@ -281,70 +811,36 @@ impl Handler<MessageFromServer> for Supervisor {
 
         self.update_active_follower();
 
-        let mut new_history_frames = [const { Vec::new() }; 4];
-        let mut new_frequencies_frames = [const { Vec::new() }; 4];
-
+        let now = Instant::now();
         let move_time = self.active_follower().most_recent_mouse_move();
-        let recently_moved = (Instant::now() - move_time) < Duration::from_millis(50);
-        let anywhere_near_recently_moved =
-            (Instant::now() - move_time) < Duration::from_millis(10000);
-
-        for (sample_index_within_report, inputs) in report.samples.iter().enumerate() {
-            let _average = inputs.iter().map(|&i| i as f64).mean();
+        let decision_context = DecisionContext {
+            enabled: self.enabled && self.calibration.is_none(),
+            recently_moved: (now - move_time) < Duration::from_millis(50),
+            anywhere_near_recently_moved: (now - move_time) < Duration::from_millis(10000),
+        };
+        let mut decisions = CollectedDecisions::default();
 
-            let mouse_active_before = self.servers[server_index].signals[2].is_active();
-            for (signal, &input, new_history_frames, new_frequencies_frames) in multizip((
+        while let Some(sample) = self.timeline.pop_ready(now) {
+            let server_index = sample.server_index;
+            let first_channel = self.first_channel(server_index);
+            let time = sample.sample_index as f64 / self.servers[server_index].sample_rate;
+            self.decision_maker.process_sample(
                 &mut self.servers[server_index].signals,
-                inputs,
-                &mut new_history_frames,
-                &mut new_frequencies_frames,
-            )) {
-                signal.receive_raw(
-                    input as f64, //- inputs[3] as f64 + 1500.0, /*- average*/
-                    (report.first_sample_index + sample_index_within_report as u64) as f64 / 1020.0,
-                    mouse_active_before && !anywhere_near_recently_moved,
-                    //&mut self.fft_planner,
-                    |f| new_history_frames.push(f),
-                    |f| new_frequencies_frames.push(f),
+                first_channel,
+                &sample,
+                time,
+                decision_context,
+                &mut decisions,
+            );
+            let progress = self.calibration.as_mut().and_then(|calibration| {
+                calibration.observe(
+                    &self.servers[server_index].signals,
+                    first_channel,
+                    sample.local_time,
                 )
-            }
-
-            if self.servers[server_index].signals[2].is_active() != mouse_active_before {
-                if self.servers[server_index].signals[2].is_active() {
-                    if self.enabled && !recently_moved && anywhere_near_recently_moved {
-                        self.active_follower().mousedown();
-                        self.mouse_pressed = true;
-                    }
-                } else {
-                    if self.mouse_pressed {
-                        assert!(self.enabled);
-                        self.active_follower().mouse_up();
-                        self.mouse_pressed = false;
-                    }
-                }
-            }
-
-            if self.enabled
-                && self.servers[server_index].signals[0].is_active()
-                    != self.servers[server_index].signals[1].is_active()
-            {
-                fn progress(inputs: usize) -> usize {
-                    let s = 400;
-                    let denom = 300 * s;
-                    (inputs * s + inputs * inputs + denom - 1) / denom
-                }
-                if progress(self.inputs_since_scroll_start + 1)
-                    > progress(self.inputs_since_scroll_start)
-                {
-                    if self.servers[server_index].signals[0].is_active() {
-                        self.active_follower().scroll_y(1);
-                    } else {
-                        self.active_follower().scroll_y(-1);
-                    }
-                }
-                self.inputs_since_scroll_start += 1;
-            } else {
-                self.inputs_since_scroll_start = 0;
+            });
+            if let Some(progress) = progress {
+                self.calibration_progressed(progress);
             }
 
             self.total_inputs += 1;
@@ -355,19 +851,58 @@ impl Handler<MessageFromServer> for Supervisor {
             //     report.time_since_start.as_micros(),
             // );
         }
-        if !new_history_frames[0].is_empty() {
-            self.frontend_session
-                .notify(MessageToFrontend::NewHistoryFrames {
-                    server_index,
-                    frames: new_history_frames,
-                });
+        let CollectedDecisions {
+            history_frames,
+            frequencies_frames,
+            follower_messages,
+            samples_dropped,
+            mains_frequencies,
+        } = decisions;
+        for message in follower_messages {
+            self.active_follower().handle_message(message);
         }
-        if !new_frequencies_frames[0].is_empty() {
+        for (server_index, gap) in samples_dropped {
+            if gap.handling == GapHandling::Reset {
+                eprintln!(
+                    "Server {} dropped {} samples; resetting its signals",
+                    server_index, gap.num_missing
+                );
+            }
             self.frontend_session
-                .notify(MessageToFrontend::NewFrequenciesFrames {
-                    server_index,
-                    frames: new_frequencies_frames,
-                });
+                .notify(MessageToFrontend::SamplesDropped { server_index, gap });
+        }
+        for message in mains_frequencies {
+            if let MessageToFrontend::UpdateMainsFrequency {
+                server_index,
+                channel,
+                frequency,
+                detected: true,
+            } = message
+            {
+                info!(
+                    "detected {} Hz mains on server {} channel {}",
+                    frequency, server_index, channel
+                );
+            }
+            self.frontend_session.notify(message);
+        }
+        for (server_index, frames) in history_frames.into_iter().enumerate() {
+            if frames.iter().any(|frames| !frames.is_empty()) {
+                self.frontend_session
+                    .notify(MessageToFrontend::NewHistoryFrames {
+                        server_index,
+                        frames,
+                    });
+            }
+        }
+        for (server_index, frames) in frequencies_frames.into_iter().enumerate() {
+            if frames.iter().any(|frames| !frames.is_empty()) {
+                self.frontend_session
+                    .notify(MessageToFrontend::NewFrequenciesFrames {
+                        server_index,
+                        frames,
+                    });
+            }
         }
     }
 }
@@ -375,13 +910,17 @@ impl Handler<MessageFromServer> for Supervisor {
 impl Supervisor {
     pub async fn run(
         SupervisorOptions {
-            server_address,
+            sources,
             gui_port,
             follower_port,
+            record_path,
+            mains_frequency,
+            gestures,
+            gestures_path,
+            profile,
         }: SupervisorOptions,
     ) -> anyhow::Result<()> {
         let start_time = Instant::now();
-        let server_addresses = [server_address.parse::<SocketAddr>().unwrap()];
 
         let audio_output_stream_handle = {
             let (_audio_output_stream, audio_output_stream_handle) =
@@ -391,43 +930,71 @@ impl Supervisor {
         };
         let local_follower =
             SupervisedFollower::new(LocalFollower::new(audio_output_stream_handle));
-        let supervisor = Supervisor {
+        let mut supervisor = Supervisor {
             start_time,
             total_inputs: 0,
             local_follower,
             remote_followers: HashMap::new(),
             active_follower_id: FollowerId::Local,
-            servers: server_addresses
+            servers: sources
                 .iter()
-                .map(|&_address| SupervisedServer {
+                .map(|_source| SupervisedServer {
                     server_run_id: 0,
-                    remote_time_estimator: RemoteTimeEstimator::default(),
-                    signals: Default::default(),
+                    sample_rate: DEFAULT_SAMPLE_RATE,
+                    mains_frequency,
+                    signals: Vec::new(),
+                    link_statistics: LinkStatistics::default(),
                 })
                 .collect(),
+            timeline: Timeline::new(start_time, sources.len()),
             frontend_session: None,
+            recorder: None,
             enabled: false,
-            mouse_pressed: false,
-
+            decision_maker: DecisionMaker::new(gestures),
+            gestures_path,
+            profile_name: profile.clone(),
+            profile_unsaved: false,
+            autosave_pending: false,
+            calibration: None,
+            calibration_results: None,
             //fft_planner: FftPlanner::new(),
-            inputs_since_scroll_start: 0,
+        };
+        supervisor.switch_profile(&profile)?;
+        if record_path.is_some() {
+            supervisor.start_recording(record_path);
         }
-        .start();
+        let supervisor = supervisor.start();
 
-        task::spawn({
+        for (server_index, source) in sources.into_iter().enumerate() {
             let supervisor = supervisor.clone();
-            async move {
-                let mut stream = messages_from_server();
-                while let Some(report) = stream.next().await {
-                    let local_time_received = Instant::now();
-                    supervisor.do_send(MessageFromServer {
-                        server_index: 0,
-                        local_time_received,
-                        report,
-                    })
+            task::spawn(async move {
+                let mut stream = source.events();
+                while let Some(event) = stream.next().await {
+                    match event {
+                        SourceEvent::Report(report) => {
+                            let local_time_received = Instant::now();
+                            supervisor.do_send(MessageFromServer {
+                                server_index,
+                                local_time_received,
+                                report,
+                            })
+                        }
+                        SourceEvent::SessionInfo(info) => {
+                            supervisor.do_send(SessionInfoReceived { server_index, info })
+                        }
+                        SourceEvent::Reconnected => {
+                            supervisor.do_send(ServerReconnected { server_index })
+                        }
+                        SourceEvent::LinkStatistics(statistics) => {
+                            supervisor.do_send(LinkStatisticsUpdated {
+                                server_index,
+                                statistics,
+                            })
+                        }
+                    }
                 }
-            }
-        });
+            });
+        }
 
         // for (server_index, server_address) in server_addresses.iter().cloned().enumerate() {
         //     let supervisor = supervisor.clone();
@@ -0,0 +1,225 @@
+use crate::variables::get_variable;
+use serde::{Deserialize, Serialize};
+
+/*
+
+Turns the activations of one channel into taps, double taps and holds, so that gestures can be
+more than "on for as long as the muscle is on".
+
+An activation no longer than `tap_max_duration` seconds is a tap, but it's only reported once
+`double_tap_max_gap` seconds have passed without another one starting; if a second tap starts
+within that time, the pair is reported as a double tap instead, when the second one ends.
+An activation that lasts `hold_min_duration` seconds becomes a hold, which lasts until the
+activation ends. Activations in between are ignored, so a press that was a little too long to be
+a tap doesn't turn into a hold.
+
+*/
+
+#[derive(Copy, Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub enum TapEvent {
+    Tap,
+    DoubleTap,
+    Hold,
+    /// the end of a hold
+    Release,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+enum TapState {
+    #[default]
+    Idle,
+    Down {
+        since: f64,
+        // this activation might be the second half of a double tap
+        after_tap: bool,
+        holding: bool,
+    },
+    WaitingForSecondTap {
+        released_at: f64,
+    },
+}
+
+/// The timing variables, in seconds, read once for all the channels of a sample.
+#[derive(Copy, Clone, Debug)]
+pub struct TapTimings {
+    pub tap_max_duration: f64,
+    pub double_tap_max_gap: f64,
+    pub hold_min_duration: f64,
+}
+
+impl TapTimings {
+    pub fn from_variables() -> TapTimings {
+        TapTimings {
+            tap_max_duration: get_variable("tap_max_duration"),
+            double_tap_max_gap: get_variable("double_tap_max_gap"),
+            hold_min_duration: get_variable("hold_min_duration"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TapRecognizer {
+    state: TapState,
+}
+
+impl TapRecognizer {
+    /// Whether we're between a `Hold` and its `Release`.
+    pub fn holding(&self) -> bool {
+        matches!(self.state, TapState::Down { holding: true, .. })
+    }
+
+    /// Observes whether the channel is active at `time` (in seconds), and reports any events that
+    /// that completes. A double tap that turns into a hold reports the first tap, then the hold.
+    pub fn update(
+        &mut self,
+        active: bool,
+        time: f64,
+        timings: &TapTimings,
+        mut report: impl FnMut(TapEvent),
+    ) {
+        let &TapTimings {
+            tap_max_duration,
+            double_tap_max_gap,
+            hold_min_duration,
+        } = timings;
+        self.state = match (self.state, active) {
+            (TapState::Idle, false) => TapState::Idle,
+            (TapState::Idle, true) => TapState::Down {
+                since: time,
+                after_tap: false,
+                holding: false,
+            },
+            (
+                TapState::Down {
+                    since,
+                    after_tap,
+                    holding,
+                },
+                true,
+            ) => {
+                if !holding && time - since >= hold_min_duration {
+                    if after_tap {
+                        report(TapEvent::Tap);
+                    }
+                    report(TapEvent::Hold);
+                    TapState::Down {
+                        since,
+                        after_tap: false,
+                        holding: true,
+                    }
+                } else {
+                    self.state
+                }
+            }
+            (
+                TapState::Down {
+                    since,
+                    after_tap,
+                    holding,
+                },
+                false,
+            ) => {
+                if holding {
+                    report(TapEvent::Release);
+                    TapState::Idle
+                } else if time - since <= tap_max_duration {
+                    if after_tap {
+                        report(TapEvent::DoubleTap);
+                        TapState::Idle
+                    } else {
+                        TapState::WaitingForSecondTap { released_at: time }
+                    }
+                } else {
+                    if after_tap {
+                        report(TapEvent::Tap);
+                    }
+                    TapState::Idle
+                }
+            }
+            (TapState::WaitingForSecondTap { released_at }, false) => {
+                if time - released_at > double_tap_max_gap {
+                    report(TapEvent::Tap);
+                    TapState::Idle
+                } else {
+                    self.state
+                }
+            }
+            (TapState::WaitingForSecondTap { .. }, true) => TapState::Down {
+                since: time,
+                after_tap: true,
+                holding: false,
+            },
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMINGS: TapTimings = TapTimings {
+        tap_max_duration: 0.3,
+        double_tap_max_gap: 0.3,
+        hold_min_duration: 0.6,
+    };
+
+    /// The events, and the millisecond they're reported at, for a channel that's active during
+    /// `activations` (each a start and end millisecond), sampled every millisecond for 2 seconds.
+    fn events(activations: &[(u32, u32)]) -> Vec<(u32, TapEvent)> {
+        let mut recognizer = TapRecognizer::default();
+        let mut events = Vec::new();
+        for millis in 0..2000 {
+            let active = activations
+                .iter()
+                .any(|&(start, end)| (start..end).contains(&millis));
+            recognizer.update(active, millis as f64 / 1000.0, &TIMINGS, |event| {
+                events.push((millis, event))
+            });
+        }
+        events
+    }
+
+    #[test]
+    fn single_tap() {
+        // reported once the double tap gap is over
+        assert_eq!(events(&[(100, 200)]), vec![(501, TapEvent::Tap)]);
+    }
+
+    #[test]
+    fn double_tap() {
+        assert_eq!(
+            events(&[(100, 200), (400, 500)]),
+            vec![(500, TapEvent::DoubleTap)]
+        );
+    }
+
+    #[test]
+    fn tap_then_hold() {
+        assert_eq!(
+            events(&[(100, 200), (400, 1500)]),
+            vec![
+                (1000, TapEvent::Tap),
+                (1000, TapEvent::Hold),
+                (1500, TapEvent::Release)
+            ]
+        );
+    }
+
+    #[test]
+    fn hold() {
+        assert_eq!(
+            events(&[(100, 1200)]),
+            vec![(700, TapEvent::Hold), (1200, TapEvent::Release)]
+        );
+    }
+
+    #[test]
+    fn too_long_for_a_tap_and_too_short_for_a_hold() {
+        assert_eq!(events(&[(100, 550)]), vec![]);
+        // which also cancels the tap before it
+        assert_eq!(
+            events(&[(100, 200), (400, 850)]),
+            vec![(850, TapEvent::Tap)]
+        );
+    }
+}
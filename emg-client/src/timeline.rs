@@ -0,0 +1,352 @@
+use crate::remote_time_estimator::RemoteTimeEstimator;
+use crate::sample_source::ReportFromServer;
+use crate::variables::get_variable;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/*
+
+Puts the samples from several servers onto one local timeline, so that decisions that involve
+channels from more than one server see them in the order they actually happened.
+
+Each server's sample indices are converted to local times by its own `RemoteTimeEstimator`.
+A sample is only handed out once every other server has sent something at least as late,
+so that nothing earlier can still turn up. But a server that has gone quiet mustn't hold up
+the others indefinitely, so samples are also handed out once they're `MAX_ALIGNMENT_DELAY` old.
+
+With a single server, every sample is handed out as soon as it arrives.
+
+Signal processing assumes the samples from each server are contiguous, so this is also where
+missing samples are dealt with. Short gaps (up to the `max_interpolated_gap_samples` variable)
+are filled in by linear interpolation. After a longer gap, the first sample is marked so that
+its server's signals can start over. Repeated samples are dropped.
+
+*/
+
+const MAX_ALIGNMENT_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Debug)]
+pub struct TimelineSample {
+    pub server_index: usize,
+    pub sample_index: u64,
+    /// seconds since the timeline started
+    pub local_time: f64,
+    /// one value per channel
+    pub inputs: Vec<u16>,
+    /// set on the first sample after some samples went missing
+    pub gap_before: Option<SampleGap>,
+}
+
+#[derive(Copy, Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct SampleGap {
+    pub num_missing: u64,
+    pub handling: GapHandling,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub enum GapHandling {
+    /// the missing samples were made up, and come next
+    Interpolated,
+    /// too much was missing to make it up, so whatever depends on contiguous samples should start over
+    Reset,
+}
+
+// estimates can be slightly before the start, e.g. for samples in the first report
+fn seconds_since(start: Instant, instant: Instant) -> f64 {
+    match instant.checked_duration_since(start) {
+        Some(duration) => duration.as_secs_f64(),
+        None => -(start - instant).as_secs_f64(),
+    }
+}
+
+struct ServerTimeline {
+    remote_time_estimator: RemoteTimeEstimator,
+    pending: VecDeque<TimelineSample>,
+    received_through: f64,
+    last_received: Option<(u64, Vec<u16>)>,
+}
+
+impl Default for ServerTimeline {
+    fn default() -> Self {
+        ServerTimeline {
+            remote_time_estimator: RemoteTimeEstimator::default(),
+            pending: VecDeque::new(),
+            received_through: f64::NEG_INFINITY,
+            last_received: None,
+        }
+    }
+}
+
+pub struct Timeline {
+    start_time: Instant,
+    servers: Vec<ServerTimeline>,
+}
+
+impl Timeline {
+    pub fn new(start_time: Instant, num_servers: usize) -> Timeline {
+        Timeline {
+            start_time,
+            servers: (0..num_servers)
+                .map(|_| ServerTimeline::default())
+                .collect(),
+        }
+    }
+
+    /// For when we don't know how many servers there are in advance, as in a replay.
+    pub fn with_num_servers(mut self, num_servers: usize) -> Timeline {
+        self.servers
+            .resize_with(num_servers, ServerTimeline::default);
+        self
+    }
+
+    pub fn local_time(&self, instant: Instant) -> f64 {
+        seconds_since(self.start_time, instant)
+    }
+
+    /// Forget what we knew about a server's clock, e.g. because it restarted.
+    /// Any of its samples that haven't been handed out yet are dropped.
+    pub fn restart_server(&mut self, server_index: usize) {
+        self.servers[server_index] = ServerTimeline::default();
+    }
+
+    pub fn add_report(
+        &mut self,
+        server_index: usize,
+        report: &ReportFromServer,
+        local_time_received: Instant,
+    ) {
+        let Some(last_index) =
+            (report.first_sample_index + report.samples.len() as u64).checked_sub(1)
+        else {
+            return;
+        };
+        let start_time = self.start_time;
+        let server = &mut self.servers[server_index];
+        server
+            .remote_time_estimator
+            .observe(last_index as f64, local_time_received);
+        let max_interpolated_gap = get_variable("max_interpolated_gap_samples");
+        for (offset, inputs) in report.samples.iter().enumerate() {
+            let sample_index = report.first_sample_index + offset as u64;
+            let last_received = server.last_received.take();
+            let mut push = |sample_index: u64, inputs, gap_before| {
+                let estimated = server
+                    .remote_time_estimator
+                    .estimate_local_time(sample_index as f64);
+                server.pending.push_back(TimelineSample {
+                    server_index,
+                    sample_index,
+                    local_time: seconds_since(start_time, estimated),
+                    inputs,
+                    gap_before,
+                });
+            };
+            let mut gap_before = None;
+            match last_received {
+                Some((last_index, _)) if sample_index <= last_index => {
+                    server.last_received = last_received;
+                    continue;
+                }
+                Some((last_index, ref last_inputs)) if sample_index > last_index + 1 => {
+                    let num_missing = sample_index - last_index - 1;
+                    if num_missing as f64 <= max_interpolated_gap {
+                        for missing_index in last_index + 1..sample_index {
+                            let fraction =
+                                (missing_index - last_index) as f64 / (num_missing + 1) as f64;
+                            let interpolated = last_inputs
+                                .iter()
+                                .zip(inputs)
+                                .map(|(&a, &b)| {
+                                    let (a, b) = (a as f64, b as f64);
+                                    (a + (b - a) * fraction).round() as u16
+                                })
+                                .collect();
+                            let gap = (missing_index == last_index + 1).then_some(SampleGap {
+                                num_missing,
+                                handling: GapHandling::Interpolated,
+                            });
+                            push(missing_index, interpolated, gap);
+                        }
+                    } else {
+                        gap_before = Some(SampleGap {
+                            num_missing,
+                            handling: GapHandling::Reset,
+                        });
+                    }
+                }
+                _ => {}
+            }
+            push(sample_index, inputs.clone(), gap_before);
+            server.last_received = Some((sample_index, inputs.clone()));
+        }
+        server.received_through = server.received_through.max(seconds_since(
+            start_time,
+            server
+                .remote_time_estimator
+                .estimate_local_time(last_index as f64),
+        ));
+    }
+
+    fn earliest_pending(&self) -> Option<usize> {
+        self.servers
+            .iter()
+            .enumerate()
+            .filter_map(|(index, server)| Some((index, server.pending.front()?.local_time)))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(index, _)| index)
+    }
+
+    /// The earliest sample that can't be preceded by anything that hasn't arrived yet, if any.
+    pub fn pop_ready(&mut self, now: Instant) -> Option<TimelineSample> {
+        let index = self.earliest_pending()?;
+        let time = self.servers[index].pending.front()?.local_time;
+        let everyone_caught_up = self
+            .servers
+            .iter()
+            .enumerate()
+            .all(|(other, server)| other == index || server.received_through >= time);
+        let waited_long_enough = time <= self.local_time(now) - MAX_ALIGNMENT_DELAY.as_secs_f64();
+        if everyone_caught_up || waited_long_enough {
+            self.servers[index].pending.pop_front()
+        } else {
+            None
+        }
+    }
+
+    /// The earliest sample, without waiting for anything else, e.g. at the end of a recording.
+    pub fn pop_any(&mut self) -> Option<TimelineSample> {
+        let index = self.earliest_pending()?;
+        self.servers[index].pending.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(first_sample_index: u64, values: &[u16]) -> ReportFromServer {
+        ReportFromServer {
+            server_run_id: 1,
+            first_sample_index,
+            samples: values.iter().map(|&value| vec![value]).collect(),
+        }
+    }
+
+    fn drain(timeline: &mut Timeline) -> Vec<TimelineSample> {
+        std::iter::from_fn(|| timeline.pop_any()).collect()
+    }
+
+    fn indices_and_values(samples: &[TimelineSample]) -> Vec<(u64, u16)> {
+        samples
+            .iter()
+            .map(|sample| (sample.sample_index, sample.inputs[0]))
+            .collect()
+    }
+
+    #[test]
+    fn repeated_and_out_of_order_samples_are_dropped() {
+        let start = Instant::now();
+        let mut timeline = Timeline::new(start, 1);
+        timeline.add_report(0, &report(0, &[10, 11, 12, 13]), start);
+        timeline.add_report(
+            0,
+            &report(2, &[12, 13, 14, 15]),
+            start + Duration::from_millis(4),
+        );
+        timeline.add_report(0, &report(1, &[11]), start + Duration::from_millis(5));
+        let samples = drain(&mut timeline);
+        assert_eq!(
+            indices_and_values(&samples),
+            vec![(0, 10), (1, 11), (2, 12), (3, 13), (4, 14), (5, 15)]
+        );
+        assert!(samples.iter().all(|sample| sample.gap_before.is_none()));
+    }
+
+    #[test]
+    fn short_gaps_are_interpolated() {
+        let start = Instant::now();
+        let mut timeline = Timeline::new(start, 1);
+        timeline.add_report(0, &report(0, &[0, 10]), start);
+        timeline.add_report(0, &report(4, &[40, 50]), start + Duration::from_millis(6));
+        let samples = drain(&mut timeline);
+        assert_eq!(
+            indices_and_values(&samples),
+            vec![(0, 0), (1, 10), (2, 20), (3, 30), (4, 40), (5, 50)]
+        );
+        assert_eq!(
+            samples[2].gap_before,
+            Some(SampleGap {
+                num_missing: 2,
+                handling: GapHandling::Interpolated
+            })
+        );
+        assert!(samples[3..]
+            .iter()
+            .all(|sample| sample.gap_before.is_none()));
+    }
+
+    #[test]
+    fn long_gaps_reset() {
+        let start = Instant::now();
+        let mut timeline = Timeline::new(start, 1);
+        timeline.add_report(0, &report(0, &[0, 10]), start);
+        timeline.add_report(0, &report(1000, &[40]), start + Duration::from_secs(1));
+        let samples = drain(&mut timeline);
+        assert_eq!(
+            indices_and_values(&samples),
+            vec![(0, 0), (1, 10), (1000, 40)]
+        );
+        assert_eq!(
+            samples[2].gap_before,
+            Some(SampleGap {
+                num_missing: 998,
+                handling: GapHandling::Reset
+            })
+        );
+    }
+
+    #[test]
+    fn a_restarted_server_starts_counting_again() {
+        let start = Instant::now();
+        let mut timeline = Timeline::new(start, 1);
+        timeline.add_report(0, &report(0, &[0, 1, 2]), start);
+        assert_eq!(drain(&mut timeline).len(), 3);
+        // without the restart, these would look like repeats
+        timeline.restart_server(0);
+        timeline.add_report(0, &report(0, &[5, 6]), start + Duration::from_secs(1));
+        let samples = drain(&mut timeline);
+        assert_eq!(indices_and_values(&samples), vec![(0, 5), (1, 6)]);
+        assert!(samples.iter().all(|sample| sample.gap_before.is_none()));
+    }
+
+    #[test]
+    fn servers_are_merged_in_local_time_order() {
+        let start = Instant::now();
+        let mut timeline = Timeline::new(start, 2);
+        let mut merged = Vec::new();
+        // both at 1 kHz, sending 10 samples at a time, with server 1 half a report behind
+        for report_index in 0..20u64 {
+            for server_index in 0..2 {
+                let received =
+                    start + Duration::from_millis(report_index * 10 + server_index as u64 * 5 + 10);
+                let values: Vec<u16> = (0..10).map(|i| (report_index * 10 + i) as u16).collect();
+                timeline.add_report(server_index, &report(report_index * 10, &values), received);
+                merged.extend(std::iter::from_fn(|| timeline.pop_ready(received)));
+            }
+        }
+        merged.extend(drain(&mut timeline));
+        assert_eq!(merged.len(), 400);
+        assert!(merged
+            .windows(2)
+            .all(|pair| pair[0].local_time <= pair[1].local_time));
+        for server_index in 0..2 {
+            let indices: Vec<u64> = merged
+                .iter()
+                .filter(|sample| sample.server_index == server_index)
+                .map(|sample| sample.sample_index)
+                .collect();
+            assert_eq!(indices, (0..200).collect::<Vec<_>>());
+        }
+    }
+}
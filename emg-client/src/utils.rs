@@ -5,41 +5,14 @@ use rodio::source::Buffered;
 use rodio::{Decoder, Source};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
-use std::collections::HashMap;
 use std::convert::TryInto;
 use std::fs::File;
 use std::io::BufReader;
 use std::path::Path;
 use std::sync::atomic::Ordering;
-use std::sync::LazyLock;
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
 use tokio_stream::StreamExt;
 
-static VARIABLES: LazyLock<RwLock<HashMap<String, f64>>> = LazyLock::new(|| {
-    RwLock::new({
-        [
-            ("max_activity_contribution_per_frequency", 8.0),
-            ("activity_threshold", 60.0),
-            ("incremental_reduction_per_frame", 1.0 / 250.0),
-        ]
-        .into_iter()
-        .map(|(k, v)| (k.to_string(), v))
-        .collect()
-    })
-});
-
-pub fn set_variable(key: &str, value: f64) {
-    *VARIABLES.write().unwrap().get_mut(key).unwrap() = value;
-}
-
-pub fn get_variable(key: &str) -> f64 {
-    VARIABLES.read().unwrap()[key]
-}
-
-pub fn get_variables() -> HashMap<String, f64> {
-    VARIABLES.read().unwrap().clone()
-}
-
 pub struct LatestSender<T> {
     atom: Arc<AtomicOptionBox<T>>,
 }
@@ -0,0 +1,530 @@
+use crate::activity_detectors::DetectorKind;
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::LazyLock;
+use std::sync::RwLock;
+
+/*
+
+The tunable numbers that the signal processing and gesture recognition read while they run, which
+can be changed live from the GUI and are kept in profiles.
+
+Every variable is declared in `VARIABLE_INFO`, with its default, the range of values that make
+sense, and a description, which the GUI builds its controls from. All of them are stored as `f64`,
+but integers have to be whole numbers, booleans are 0 or 1, and choices are the index of one of
+their options.
+
+The variables that tune how a single signal is turned into activity can also be overridden for
+individual channels, because electrodes differ a lot in gain. Channels are numbered across all
+servers, like in `gestures.rs`, and ones without an override use the global value.
+
+Each channel's activity level is a weighted sum over the frequencies it watches. The weights come
+from calibrating (see `calibration.rs`), and are kept with the variables; a channel without any
+(or a frequency beyond the end of its list) uses 1.
+
+*/
+
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub enum VariableKind {
+    Number,
+    Integer,
+    Boolean,
+    /// picks one of these options, by its index
+    Choice(Vec<String>),
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct VariableInfo {
+    pub name: String,
+    pub kind: VariableKind,
+    /// whether it can be overridden for individual channels
+    pub per_channel: bool,
+    pub default: f64,
+    pub min: f64,
+    pub max: f64,
+    /// how finely the GUI should let it be adjusted
+    pub step: f64,
+    /// e.g. "s" for seconds; empty for plain numbers
+    pub unit: String,
+    pub description: String,
+}
+
+static VARIABLE_INFO: LazyLock<Vec<VariableInfo>> = LazyLock::new(|| {
+    use VariableKind::*;
+    let variable = |name: &str,
+                    kind,
+                    per_channel,
+                    default,
+                    (min, max, step),
+                    unit: &str,
+                    description: &str| VariableInfo {
+        name: name.to_string(),
+        kind,
+        per_channel,
+        default,
+        min,
+        max,
+        step,
+        unit: unit.to_string(),
+        description: description.to_string(),
+    };
+    vec![
+        variable(
+            "activity_detector",
+            Choice(
+                DetectorKind::ALL
+                    .iter()
+                    .map(|kind| kind.description().to_string())
+                    .collect(),
+            ),
+            true,
+            DetectorKind::Nudft.variable_value(),
+            (0.0, (DetectorKind::ALL.len() - 1) as f64, 1.0),
+            "",
+            "How a channel's activity level is measured (see activity_detectors.rs); changing it makes the channel learn its idle thresholds again",
+        ),
+        variable(
+            "max_activity_contribution_per_frequency",
+            Number,
+            true,
+            8.0,
+            (0.0, 100.0, 0.5),
+            "",
+            "The most that any one frequency can add to a channel's activity level",
+        ),
+        variable(
+            "activity_threshold",
+            Number,
+            true,
+            60.0,
+            (1.0, 1000.0, 1.0),
+            "",
+            "How high a channel's activity level has to be for it to count as active",
+        ),
+        variable(
+            "artifact_baseline_swing_ratio",
+            Number,
+            true,
+            4.0,
+            (0.0, 100.0, 0.5),
+            "",
+            "How far the slow baseline can move, in multiples of the signal's fast variation, before it counts as a motion artifact (0 to never)",
+        ),
+        variable(
+            "artifact_dc_jump_ratio",
+            Number,
+            true,
+            4.0,
+            (0.0, 100.0, 0.5),
+            "",
+            "How big a sudden jump in the baseline can be, in multiples of the signal's fast variation, before it counts as a motion artifact (0 to never)",
+        ),
+        variable(
+            "artifact_impulse_flatness",
+            Number,
+            true,
+            0.8,
+            (0.0, 1.0, 0.01),
+            "",
+            "How flat the spectrum has to be for a burst to count as an impulse artifact rather than muscle activity (1 to never)",
+        ),
+        variable(
+            "artifact_hold_time",
+            Number,
+            false,
+            0.3,
+            (0.0, 5.0, 0.05),
+            "s",
+            "How long after a motion artifact the channel still can't become active",
+        ),
+        variable(
+            "incremental_reduction_per_frame",
+            Number,
+            false,
+            1.0 / 250.0,
+            (0.0, 0.1, 0.0005),
+            "",
+            "Not currently used",
+        ),
+        variable(
+            "max_interpolated_gap_samples",
+            Integer,
+            false,
+            10.0,
+            (0.0, 1000.0, 1.0),
+            "samples",
+            "Longer runs of missing samples make the signals start over instead of being interpolated",
+        ),
+        variable(
+            "tap_max_duration",
+            Number,
+            false,
+            0.3,
+            (0.05, 2.0, 0.01),
+            "s",
+            "The longest activation that counts as a tap",
+        ),
+        variable(
+            "double_tap_max_gap",
+            Number,
+            false,
+            0.3,
+            (0.05, 2.0, 0.01),
+            "s",
+            "The longest time between two taps that makes them a double tap",
+        ),
+        variable(
+            "hold_min_duration",
+            Number,
+            false,
+            0.6,
+            (0.1, 5.0, 0.05),
+            "s",
+            "How long an activation has to last to become a hold",
+        ),
+        variable(
+            "scroll_initial_speed",
+            Number,
+            false,
+            3.4,
+            (0.0, 100.0, 0.1),
+            "steps/s",
+            "How fast scrolling starts out",
+        ),
+        variable(
+            "scroll_acceleration",
+            Number,
+            false,
+            17.3,
+            (0.0, 200.0, 0.1),
+            "steps/s²",
+            "How quickly scrolling speeds up",
+        ),
+        variable(
+            "scroll_max_speed",
+            Number,
+            false,
+            0.0,
+            (0.0, 500.0, 1.0),
+            "steps/s",
+            "Scrolling doesn't speed up beyond this (0 to keep speeding up)",
+        ),
+        variable(
+            "scroll_proportional",
+            Boolean,
+            false,
+            0.0,
+            (0.0, 1.0, 1.0),
+            "",
+            "Make the scroll speed follow how strongly the muscles are activated, instead of speeding up over time",
+        ),
+        variable(
+            "scroll_proportional_speed",
+            Number,
+            false,
+            10.0,
+            (0.0, 200.0, 0.5),
+            "steps/s",
+            "With proportional scrolling, the speed when the activity is at the threshold",
+        ),
+    ]
+});
+
+fn default_variables() -> HashMap<String, f64> {
+    VARIABLE_INFO
+        .iter()
+        .map(|info| (info.name.clone(), info.default))
+        .collect()
+}
+
+/// Overrides for individual channels, by channel number.
+pub type ChannelVariables = BTreeMap<usize, HashMap<String, f64>>;
+/// One weight per frequency, by channel number.
+pub type FrequencyWeights = BTreeMap<usize, Vec<f64>>;
+
+static VARIABLES: LazyLock<RwLock<HashMap<String, f64>>> =
+    LazyLock::new(|| RwLock::new(default_variables()));
+static CHANNEL_VARIABLES: LazyLock<RwLock<ChannelVariables>> = LazyLock::new(Default::default);
+static FREQUENCY_WEIGHTS: LazyLock<RwLock<FrequencyWeights>> = LazyLock::new(Default::default);
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+pub fn variable_info() -> &'static [VariableInfo] {
+    &VARIABLE_INFO
+}
+
+fn check_variable(key: &str, value: f64) -> anyhow::Result<&'static VariableInfo> {
+    let Some(info) = VARIABLE_INFO.iter().find(|info| info.name == key) else {
+        bail!("there's no variable called {:?}", key);
+    };
+    if !(info.min..=info.max).contains(&value) {
+        bail!(
+            "{} must be from {} to {}, not {}",
+            key,
+            info.min,
+            info.max,
+            value
+        );
+    }
+    match &info.kind {
+        VariableKind::Number => {}
+        VariableKind::Integer if value.fract() != 0.0 => {
+            bail!("{} must be a whole number, not {}", key, value)
+        }
+        VariableKind::Integer => {}
+        VariableKind::Boolean if value != 0.0 && value != 1.0 => {
+            bail!("{} must be 0 or 1, not {}", key, value)
+        }
+        VariableKind::Boolean => {}
+        VariableKind::Choice(choices)
+            if value.fract() != 0.0 || value as usize >= choices.len() =>
+        {
+            bail!(
+                "{} must be the number of one of its options, from 0 to {}, not {}",
+                key,
+                choices.len() - 1,
+                value
+            )
+        }
+        VariableKind::Choice(_) => {}
+    }
+    Ok(info)
+}
+
+fn check_channel_variable(key: &str, value: f64) -> anyhow::Result<()> {
+    if !check_variable(key, value)?.per_channel {
+        bail!("{} can't be set separately for each channel", key);
+    }
+    Ok(())
+}
+
+/// Goes up whenever a variable, channel override or frequency weight changes, so that code that would otherwise read
+/// some on every sample can keep its own copies until then.
+pub fn variables_generation() -> u64 {
+    GENERATION.load(Ordering::Acquire)
+}
+
+fn variables_changed() {
+    GENERATION.fetch_add(1, Ordering::AcqRel);
+}
+
+/// Puts every variable back to its default and removes every channel override, and then sets the
+/// given ones, skipping (and returning the errors for) any that don't exist or have invalid values.
+pub fn reset_variables(
+    values: &HashMap<String, f64>,
+    channel_values: &ChannelVariables,
+) -> Vec<anyhow::Error> {
+    let mut variables = VARIABLES.write().unwrap();
+    *variables = default_variables();
+    let mut errors = Vec::new();
+    for (key, &value) in values {
+        match check_variable(key, value) {
+            Ok(_) => *variables.get_mut(key).unwrap() = value,
+            Err(e) => errors.push(e),
+        }
+    }
+    let mut channel_variables = CHANNEL_VARIABLES.write().unwrap();
+    channel_variables.clear();
+    for (&channel, values) in channel_values {
+        for (key, &value) in values {
+            match check_channel_variable(key, value) {
+                Ok(()) => {
+                    channel_variables
+                        .entry(channel)
+                        .or_default()
+                        .insert(key.clone(), value);
+                }
+                Err(e) => errors.push(e.context(format!("channel {}", channel))),
+            }
+        }
+    }
+    variables_changed();
+    errors
+}
+
+pub fn set_variable(key: &str, value: f64) -> anyhow::Result<()> {
+    check_variable(key, value)?;
+    *VARIABLES.write().unwrap().get_mut(key).unwrap() = value;
+    variables_changed();
+    Ok(())
+}
+
+/// Overrides a variable for one channel, or with `None`, makes it use the global value again.
+pub fn set_channel_variable(channel: usize, key: &str, value: Option<f64>) -> anyhow::Result<()> {
+    let mut channel_variables = CHANNEL_VARIABLES.write().unwrap();
+    match value {
+        Some(value) => {
+            check_channel_variable(key, value)?;
+            channel_variables
+                .entry(channel)
+                .or_default()
+                .insert(key.to_string(), value);
+        }
+        None => {
+            if let Some(values) = channel_variables.get_mut(&channel) {
+                values.remove(key);
+                if values.is_empty() {
+                    channel_variables.remove(&channel);
+                }
+            }
+        }
+    }
+    variables_changed();
+    Ok(())
+}
+
+pub fn get_variable(key: &str) -> f64 {
+    VARIABLES.read().unwrap()[key]
+}
+
+pub fn get_variables() -> HashMap<String, f64> {
+    VARIABLES.read().unwrap().clone()
+}
+
+/// The channel's override for the variable if it has one, or else the global value.
+pub fn get_channel_variable(channel: usize, key: &str) -> f64 {
+    if let Some(&value) = CHANNEL_VARIABLES
+        .read()
+        .unwrap()
+        .get(&channel)
+        .and_then(|values| values.get(key))
+    {
+        return value;
+    }
+    get_variable(key)
+}
+
+pub fn get_channel_variables() -> ChannelVariables {
+    CHANNEL_VARIABLES.read().unwrap().clone()
+}
+
+fn check_frequency_weights(weights: &[f64]) -> anyhow::Result<()> {
+    if let Some(weight) = weights.iter().find(|w| !(w.is_finite() && **w >= 0.0)) {
+        bail!("frequency weights can't be negative, but one is {}", weight);
+    }
+    Ok(())
+}
+
+/// Replaces all the frequency weights, skipping (and returning the errors for) invalid ones.
+pub fn reset_frequency_weights(weights: &FrequencyWeights) -> Vec<anyhow::Error> {
+    let mut errors = Vec::new();
+    let mut frequency_weights = FREQUENCY_WEIGHTS.write().unwrap();
+    frequency_weights.clear();
+    for (&channel, weights) in weights {
+        match check_frequency_weights(weights) {
+            Ok(()) => {
+                frequency_weights.insert(channel, weights.clone());
+            }
+            Err(e) => errors.push(e.context(format!("channel {}", channel))),
+        }
+    }
+    variables_changed();
+    errors
+}
+
+/// Sets one channel's frequency weights, or with `None`, makes them all 1 again.
+pub fn set_frequency_weights(channel: usize, weights: Option<Vec<f64>>) -> anyhow::Result<()> {
+    let mut frequency_weights = FREQUENCY_WEIGHTS.write().unwrap();
+    match weights {
+        Some(weights) => {
+            check_frequency_weights(&weights)?;
+            frequency_weights.insert(channel, weights);
+        }
+        None => {
+            frequency_weights.remove(&channel);
+        }
+    }
+    variables_changed();
+    Ok(())
+}
+
+/// Calls `f` with the channel's frequency weights, if it has any, without copying them.
+pub fn with_frequency_weights<R>(channel: usize, f: impl FnOnce(Option<&[f64]>) -> R) -> R {
+    f(FREQUENCY_WEIGHTS
+        .read()
+        .unwrap()
+        .get(&channel)
+        .map(Vec::as_slice))
+}
+
+pub fn get_frequency_weights() -> FrequencyWeights {
+    FREQUENCY_WEIGHTS.read().unwrap().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the variables are shared by every test, so these only set channels no other test uses, and
+    // only try to set global values that get rejected
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        let rejected = [
+            ("activity_threshold", 0.5),
+            ("activity_threshold", 1000.5),
+            ("activity_threshold", f64::NAN),
+            ("max_interpolated_gap_samples", 2.5),
+            ("scroll_proportional", 0.5),
+            ("activity_detector", 1.5),
+            ("no_such_variable", 1.0),
+        ];
+        for (key, value) in rejected {
+            let before = get_variables();
+            assert!(set_variable(key, value).is_err(), "{} = {}", key, value);
+            assert!(
+                set_channel_variable(1000, key, Some(value)).is_err(),
+                "{} = {}",
+                key,
+                value
+            );
+            assert_eq!(get_variables().get(key), before.get(key));
+        }
+        assert!(!get_channel_variables().contains_key(&1000));
+
+        check_variable("activity_threshold", 1.0).unwrap();
+        check_variable("activity_threshold", 1000.0).unwrap();
+        check_variable("max_interpolated_gap_samples", 3.0).unwrap();
+        check_variable("scroll_proportional", 1.0).unwrap();
+        check_variable("activity_detector", 3.0).unwrap();
+    }
+
+    #[test]
+    fn only_per_channel_variables_can_be_overridden() {
+        assert!(set_channel_variable(1001, "tap_max_duration", Some(0.3)).is_err());
+        assert!(!get_channel_variables().contains_key(&1001));
+        check_channel_variable("activity_threshold", 80.0).unwrap();
+    }
+
+    #[test]
+    fn channel_overrides_shadow_the_global_value() {
+        let global = get_variable("activity_threshold");
+        let value = if global == 80.0 { 90.0 } else { 80.0 };
+        set_channel_variable(1002, "activity_threshold", Some(value)).unwrap();
+        assert_eq!(get_channel_variable(1002, "activity_threshold"), value);
+        assert_eq!(get_channel_variable(1003, "activity_threshold"), global);
+        assert_eq!(get_variable("activity_threshold"), global);
+        assert_eq!(
+            get_channel_variables()[&1002],
+            HashMap::from([("activity_threshold".to_string(), value)])
+        );
+
+        set_channel_variable(1002, "activity_threshold", None).unwrap();
+        assert_eq!(get_channel_variable(1002, "activity_threshold"), global);
+        assert!(!get_channel_variables().contains_key(&1002));
+    }
+
+    #[test]
+    fn frequency_weights_count_as_changes() {
+        let generation = variables_generation();
+        set_frequency_weights(1004, Some(vec![1.0, 2.0])).unwrap();
+        assert!(variables_generation() > generation);
+        with_frequency_weights(1004, |weights| assert_eq!(weights, Some(&[1.0, 2.0][..])));
+
+        let generation = variables_generation();
+        set_frequency_weights(1004, None).unwrap();
+        assert!(variables_generation() > generation);
+        with_frequency_weights(1004, |weights| assert_eq!(weights, None));
+    }
+}
@@ -1,3 +1,9 @@
+use crate::calibration::CalibrationState;
+use crate::crosstalk::CrossTalk;
+use crate::frame_parser::LinkStatistics;
+use crate::gestures::GestureMapping;
+use crate::timeline::SampleGap;
+use crate::variables::{ChannelVariables, VariableInfo};
 use actix::Message;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, VecDeque};
@@ -7,6 +13,19 @@ use std::collections::{HashMap, VecDeque};
 pub enum MessageFromFrontend {
     SetEnabled(bool),
     SetVariable(String, f64),
+    /// override a variable for one channel, or with `None`, stop overriding it
+    SetChannelVariable(usize, String, Option<f64>),
+    StartRecording,
+    StopRecording,
+    SetGestureMapping(GestureMapping),
+    SwitchProfile(String),
+    SaveProfile,
+    SaveProfileAs(String),
+    RevertProfile,
+    StartCalibration,
+    /// stop calibrating, or throw away the results
+    CancelCalibration,
+    ApplyCalibration,
 }
 
 #[derive(Clone, PartialEq, Serialize, Deserialize, Debug, Message)]
@@ -15,25 +34,72 @@ pub enum MessageToFrontend {
     Initialize {
         enabled: bool,
         variables: HashMap<String, f64>,
+        channel_variables: ChannelVariables,
+        variable_info: Vec<VariableInfo>,
     },
     NewHistoryFrames {
         server_index: usize,
-        frames: [Vec<HistoryFrame>; 4],
+        // one list per channel
+        frames: Vec<Vec<HistoryFrame>>,
     },
     NewFrequenciesFrames {
         server_index: usize,
-        frames: [Vec<FrequenciesFrame>; 4],
+        frames: Vec<Vec<FrequenciesFrame>>,
     },
     UpdateFollower {
         name: String,
         latest_move_time: f64,
     },
+    UpdateRecording {
+        path: Option<String>,
+    },
+    UpdateLinkStatistics {
+        server_index: usize,
+        statistics: LinkStatistics,
+    },
+    SamplesDropped {
+        server_index: usize,
+        gap: SampleGap,
+    },
+    UpdateMainsFrequency {
+        server_index: usize,
+        channel: usize,
+        // in Hz
+        frequency: f64,
+        // false if it was set on the command line, or we haven't detected it yet
+        detected: bool,
+    },
+    UpdateVariables {
+        variables: HashMap<String, f64>,
+        channel_variables: ChannelVariables,
+    },
+    UpdateProfile {
+        name: String,
+        unsaved_changes: bool,
+        // every profile that exists, to choose from
+        profiles: Vec<String>,
+    },
+    UpdateGestureMapping {
+        mapping: GestureMapping,
+        // why the mapping that the frontend sent wasn't used instead
+        error: Option<String>,
+    },
+    UpdateCalibration(CalibrationState),
+    UpdateCrossTalk {
+        crosstalk: CrossTalk,
+    },
+    /// something the user asked for couldn't be done
+    Error {
+        message: String,
+    },
 }
 
 #[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
 pub struct HistoryFrame {
     pub time: f64,
     pub value: f64,
+    /// how much was subtracted from `value` as cross-talk from other channels
+    pub crosstalk: f64,
     pub activity_threshold: f64,
     pub too_much_threshold: f64,
 }
@@ -0,0 +1,356 @@
+use crate::activity_detectors::DetectorKind;
+use crate::profiles::{Profile, PROFILES_DIRECTORY};
+use crate::recording::RecordingReader;
+use crate::signal::{ActivityThresholdStats, MainsFrequency, Signal};
+use crate::variables::get_channel_variable;
+use anyhow::{bail, Context};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/*
+
+Learns how much each frequency should count towards a channel's activity level, from examples of
+the channel at rest and contracted: either the steps of a calibration, or whole recordings, with
+`emg_client learn-weights`.
+
+The examples are "records": the channel's DFT norm at every frequency, taken every
+`RECORD_INTERVAL`. The rest examples give the idle threshold of each frequency, the same way
+`Signal` learns them, and with those, each record becomes a vector of per-frequency activity levels.
+
+The weights are fitted by logistic regression, with each class counting equally however many
+examples it has, a little L2 regularization, and the weights kept non-negative, so a frequency that
+mostly carries motion artifacts or mains harmonics is ignored rather than subtracted. The
+classifier says "active" where the weighted sum of the levels is above minus its bias, so the
+weights are scaled to put that boundary at the channel's `activity_threshold`.
+
+If the examples can't be told apart, there are no learned weights, and the channel goes on
+counting every frequency equally.
+
+*/
+
+pub const RECORD_INTERVAL: f64 = 0.01;
+// about the size of the chunks that `Signal` takes maxima over
+const RECORDS_PER_CHUNK: usize = 10;
+// the signal processing needs a moment after starting
+const RECORDING_SETTLING_TIME: f64 = 1.0;
+const ITERATIONS: usize = 1000;
+const L2_REGULARIZATION: f64 = 0.001;
+
+/// The idle threshold of each frequency, or `None` if there aren't enough records.
+pub fn rest_threshold_stats(rest: &[Vec<f64>]) -> Option<Vec<ActivityThresholdStats>> {
+    let num_frequencies = rest.first()?.len();
+    (0..num_frequencies)
+        .map(|frequency| {
+            let maxima: Vec<f64> = rest
+                .chunks(RECORDS_PER_CHUNK)
+                .map(|chunk| {
+                    chunk
+                        .iter()
+                        .map(|record| record[frequency])
+                        .fold(0.0, f64::max)
+                })
+                .collect();
+            ActivityThresholdStats::from_chunk_maxima(&maxima)
+        })
+        .collect()
+}
+
+/// Turns a record of DFT norms into per-frequency activity levels.
+pub fn record_levels(
+    record: &[f64],
+    threshold_stats: &[ActivityThresholdStats],
+    max_contribution: f64,
+) -> Vec<f64> {
+    record
+        .iter()
+        .zip(threshold_stats)
+        .map(|(&norm, stats)| stats.activity_level(norm, max_contribution))
+        .collect()
+}
+
+pub fn frequency_levels(
+    records: &[Vec<f64>],
+    threshold_stats: &[ActivityThresholdStats],
+    max_contribution: f64,
+) -> Vec<Vec<f64>> {
+    records
+        .iter()
+        .map(|record| record_levels(record, threshold_stats, max_contribution))
+        .collect()
+}
+
+pub fn weighted_sum(levels: &[f64], weights: &[f64]) -> f64 {
+    levels.iter().zip(weights).map(|(l, w)| l * w).sum()
+}
+
+fn sigmoid(x: f64) -> f64 {
+    1.0 / (1.0 + (-x).exp())
+}
+
+/// Fits weights for records of per-frequency activity levels, so that the weighted sum is above
+/// `activity_threshold` for contractions, and below it at rest. `None` if that can't be done.
+pub fn learn_frequency_weights(
+    rest: &[Vec<f64>],
+    contraction: &[Vec<f64>],
+    activity_threshold: f64,
+) -> Option<Vec<f64>> {
+    let num_frequencies = rest.first()?.len();
+    if contraction.is_empty() {
+        return None;
+    }
+    let examples: Vec<(&[f64], f64, f64)> = rest
+        .iter()
+        .map(|levels| (levels.as_slice(), 0.0, 0.5 / rest.len() as f64))
+        .chain(
+            contraction
+                .iter()
+                .map(|levels| (levels.as_slice(), 1.0, 0.5 / contraction.len() as f64)),
+        )
+        .collect();
+    // the gradient can't change faster than this, so steps of 1/this always make progress
+    let lipschitz_constant = 0.25
+        * examples
+            .iter()
+            .map(|&(levels, _, importance)| {
+                importance * (1.0 + levels.iter().map(|l| l * l).sum::<f64>())
+            })
+            .sum::<f64>()
+        + L2_REGULARIZATION;
+    let step_size = 1.0 / lipschitz_constant;
+
+    let mut weights = vec![0.0; num_frequencies];
+    let mut bias = 0.0;
+    for _ in 0..ITERATIONS {
+        let mut weights_gradient: Vec<f64> =
+            weights.iter().map(|w| w * L2_REGULARIZATION).collect();
+        let mut bias_gradient = 0.0;
+        for &(levels, label, importance) in &examples {
+            let error = importance * (sigmoid(weighted_sum(levels, &weights) + bias) - label);
+            for (gradient, level) in weights_gradient.iter_mut().zip(levels) {
+                *gradient += error * level;
+            }
+            bias_gradient += error;
+        }
+        for (weight, gradient) in weights.iter_mut().zip(&weights_gradient) {
+            *weight = (*weight - step_size * gradient).max(0.0);
+        }
+        bias -= step_size * bias_gradient;
+    }
+
+    let boundary = -bias;
+    if !(boundary.is_finite() && boundary > 0.0) || weights.iter().all(|&w| w == 0.0) {
+        return None;
+    }
+    Some(
+        weights
+            .iter()
+            .map(|w| w * activity_threshold / boundary)
+            .collect(),
+    )
+}
+
+/// Runs a recording through the signal processing, and returns records of each channel's DFT norms,
+/// by channel number (across all servers, like in `gestures.rs`).
+pub fn recorded_nudft_norms(
+    path: impl AsRef<Path>,
+    mains_frequency: MainsFrequency,
+) -> anyhow::Result<Vec<Vec<Vec<f64>>>> {
+    struct Server {
+        server_run_id: u64,
+        sample_rate: f64,
+        signals: Vec<Signal>,
+        start_time: Option<f64>,
+        last_recorded: Vec<f64>,
+    }
+    let mut reader = RecordingReader::open(path)?;
+    let mut servers: Vec<Server> = Vec::new();
+    let mut records: Vec<Vec<Vec<f64>>> = Vec::new();
+    while let Some(recorded) = reader.next_report()? {
+        let server_index = recorded.server_index as usize;
+        let report = recorded.to_report();
+        while servers.len() <= server_index {
+            servers.push(Server {
+                server_run_id: 0,
+                sample_rate: 0.0,
+                signals: Vec::new(),
+                start_time: None,
+                last_recorded: Vec::new(),
+            });
+        }
+        let first_channel: usize = servers[..server_index]
+            .iter()
+            .map(|server| server.signals.len())
+            .sum();
+        let server = &mut servers[server_index];
+        if server.server_run_id != report.server_run_id
+            || server.signals.len() != report.num_channels()
+            || server.sample_rate != recorded.sample_rate
+        {
+            server.server_run_id = report.server_run_id;
+            server.sample_rate = recorded.sample_rate;
+            server.signals = (0..report.num_channels())
+                .map(|_| Signal::new(recorded.sample_rate, mains_frequency))
+                .collect();
+            server.start_time = None;
+            server.last_recorded = vec![f64::MIN; report.num_channels()];
+        }
+        for (offset, inputs) in report.samples.iter().enumerate() {
+            let time = (report.first_sample_index + offset as u64) as f64 / server.sample_rate;
+            let start_time = *server.start_time.get_or_insert(time);
+            for (index, (signal, &input)) in server.signals.iter_mut().zip(inputs).enumerate() {
+                let channel = first_channel + index;
+                signal.receive_raw(input as f64, time, channel, |_| {});
+                let last_recorded = &mut server.last_recorded[index];
+                if time < start_time + RECORDING_SETTLING_TIME
+                    || time < *last_recorded + RECORD_INTERVAL
+                {
+                    continue;
+                }
+                *last_recorded = time;
+                if records.len() <= channel {
+                    records.resize_with(channel + 1, Vec::new);
+                }
+                records[channel].push(signal.nudft_norms().collect());
+            }
+        }
+    }
+    Ok(records)
+}
+
+/// Learns the weights of the channels in `contractions` (each a channel's number, and a recording
+/// of its muscle contracted throughout) against a recording of every muscle at rest, and saves them
+/// in the profile, leaving everything else in it (saved or not) as it was.
+pub fn learn_weights(
+    rest_path: impl AsRef<Path>,
+    contractions: &[(usize, PathBuf)],
+    profile_name: &str,
+    mains_frequency: MainsFrequency,
+) -> anyhow::Result<()> {
+    let rest_path = rest_path.as_ref();
+    let profile = Profile::load_saved(PROFILES_DIRECTORY, profile_name)?.unwrap_or_default();
+    // the thresholds that the weights are scaled to are the saved profile's
+    for e in crate::variables::reset_variables(&profile.variables, &profile.channel_variables) {
+        println!("# ignoring variable in profile: {:#}", e);
+    }
+
+    for (channel, _) in contractions {
+        let detector =
+            DetectorKind::from_variable(get_channel_variable(*channel, "activity_detector"));
+        if detector != DetectorKind::Nudft {
+            bail!(
+                "channel {} uses the {} activity detector, which doesn't weight frequencies",
+                channel,
+                detector
+            );
+        }
+    }
+
+    let rest = recorded_nudft_norms(rest_path, mains_frequency)?;
+    let mut contraction_records: BTreeMap<usize, Vec<Vec<f64>>> = BTreeMap::new();
+    for (channel, path) in contractions {
+        let mut records = recorded_nudft_norms(path, mains_frequency)?;
+        if records.len() <= *channel {
+            bail!("{} doesn't have a channel {}", path.display(), channel);
+        }
+        contraction_records
+            .entry(*channel)
+            .or_default()
+            .append(&mut records[*channel]);
+    }
+
+    let mut learned_weights = BTreeMap::new();
+    for (channel, contraction) in contraction_records {
+        let rest = rest.get(channel).with_context(|| {
+            format!("{} doesn't have a channel {}", rest_path.display(), channel)
+        })?;
+        let threshold_stats = rest_threshold_stats(rest)
+            .with_context(|| format!("{} is too short", rest_path.display()))?;
+        let max_contribution =
+            get_channel_variable(channel, "max_activity_contribution_per_frequency");
+        let rest_levels = frequency_levels(rest, &threshold_stats, max_contribution);
+        let contraction_levels = frequency_levels(&contraction, &threshold_stats, max_contribution);
+        let weights = learn_frequency_weights(
+            &rest_levels,
+            &contraction_levels,
+            get_channel_variable(channel, "activity_threshold"),
+        )
+        .with_context(|| {
+            format!(
+                "couldn't tell channel {}'s contraction apart from rest",
+                channel
+            )
+        })?;
+        let recognized = |levels: &[Vec<f64>]| {
+            let threshold = get_channel_variable(channel, "activity_threshold");
+            levels
+                .iter()
+                .filter(|levels| weighted_sum(levels, &weights) > threshold)
+                .count() as f64
+                / levels.len() as f64
+        };
+        println!(
+            "channel {}: active for {:.0}% of the contraction and {:.0}% of the rest",
+            channel,
+            recognized(&contraction_levels) * 100.0,
+            recognized(&rest_levels) * 100.0
+        );
+        learned_weights.insert(channel, weights);
+    }
+    Profile::update(PROFILES_DIRECTORY, profile_name, |profile| {
+        profile.frequency_weights.extend(learned_weights.clone());
+    })?;
+    println!("saved the weights in profile {:?}", profile_name);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    /// Records of 6 frequencies' activity levels, where only frequency 2 is any higher during
+    /// contractions.
+    fn records(contracted: bool) -> Vec<Vec<f64>> {
+        let mut rng = StdRng::seed_from_u64(if contracted { 1 } else { 2 });
+        let mut noise = || rng.gen_range(0.0..1.0);
+        (0..300)
+            .map(|_| {
+                (0..6)
+                    .map(|frequency| match (frequency, contracted) {
+                        (2, true) => 5.0 + noise() * 5.0,
+                        _ => noise() * 3.0,
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn the_separating_frequency_gets_the_most_weight() {
+        let (rest, contraction) = (records(false), records(true));
+        let weights = learn_frequency_weights(&rest, &contraction, 60.0).unwrap();
+        assert_eq!(weights.len(), 6);
+        let heaviest = (0..6)
+            .max_by(|&a, &b| weights[a].total_cmp(&weights[b]))
+            .unwrap();
+        assert_eq!(heaviest, 2, "{:?}", weights);
+        assert!(weights.iter().all(|&w| w >= 0.0));
+
+        // and the threshold separates the two
+        let above = |records: &[Vec<f64>]| {
+            records
+                .iter()
+                .filter(|levels| weighted_sum(levels, &weights) > 60.0)
+                .count()
+        };
+        assert!(above(&contraction) > 290, "{}", above(&contraction));
+        assert!(above(&rest) < 10, "{}", above(&rest));
+    }
+
+    #[test]
+    fn nothing_is_learned_without_both_kinds_of_examples() {
+        assert_eq!(learn_frequency_weights(&records(false), &[], 60.0), None);
+        assert_eq!(learn_frequency_weights(&[], &records(true), 60.0), None);
+    }
+}
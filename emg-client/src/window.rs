@@ -0,0 +1,58 @@
+use std::collections::VecDeque;
+use std::ops::{AddAssign, Div, SubAssign};
+
+/// The last `size` values pushed, along with their sum, kept up to date as values come and go.
+pub struct Window<T> {
+    size: usize,
+    num_values_seen: u64,
+    values: VecDeque<T>,
+    // invariant: equal to the sum of `values`, except maybe floating-point rounding error.
+    cached_sum: T,
+    // keeping a running sum of floating-point numbers by repeatedly adding and subtracting, may drift over time.
+    // therefore, occasionally remake the sum from scratch.
+    next_sum: T,
+}
+
+impl<T: Default + Copy + AddAssign + SubAssign> Window<T> {
+    pub fn new(size: usize) -> Window<T> {
+        Window {
+            size,
+            num_values_seen: 0,
+            values: VecDeque::with_capacity(size),
+            cached_sum: T::default(),
+            next_sum: T::default(),
+        }
+    }
+    pub fn push(&mut self, value: T) -> Option<T> {
+        self.num_values_seen += 1;
+        let mut result = None;
+        if self.values.len() >= self.size {
+            let dropped = self.values.pop_front().unwrap();
+            self.cached_sum -= dropped;
+            result = Some(dropped);
+        }
+        self.values.push_back(value);
+        self.cached_sum += value;
+        self.next_sum += value;
+        if self.num_values_seen % self.size as u64 == 0 {
+            self.cached_sum = self.next_sum;
+            self.next_sum = T::default();
+        }
+        result
+    }
+    // pub fn sum(&self) -> T {
+    //     self.cached_sum
+    // }
+    pub fn values(&self) -> impl Iterator<Item = &T> + '_ {
+        self.values.iter()
+    }
+    pub fn is_full(&self) -> bool {
+        self.values.len() == self.size
+    }
+}
+
+impl<T: Copy + Div<f64>> Window<T> {
+    pub fn mean(&self) -> T::Output {
+        self.cached_sum / self.values.len() as f64
+    }
+}
@@ -4,6 +4,22 @@ const context = canvas.getContext ("2d")
 const followers_element = document.getElementById ("followers");
 const variables_element = document.getElementById ("variables");
 const enabled_checkbox = document.getElementById ("enabled_checkbox");
+const recording_button = document.getElementById ("recording_button");
+const recording_status = document.getElementById ("recording_status");
+const links_element = document.getElementById ("links");
+const profile_select = document.getElementById ("profile_select");
+const profile_status = document.getElementById ("profile_status");
+const gestures_editor = document.getElementById ("gestures_editor");
+const gestures_button = document.getElementById ("gestures_button");
+const gestures_status = document.getElementById ("gestures_status");
+const errors_element = document.getElementById ("errors");
+const calibration_button = document.getElementById ("calibration_button");
+const calibration_apply_button = document.getElementById ("calibration_apply_button");
+const calibration_status = document.getElementById ("calibration_status");
+const crosstalk_element = document.getElementById ("crosstalk");
+const channel_variables_element = document.getElementById ("channel_variables");
+const channel_variables_channel = document.getElementById ("channel_variables_channel");
+const MAX_SHOWN_ERRORS = 5;
 
 let socket = null
 
@@ -17,10 +33,51 @@ enabled_checkbox.addEventListener("click", e => {
   send("SetEnabled", enabled_checkbox.checked);
 });
 
+let recording = false;
+recording_button.addEventListener("click", e => {
+  send(recording ? "StopRecording" : "StartRecording", null);
+});
+
+profile_select.addEventListener("change", e => {
+  send("SwitchProfile", profile_select.value);
+});
+document.getElementById ("profile_save_button").addEventListener("click", e => {
+  send("SaveProfile", null);
+});
+document.getElementById ("profile_save_as_button").addEventListener("click", e => {
+  const name = prompt("Save the current variables as a profile named:");
+  if (name) {
+    send("SaveProfileAs", name);
+  }
+});
+document.getElementById ("profile_revert_button").addEventListener("click", e => {
+  send("RevertProfile", null);
+});
+
+let calibrating = false;
+calibration_button.addEventListener("click", e => {
+  send(calibrating ? "CancelCalibration" : "StartCalibration", null);
+});
+calibration_apply_button.addEventListener("click", e => {
+  send("ApplyCalibration", null);
+});
+
+gestures_button.addEventListener("click", e => {
+  let mapping;
+  try {
+    mapping = JSON.parse(gestures_editor.value);
+  } catch (error) {
+    gestures_status.textContent = error.message;
+    return;
+  }
+  gestures_status.textContent = "applying...";
+  send("SetGestureMapping", mapping);
+});
+
 const message_handlers = {}
 let recent_frames = [];
 
-message_handlers.Initialize = ({ enabled, variables }) => {
+message_handlers.Initialize = ({ enabled, variables, channel_variables, variable_info }) => {
     enabled_checkbox.checked = enabled;
     context.clearRect(0, 0, canvas.width, canvas.height);
     recent_frames = [];
@@ -28,62 +85,203 @@ message_handlers.Initialize = ({ enabled, variables }) => {
     latest_drawn_frame_time = 0;
     followers_element.innerHTML = "";
     variables_element.innerHTML = "";
+    errors_element.innerHTML = "";
+    channel_variables_element.innerHTML = "";
+    for (const info of variable_info) {
+      add_variable_editor(info);
+      if (info.per_channel) {
+        add_channel_variable_editor(info);
+      }
+    }
+    update_variables(variables, channel_variables);
+//    for (const server of recent_frames) {
+//      for (const signal of server) {
+//        for (const frame_kind of Object.values(signal)) {
+//          frame_kind.length = 0;
+//        }
+//      }
+//    }
+}
 
+message_handlers.UpdateVariables = ({ variables, channel_variables }) => {
+    update_variables(variables, channel_variables);
+}
 
-  for (const [name, value] of Object.entries(variables)) {
-    const id = `variables_editor_${name}`;
-    if (!document.getElementById(id)) {
-      const new_container = document.createElement("div");
-      new_container.id = id;
-      new_container.className = "variables_editor_entry";
-      const number_input = document.createElement("input");
-      const range_input = document.createElement("input");
-
-      const update_range_input = val => {
-        range_input.value = val;
-        if (val > 0) {
-          range_input.setAttribute("min", val * 0.01);
-          range_input.setAttribute("max", val * 5);
-        } else {
-          range_input.setAttribute("min", val * 5);
-          range_input.setAttribute("max", -val * 5);
+message_handlers.Error = ({ message }) => {
+  const entry = document.createElement("div");
+  entry.textContent = message;
+  errors_element.appendChild(entry);
+  while (errors_element.childElementCount > MAX_SHOWN_ERRORS) {
+    errors_element.removeChild(errors_element.firstChild);
+  }
+}
+
+function set_variable(name, value) {
+  send("SetVariable", [name, value]);
+  latest_variables[name] = value;
+  show_channel_variables();
+}
+
+// each editor has a `set` function, for when the supervisor changes the variable
+const variable_editors = {};
+
+// for variables that pick one of several options
+function new_choices_select(choices) {
+  const select = document.createElement("select");
+  choices.forEach((choice, index) => {
+    const option = document.createElement("option");
+    option.value = index;
+    option.textContent = choice;
+    select.appendChild(option);
+  });
+  return select;
+}
+
+function add_variable_editor({ name, kind, min, max, step, unit, description }) {
+  const container = document.createElement("div");
+  container.className = "variables_editor_entry";
+  container.title = description;
+  const id = `variables_editor_${name}`;
+  const label = document.createElement("label");
+  label.textContent = unit ? `${name} (${unit})` : name;
+
+  if (kind === "Boolean") {
+    const checkbox = document.createElement("input");
+    checkbox.id = id;
+    checkbox.setAttribute("type", "checkbox");
+    checkbox.addEventListener("input", (event) => {
+      set_variable(name, checkbox.checked ? 1 : 0);
+    });
+    label.setAttribute("for", checkbox.id);
+    container.appendChild(checkbox);
+    variable_editors[name] = { set: value => { checkbox.checked = value !== 0; } };
+  } else if (kind.Choice) {
+    const select = new_choices_select(kind.Choice);
+    select.id = id;
+    select.addEventListener("input", (event) => {
+      set_variable(name, Number(select.value));
+    });
+    label.setAttribute("for", select.id);
+    container.appendChild(select);
+    variable_editors[name] = { set: value => { select.value = value; } };
+  } else {
+    const number_input = document.createElement("input");
+    const range_input = document.createElement("input");
+    for (const input of [number_input, range_input]) {
+      input.setAttribute("min", min);
+      input.setAttribute("max", max);
+      input.setAttribute("step", step);
+    }
+
+    number_input.id = id;
+    number_input.setAttribute("type", "number");
+    number_input.addEventListener("input", (event) => {
+      // don't send half-typed values, e.g. an empty box
+      if (number_input.checkValidity() && !isNaN(number_input.valueAsNumber)) {
+        set_variable(name, number_input.valueAsNumber);
+        range_input.value = number_input.valueAsNumber;
+      }
+    });
+
+    range_input.setAttribute("type", "range");
+    range_input.addEventListener("input", (event) => {
+      set_variable(name, range_input.valueAsNumber);
+      number_input.value = range_input.valueAsNumber;
+    });
+
+    label.setAttribute("for", number_input.id);
+    container.appendChild(range_input);
+    container.appendChild(number_input);
+    variable_editors[name] = {
+      set: value => {
+        if (number_input.valueAsNumber !== value) {
+          number_input.value = value;
         }
-        range_input.setAttribute("step", val * 0.01);
+        range_input.value = value;
       }
+    };
+  }
 
-      number_input.id = `${id}_number`;
-      number_input.setAttribute("type", "number");
-      number_input.value = value;
-      number_input.addEventListener("input", (event) => {
-        send("SetVariable", [name, number_input.valueAsNumber]);
-        update_range_input(number_input.valueAsNumber);
-      });
+  container.appendChild(label);
+  variables_element.appendChild(container);
+}
 
-      range_input.id = `${id}_range`;
-      range_input.setAttribute("type", "range");
-      update_range_input(value);
-      range_input.addEventListener("input", (event) => {
-        send("SetVariable", [name, range_input.valueAsNumber]);
-        number_input.value = range_input.valueAsNumber;
-      });
+// the overrides for each channel, which are shown for one channel at a time
+let latest_variables = {};
+let latest_channel_variables = {};
+const channel_variable_editors = {};
+
+function add_channel_variable_editor({ name, kind, min, max, step, unit, description }) {
+  const container = document.createElement("div");
+  container.title = description;
+  const checkbox = document.createElement("input");
+  checkbox.setAttribute("type", "checkbox");
+  let value_input;
+  let input_value;
+  if (kind.Choice) {
+    value_input = new_choices_select(kind.Choice);
+    input_value = () => Number(value_input.value);
+  } else {
+    value_input = document.createElement("input");
+    value_input.setAttribute("type", "number");
+    value_input.setAttribute("min", min);
+    value_input.setAttribute("max", max);
+    value_input.setAttribute("step", step);
+    input_value = () => value_input.valueAsNumber;
+  }
+  const label = document.createElement("label");
+  label.textContent = unit ? `override ${name} (${unit})` : `override ${name}`;
+
+  const send_override = () => {
+    const channel = channel_variables_channel.valueAsNumber;
+    if (!checkbox.checked) {
+      send("SetChannelVariable", [channel, name, null]);
+    } else if (value_input.checkValidity() && !isNaN(input_value())) {
+      send("SetChannelVariable", [channel, name, input_value()]);
+    }
+  };
+  checkbox.addEventListener("input", (event) => {
+    value_input.disabled = !checkbox.checked;
+    send_override();
+  });
+  value_input.addEventListener("input", send_override);
+
+  container.appendChild(checkbox);
+  container.appendChild(value_input);
+  container.appendChild(label);
+  channel_variables_element.appendChild(container);
+  channel_variable_editors[name] = {
+    show: overrides => {
+      const overridden = name in overrides;
+      checkbox.checked = overridden;
+      value_input.disabled = !overridden;
+      // without an override, start from the global value
+      const value = overridden ? overrides[name] : latest_variables[name];
+      if (input_value() !== value) {
+        value_input.value = value;
+      }
+    }
+  };
+}
 
-      const label = document.createElement("label");
-      label.setAttribute("for", number_input.id);
-      label.textContent = name;
+function show_channel_variables() {
+  const overrides = latest_channel_variables[channel_variables_channel.value] || {};
+  for (const editor of Object.values(channel_variable_editors)) {
+    editor.show(overrides);
+  }
+}
+channel_variables_channel.addEventListener("input", show_channel_variables);
 
-      new_container.appendChild(range_input);
-      new_container.appendChild(number_input);
-      new_container.appendChild(label);
-      variables_element.appendChild(new_container);
+function update_variables(variables, channel_variables) {
+  latest_variables = variables;
+  latest_channel_variables = channel_variables;
+  for (const [name, value] of Object.entries(variables)) {
+    const editor = variable_editors[name];
+    if (editor) {
+      editor.set(value);
     }
   }
-//    for (const server of recent_frames) {
-//      for (const signal of server) {
-//        for (const frame_kind of Object.values(signal)) {
-//          frame_kind.length = 0;
-//        }
-//      }
-//    }
+  show_channel_variables();
 }
 
 function new_signal_frames() {
@@ -96,12 +294,8 @@ function new_server_frames() {
   return {
     latest_received_frame_time: 0,
     latest_drawn_frame_time: 0,
-    signals: [
-      new_signal_frames(),
-      new_signal_frames(),
-      new_signal_frames(),
-      new_signal_frames(),
-    ]
+    // one per channel; servers can have any number of channels, which we find out from their frames
+    signals: []
   }
 }
 function server_frames (server_index) {
@@ -112,6 +306,17 @@ function server_frames (server_index) {
 }
 function add_fresh_frames(kind, server_index, frames) {
   const server = server_frames(server_index);
+  if (server.signals.length != frames.length) {
+    server.signals.length = Math.min(server.signals.length, frames.length);
+    while (server.signals.length < frames.length) {
+      server.signals.push(new_signal_frames());
+    }
+    // everything moved over, so start the drawing over
+    context.clearRect(0, 0, canvas.width, canvas.height);
+    for (const other of recent_frames) {
+      other.latest_drawn_frame_time = 0;
+    }
+  }
   server.signals.forEach(function(signal, signal_index) {
     for (const frame of frames[signal_index]) {
       signal[kind].push(frame);
@@ -142,6 +347,134 @@ message_handlers.UpdateFollower = ({ name, latest_move_time }) => {
     }
 }
 
+message_handlers.UpdateRecording = ({ path }) => {
+    recording = path !== null;
+    recording_button.textContent = recording ? "Stop recording" : "Start recording";
+    recording_status.textContent = recording ? "Recording to " + path : "";
+}
+
+message_handlers.UpdateLinkStatistics = ({ server_index, statistics }) => {
+    const id = "link_" + server_index;
+    let element = document.getElementById (id);
+    if (!element) {
+      element = document.createElement("div");
+      element.id = id;
+      links_element.appendChild(element);
+    }
+    const { samples, dropped_samples, corrupted_frames, corrupted_samples } = statistics;
+    element.innerText = `Server ${server_index}: ${samples} samples, ${dropped_samples} dropped, ${corrupted_frames} corrupted frames (${corrupted_samples} samples)`;
+}
+
+message_handlers.SamplesDropped = ({ server_index, gap }) => {
+    const id = "gap_" + server_index;
+    let element = document.getElementById (id);
+    if (!element) {
+      element = document.createElement("div");
+      element.id = id;
+      links_element.appendChild(element);
+    }
+    const { num_missing, handling } = gap;
+    const what = handling === "Interpolated" ? "interpolated" : "signals reset";
+    element.innerText = `Server ${server_index}: last gap was ${num_missing} samples at ${new Date().toLocaleTimeString()} (${what})`;
+}
+
+message_handlers.UpdateProfile = ({ name, unsaved_changes, profiles }) => {
+    profile_select.innerHTML = "";
+    // a profile that hasn't been saved yet isn't in the list
+    for (const profile of profiles.includes(name) ? profiles : [...profiles, name].sort()) {
+      const option = document.createElement("option");
+      option.value = profile;
+      option.textContent = profile;
+      profile_select.appendChild(option);
+    }
+    profile_select.value = name;
+    profile_status.textContent = unsaved_changes ? "(unsaved changes)" : "";
+}
+
+message_handlers.UpdateGestureMapping = ({ mapping, error }) => {
+    if (error !== null) {
+      // leave the rejected text in the editor so that it can be fixed
+      gestures_status.textContent = "Rejected: " + error;
+    } else {
+      gestures_status.textContent = "";
+      gestures_editor.value = JSON.stringify(mapping, null, 2);
+    }
+}
+
+// a table of what fraction of each source channel's activity each channel picks up
+function crosstalk_table (crosstalk) {
+    const sources = [...new Set(Object.values(crosstalk).flatMap(Object.keys))].sort((a, b) => a - b);
+    if (sources.length === 0) {
+      return "No cross-talk between channels";
+    }
+    let html = "<table><tr><th>Picks up from</th>";
+    for (const source of sources) {
+      html += `<th>${source}</th>`;
+    }
+    html += "</tr>";
+    for (const [channel, fractions] of Object.entries(crosstalk)) {
+      html += `<tr><th>${channel}</th>`;
+      for (const source of sources) {
+        const fraction = fractions[source];
+        html += `<td>${fraction === undefined ? "" : Math.round(fraction * 100) + "%"}</td>`;
+      }
+      html += "</tr>";
+    }
+    return html + "</table>";
+}
+
+message_handlers.UpdateCrossTalk = ({ crosstalk }) => {
+    crosstalk_element.innerHTML = crosstalk_table (crosstalk);
+}
+
+let calibration_countdown = null;
+message_handlers.UpdateCalibration = (state) => {
+    clearInterval(calibration_countdown);
+    calibration_apply_button.style.display = "none";
+    calibrating = state !== "Idle";
+    calibration_button.textContent = calibrating ? "Cancel calibration" : "Calibrate";
+    if (state === "Idle") {
+      calibration_status.textContent = "";
+    } else if (state.Prompt) {
+      const { step_index, num_steps, instruction, duration } = state.Prompt;
+      const end = Date.now() + duration * 1000;
+      const show = () => {
+        const seconds_left = Math.max(0, Math.ceil((end - Date.now()) / 1000));
+        calibration_status.textContent = `Step ${step_index + 1} of ${num_steps}: ${instruction} (${seconds_left} s)`;
+      };
+      show();
+      calibration_countdown = setInterval(show, 200);
+    } else if (state.Finished) {
+      const { channels, gestures, crosstalk } = state.Finished;
+      const percent = fraction => `${Math.round(fraction * 100)}%`;
+      let html = "<table><tr><th>Channel</th><th>Rest</th><th>Contraction</th><th>Separation</th><th>Threshold</th></tr>";
+      for (const c of channels) {
+        const threshold = c.activity_threshold === null ? "unchanged" : c.activity_threshold.toFixed(1);
+        html += `<tr><td>${c.channel}</td><td>${c.rest_level.toFixed(1)}</td><td>${c.contraction_level.toFixed(1)}</td><td>${c.separation.toFixed(1)}σ</td><td>${threshold}</td></tr>`;
+      }
+      html += "</table><table><tr><th>Rule</th><th>Recognized</th><th>While resting</th></tr>";
+      for (const g of gestures) {
+        html += `<tr><td>${g.rule}</td><td>${percent(g.recognized)}</td><td>${percent(g.false_alarms)}</td></tr>`;
+      }
+      html += "</table>" + crosstalk_table (crosstalk);
+      calibration_status.innerHTML = html;
+      calibration_button.textContent = "Discard calibration";
+      calibration_apply_button.style.display = "";
+    }
+}
+
+message_handlers.UpdateMainsFrequency = ({ server_index, channel, frequency, detected }) => {
+    const id = `mains_${server_index}_${channel}`;
+    let element = document.getElementById (id);
+    if (!element) {
+      element = document.createElement("div");
+      element.id = id;
+      links_element.appendChild(element);
+    }
+    const how = detected ? "detected" : "assumed";
+    element.innerText = `Server ${server_index} channel ${channel}: ${frequency} Hz mains (${how})`;
+}
+
 function connect() {
     if (socket) { socket.close() }
     socket = new WebSocket(`ws://${location.host}/session`)
@@ -235,13 +568,18 @@ const activity_colors = ["#888800", "#880088", "#000000", "#008888"];
 const freq_colors = [[1, 15, 0], [15, 0, 1], [5, 1, 0], [0, 2, 15]];
 
 function update_canvas() {
+  const total_channels = recent_frames.reduce((total, server) => total + server.signals.length, 0);
+  let first_channel = 0;
   recent_frames.forEach(function(server, server_index) {
+    const server_first_channel = first_channel;
+    first_channel += server.signals.length;
     if (server.latest_drawn_frame_time == server.latest_received_frame_time) {
       return;
     }
 
+    // each channel of each server gets an equal share of the width
     function x1(i) {
-      return Math.round((i + server_index * server.signals.length) * canvas.width / (server.signals.length * recent_frames.length))
+      return Math.round((server_first_channel + i) * canvas.width / total_channels)
     }
     server.signals.forEach(function(signal, signal_index) {
       update_subcanvas({
@@ -283,9 +621,12 @@ function update_canvas() {
               context.stroke();
           }
 
-          context.strokeStyle = activity_colors[signal_index];
+          context.strokeStyle = activity_colors[signal_index % activity_colors.length];
           print_line ("value");
 
+          context.strokeStyle = "#aaaaaa";
+          print_line ("crosstalk");
+
           context.strokeStyle = "#88ff88";
           print_line ("activity_threshold");
 
@@ -294,12 +635,12 @@ function update_canvas() {
         }
       });
 
-      const num_frequency_sections = server.signals[0].frequencies[0] ? server.signals[0].frequencies[0].values.length : 0;
+      const num_frequency_sections = signal.frequencies[0] ? signal.frequencies[0].values.length : 0;
 
       function x2(j) {
         return x1(signal_index + j / num_frequency_sections)
       }
-      const fc = freq_colors[signal_index];
+      const fc = freq_colors[signal_index % freq_colors.length];
       for (let j = 0; j < num_frequency_sections; j++) {
         update_subcanvas({
           canvas, context,
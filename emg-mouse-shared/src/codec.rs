@@ -0,0 +1,426 @@
+use core::convert::TryInto;
+use core::fmt;
+
+/*
+
+The framing that EMG servers use to send samples, whether over a UART, BLE notifications or TCP:
+
+  marker              8 bytes   "emg_dat3"
+  server_run_id       u64 LE    random, chosen each time the server starts
+  first_sample_index  u64 LE
+  num_samples         u16 LE    1 to MAX_SAMPLES_PER_FRAME
+  num_channels        u8        1 to MAX_CHANNELS
+  samples             bytes_per_sample(num_channels) bytes per sample, 12 bits per channel
+  crc                 u32 LE    CRC-32 of everything between the marker and the crc
+
+Each sample is packed as the high 8 bits of each channel, followed by the low 4 bits of
+each pair of channels in one byte (channels 0 and 1, then 2 and 3, and so on). With an odd number
+of channels, the low half of the last byte is unused. So 4 channels take 6 bytes.
+
+Older servers always have 4 channels, and leave out num_channels. Those from before
+the checksum was added send "emg_data" and no crc; the others send "emg_dat2".
+
+This has to match `emg_server.c`. Nothing here allocates, so it can also be used on the server side.
+
+*/
+
+pub const FRAME_MARKER: &[u8; 8] = b"emg_dat3";
+pub const FOUR_CHANNEL_FRAME_MARKER: &[u8; 8] = b"emg_dat2";
+pub const LEGACY_FRAME_MARKER: &[u8; 8] = b"emg_data";
+pub const MARKER_SIZE: usize = 8;
+pub const MAX_HEADER_SIZE: usize = 27;
+pub const CRC_SIZE: usize = 4;
+// the server never sends more than these, so anything bigger can only mean corruption
+pub const MAX_SAMPLES_PER_FRAME: usize = 80;
+pub const MAX_CHANNELS: usize = 16;
+pub const MAX_FRAME_SIZE: usize =
+    MAX_HEADER_SIZE + MAX_SAMPLES_PER_FRAME * bytes_per_sample(MAX_CHANNELS) + CRC_SIZE;
+
+pub const fn bytes_per_sample(num_channels: usize) -> usize {
+    num_channels + num_channels.div_ceil(2)
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum FrameVersion {
+    /// "emg_data": 4 channels, no checksum
+    Legacy,
+    /// "emg_dat2": 4 channels
+    FourChannel,
+    /// "emg_dat3"
+    Current,
+}
+
+impl FrameVersion {
+    pub fn from_marker(marker: &[u8]) -> Option<FrameVersion> {
+        match marker.try_into().ok()? {
+            FRAME_MARKER => Some(FrameVersion::Current),
+            FOUR_CHANNEL_FRAME_MARKER => Some(FrameVersion::FourChannel),
+            LEGACY_FRAME_MARKER => Some(FrameVersion::Legacy),
+            _ => None,
+        }
+    }
+
+    pub fn marker(self) -> &'static [u8; 8] {
+        match self {
+            FrameVersion::Legacy => LEGACY_FRAME_MARKER,
+            FrameVersion::FourChannel => FOUR_CHANNEL_FRAME_MARKER,
+            FrameVersion::Current => FRAME_MARKER,
+        }
+    }
+
+    pub fn header_size(self) -> usize {
+        match self {
+            FrameVersion::Current => MAX_HEADER_SIZE,
+            _ => 26,
+        }
+    }
+
+    pub fn checksummed(self) -> bool {
+        self != FrameVersion::Legacy
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DecodeError {
+    BadMarker,
+    BadSampleCount(u16),
+    BadChannelCount(u8),
+    WrongLength { expected: usize, actual: usize },
+    BadChecksum,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::BadMarker => write!(f, "frame doesn't start with a marker"),
+            DecodeError::BadSampleCount(count) => {
+                write!(f, "frame claims to have {} samples", count)
+            }
+            DecodeError::BadChannelCount(count) => {
+                write!(f, "frame claims to have {} channels", count)
+            }
+            DecodeError::WrongLength { expected, actual } => {
+                write!(f, "frame should be {} bytes, not {}", expected, actual)
+            }
+            DecodeError::BadChecksum => write!(f, "frame has a bad checksum"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DecodeError {}
+
+/// The standard CRC-32 (as used by zlib, and by `esp_rom_crc32_le(0, ...)` on the server).
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// Packs one sample into `output`, which must be `bytes_per_sample(sample.len())` long.
+/// Only the low 12 bits of each channel are kept.
+pub fn pack_sample(sample: &[u16], output: &mut [u8]) {
+    let num_channels = sample.len();
+    assert_eq!(output.len(), bytes_per_sample(num_channels));
+    let (high, low) = output.split_at_mut(num_channels);
+    for (byte, &value) in high.iter_mut().zip(sample) {
+        *byte = (value >> 4) as u8;
+    }
+    for (byte, pair) in low.iter_mut().zip(sample.chunks(2)) {
+        let second = pair.get(1).map_or(0, |value| value & 15);
+        *byte = (((pair[0] & 15) << 4) + second) as u8;
+    }
+}
+
+pub fn unpack_channel(bytes: &[u8], num_channels: usize, channel: usize) -> u16 {
+    let low = bytes[num_channels + channel / 2];
+    let low = if channel % 2 == 1 { low & 15 } else { low >> 4 };
+    (u16::from(bytes[channel]) << 4) + u16::from(low)
+}
+
+/// Unpacks one sample, one channel at a time.
+pub fn unpack_sample(bytes: &[u8], num_channels: usize) -> impl Iterator<Item = u16> + '_ {
+    (0..num_channels).map(move |channel| unpack_channel(bytes, num_channels, channel))
+}
+
+/// Unpacks a sample payload; any trailing partial sample is ignored.
+pub fn unpack_samples(
+    payload: &[u8],
+    num_channels: usize,
+) -> impl Iterator<Item = impl Iterator<Item = u16> + '_> + '_ {
+    payload
+        .chunks_exact(bytes_per_sample(num_channels))
+        .map(move |chunk| unpack_sample(chunk, num_channels))
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct FrameHeader {
+    pub version: FrameVersion,
+    pub server_run_id: u64,
+    pub first_sample_index: u64,
+    pub num_samples: u16,
+    /// always 4 for older versions
+    pub num_channels: u8,
+}
+
+impl FrameHeader {
+    pub fn header_size(&self) -> usize {
+        self.version.header_size()
+    }
+
+    pub fn payload_size(&self) -> usize {
+        self.num_samples as usize * bytes_per_sample(self.num_channels as usize)
+    }
+
+    pub fn frame_size(&self) -> usize {
+        self.header_size()
+            + self.payload_size()
+            + if self.version.checksummed() {
+                CRC_SIZE
+            } else {
+                0
+            }
+    }
+
+    /// Writes the header to the start of `output`, and returns how many bytes it took up.
+    pub fn encode(&self, output: &mut [u8]) -> usize {
+        let result = &mut output[..self.header_size()];
+        result[..8].copy_from_slice(self.version.marker());
+        result[8..16].copy_from_slice(&self.server_run_id.to_le_bytes());
+        result[16..24].copy_from_slice(&self.first_sample_index.to_le_bytes());
+        result[24..26].copy_from_slice(&self.num_samples.to_le_bytes());
+        if self.version == FrameVersion::Current {
+            result[26] = self.num_channels;
+        } else {
+            assert_eq!(self.num_channels, 4);
+        }
+        result.len()
+    }
+
+    /// Checks everything that can be checked without the rest of the frame.
+    /// `bytes` may continue past the end of the header.
+    pub fn decode(bytes: &[u8]) -> Result<FrameHeader, DecodeError> {
+        let version = FrameVersion::from_marker(bytes.get(..MARKER_SIZE).ok_or(
+            DecodeError::WrongLength {
+                expected: MARKER_SIZE,
+                actual: bytes.len(),
+            },
+        )?)
+        .ok_or(DecodeError::BadMarker)?;
+        if bytes.len() < version.header_size() {
+            return Err(DecodeError::WrongLength {
+                expected: version.header_size(),
+                actual: bytes.len(),
+            });
+        }
+        let num_samples = u16::from_le_bytes(bytes[24..26].try_into().unwrap());
+        if num_samples == 0 || num_samples as usize > MAX_SAMPLES_PER_FRAME {
+            return Err(DecodeError::BadSampleCount(num_samples));
+        }
+        let num_channels = if version == FrameVersion::Current {
+            bytes[26]
+        } else {
+            4
+        };
+        if num_channels == 0 || num_channels as usize > MAX_CHANNELS {
+            return Err(DecodeError::BadChannelCount(num_channels));
+        }
+        Ok(FrameHeader {
+            version,
+            server_run_id: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+            first_sample_index: u64::from_le_bytes(bytes[16..24].try_into().unwrap()),
+            num_samples,
+            num_channels,
+        })
+    }
+}
+
+/// A complete frame, borrowed from the buffer it was decoded from.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Frame<'a> {
+    pub header: FrameHeader,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    pub fn samples(&self) -> impl Iterator<Item = impl Iterator<Item = u16> + 'a> + 'a {
+        unpack_samples(self.payload, self.header.num_channels as usize)
+    }
+}
+
+/// Decodes exactly one frame, which must take up all of `bytes`.
+pub fn decode_frame(bytes: &[u8]) -> Result<Frame<'_>, DecodeError> {
+    let header = FrameHeader::decode(bytes)?;
+    if bytes.len() != header.frame_size() {
+        return Err(DecodeError::WrongLength {
+            expected: header.frame_size(),
+            actual: bytes.len(),
+        });
+    }
+    let payload_start = header.header_size();
+    let payload_end = payload_start + header.payload_size();
+    if header.version.checksummed() {
+        let crc = u32::from_le_bytes(bytes[payload_end..].try_into().unwrap());
+        if crc != crc32(&bytes[MARKER_SIZE..payload_end]) {
+            return Err(DecodeError::BadChecksum);
+        }
+    }
+    Ok(Frame {
+        header,
+        payload: &bytes[payload_start..payload_end],
+    })
+}
+
+/// Encodes a frame of the current version into the start of `output`, and returns how many bytes it took up.
+/// Panics if there are too many or too few samples or channels, if the samples don't all have
+/// the same number of channels, or if `output` is too small.
+pub fn encode_frame<S: AsRef<[u16]>>(
+    server_run_id: u64,
+    first_sample_index: u64,
+    samples: &[S],
+    output: &mut [u8],
+) -> usize {
+    assert!(!samples.is_empty() && samples.len() <= MAX_SAMPLES_PER_FRAME);
+    let num_channels = samples[0].as_ref().len();
+    assert!((1..=MAX_CHANNELS).contains(&num_channels));
+    let header = FrameHeader {
+        version: FrameVersion::Current,
+        server_run_id,
+        first_sample_index,
+        num_samples: samples.len() as u16,
+        num_channels: num_channels as u8,
+    };
+    let frame = &mut output[..header.frame_size()];
+    let payload_start = header.encode(frame);
+    let payload_end = payload_start + header.payload_size();
+    for (chunk, sample) in frame[payload_start..payload_end]
+        .chunks_exact_mut(bytes_per_sample(num_channels))
+        .zip(samples)
+    {
+        assert_eq!(sample.as_ref().len(), num_channels);
+        pack_sample(sample.as_ref(), chunk);
+    }
+    let crc = crc32(&frame[MARKER_SIZE..payload_end]);
+    frame[payload_end..].copy_from_slice(&crc.to_le_bytes());
+    frame.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    fn sample(num_channels: usize) -> impl Strategy<Value = Vec<u16>> {
+        vec(0..4096u16, num_channels)
+    }
+
+    fn samples() -> impl Strategy<Value = Vec<Vec<u16>>> {
+        (1..=MAX_CHANNELS)
+            .prop_flat_map(|num_channels| vec(sample(num_channels), 1..=MAX_SAMPLES_PER_FRAME))
+    }
+
+    #[test]
+    fn crc32_matches_reference() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn matches_server_packing() {
+        // as written by emg_server.c for 0xabc, 0x123, 0x456, 0xfff
+        let mut packed = [0; 6];
+        pack_sample(&[0xabc, 0x123, 0x456, 0xfff], &mut packed);
+        assert_eq!(packed, [0xab, 0x12, 0x45, 0xff, 0xc3, 0x6f]);
+    }
+
+    #[test]
+    fn older_versions_have_four_channels() {
+        for version in [FrameVersion::Legacy, FrameVersion::FourChannel] {
+            let header = FrameHeader {
+                version,
+                server_run_id: 1,
+                first_sample_index: 2,
+                num_samples: 3,
+                num_channels: 4,
+            };
+            let mut bytes = [0; MAX_HEADER_SIZE];
+            let size = header.encode(&mut bytes);
+            assert_eq!(size, 26);
+            assert_eq!(FrameHeader::decode(&bytes[..size]), Ok(header));
+            assert_eq!(header.payload_size(), 18);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn sample_round_trip(sample in (1..=MAX_CHANNELS).prop_flat_map(sample)) {
+            let mut packed = vec![0; bytes_per_sample(sample.len())];
+            pack_sample(&sample, &mut packed);
+            prop_assert!(unpack_sample(&packed, sample.len()).eq(sample.iter().copied()));
+        }
+
+        #[test]
+        fn header_round_trip(
+            server_run_id: u64,
+            first_sample_index: u64,
+            num_samples in 1..=MAX_SAMPLES_PER_FRAME as u16,
+            num_channels in 1..=MAX_CHANNELS as u8,
+        ) {
+            let header = FrameHeader {
+                version: FrameVersion::Current,
+                server_run_id,
+                first_sample_index,
+                num_samples,
+                num_channels,
+            };
+            let mut bytes = [0; MAX_HEADER_SIZE];
+            let size = header.encode(&mut bytes);
+            prop_assert_eq!(FrameHeader::decode(&bytes[..size]), Ok(header));
+        }
+
+        #[test]
+        fn frame_round_trip(
+            server_run_id: u64,
+            first_sample_index: u64,
+            samples in samples(),
+        ) {
+            let mut buffer = [0; MAX_FRAME_SIZE];
+            let size = encode_frame(server_run_id, first_sample_index, &samples, &mut buffer);
+            let frame = decode_frame(&buffer[..size]).unwrap();
+            prop_assert_eq!(
+                frame.header,
+                FrameHeader {
+                    version: FrameVersion::Current,
+                    server_run_id,
+                    first_sample_index,
+                    num_samples: samples.len() as u16,
+                    num_channels: samples[0].len() as u8,
+                }
+            );
+            prop_assert!(frame.samples().map(Iterator::collect::<Vec<_>>).eq(samples));
+        }
+
+        #[test]
+        fn corruption_is_detected(
+            samples in samples(),
+            byte_index: prop::sample::Index,
+            bit in 0..8u8,
+        ) {
+            let mut buffer = [0; MAX_FRAME_SIZE];
+            let size = encode_frame(1, 2, &samples, &mut buffer);
+            buffer[byte_index.index(size)] ^= 1 << bit;
+            prop_assert!(decode_frame(&buffer[..size]).is_err());
+        }
+
+        #[test]
+        fn decoding_garbage_does_not_panic(bytes in vec(any::<u8>(), 0..MAX_FRAME_SIZE + 10)) {
+            let _ = decode_frame(&bytes);
+        }
+    }
+}
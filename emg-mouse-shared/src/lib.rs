@@ -1,10 +1,22 @@
-use serde::{Deserialize, Serialize};
-use std::time::Duration;
+#![cfg_attr(not(feature = "std"), no_std)]
 
-pub const HEARTBEAT_DURATION: Duration = Duration::from_secs(5);
+pub mod codec;
 
-#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug, Default)]
-pub struct ReportFromServer {
-    pub time_since_start: Duration,
-    pub inputs: [u16; 4],
+#[cfg(feature = "std")]
+pub use legacy::*;
+
+/// What `emg-server` (the out-of-date Rust firmware) still sends: bincode-serialized reports,
+/// one sample each, instead of the frames in `codec`. The client doesn't read these any more.
+#[cfg(feature = "std")]
+mod legacy {
+    use serde::{Deserialize, Serialize};
+    use std::time::Duration;
+
+    pub const HEARTBEAT_DURATION: Duration = Duration::from_secs(5);
+
+    #[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug, Default)]
+    pub struct ReportFromServer {
+        pub time_since_start: Duration,
+        pub inputs: [u16; 4],
+    }
 }
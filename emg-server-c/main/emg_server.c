@@ -8,6 +8,7 @@
 #include "esp_system.h"
 #include "esp_random.h"
 #include "esp_log.h"
+#include "esp_rom_crc.h"
 #include "nvs_flash.h"
 #include "esp_bt.h"
 #include "sdkconfig.h"
@@ -578,10 +579,16 @@ static void gatts_event_handler(esp_gatts_cb_event_t event, esp_gatt_if_t gatts_
 //}
 
 
-#define SEND_BUFFER_SIZE 1024
+// to change this, also change `adc_channels` in adc_task
+#define NUM_CHANNELS 4
+// 12 bits per channel
+#define BYTES_PER_SAMPLE (NUM_CHANNELS + (NUM_CHANNELS + 1) / 2)
+// a whole number of samples, so that each sample is in one piece
+#define SEND_BUFFER_SIZE (256 * NUM_CHANNELS)
 volatile uint16_t send_buffer[SEND_BUFFER_SIZE];
 const uint16_t SEND_BUFFER_UNUSED = 0xffff;
-const uint16_t HEADER_SIZE = 26;
+const uint16_t HEADER_SIZE = 27;
+const uint16_t CRC_SIZE = 4;
 
 void communication_task(void* arg) {
   uint64_t sample_index = 0;
@@ -590,21 +597,23 @@ void communication_task(void* arg) {
   const uint16_t MAX_MESSAGE_SIZE = 16 + 80*6;
   uint8_t message_data[MAX_MESSAGE_SIZE];
 
-  uint16_t latest_samples[4];
+  uint16_t latest_samples[NUM_CHANNELS];
 
 #ifdef EMG_USE_BLUETOOTH
   while (!ghack_ready) {vTaskDelay(1);}
 #endif
 
-  // first 8 bytes are a unique delimiter
-  memcpy(message_data, "emg_data", 8);
+  // first 8 bytes are a unique delimiter (the "3" is the protocol revision that added the channel count)
+  memcpy(message_data, "emg_dat3", 8);
   // next 8 bytes are a random id that disambiguates which run of the server
   esp_fill_random(message_data+8, 8);
 
-  // next 8 bytes are the index of the first sample in the notification, and next 2 are reserved for the number of samples
+  // next 8 bytes are the index of the first sample in the notification, next 2 are reserved for the number of samples,
+  // and the next 1 is the number of channels; then the samples, then a CRC-32 of everything after the delimiter
   *((uint64_t*)&message_data[16]) = sample_index;
+  message_data[26] = NUM_CHANNELS;
   while(1) {
-    while (HEADER_SIZE + (num_samples_in_message+1)*6 <= MAX_MESSAGE_SIZE) {
+    while (HEADER_SIZE + (num_samples_in_message+1)*BYTES_PER_SAMPLE + CRC_SIZE <= MAX_MESSAGE_SIZE) {
       uint16_t average = send_buffer[send_buffer_read_pos];
       if (average == SEND_BUFFER_UNUSED) {
         if (num_samples_in_message > 0) {
@@ -616,20 +625,22 @@ void communication_task(void* arg) {
           continue;
         }
       }
-      latest_samples[send_buffer_read_pos % 4] = average;
+      latest_samples[send_buffer_read_pos % NUM_CHANNELS] = average;
       send_buffer[send_buffer_read_pos++] = SEND_BUFFER_UNUSED;
       if (send_buffer_read_pos >= SEND_BUFFER_SIZE) {
         send_buffer_read_pos = 0;
       }
 
-      if (send_buffer_read_pos % 4 == 0) {
-        uint16_t start = HEADER_SIZE + num_samples_in_message*6;
-        message_data[start+0] = latest_samples[0] >> 4;
-        message_data[start+1] = latest_samples[1] >> 4;
-        message_data[start+2] = latest_samples[2] >> 4;
-        message_data[start+3] = latest_samples[3] >> 4;
-        message_data[start+4] = ((latest_samples[0] & 0xf) << 4) + (latest_samples[1] & 0xf);
-        message_data[start+5] = ((latest_samples[2] & 0xf) << 4) + (latest_samples[3] & 0xf);
+      if (send_buffer_read_pos % NUM_CHANNELS == 0) {
+        uint16_t start = HEADER_SIZE + num_samples_in_message*BYTES_PER_SAMPLE;
+        // the high 8 bits of each channel, then the low 4 bits of each pair of channels
+        for (uint16_t channel = 0; channel < NUM_CHANNELS; channel++) {
+          message_data[start+channel] = latest_samples[channel] >> 4;
+        }
+        for (uint16_t channel = 0; channel < NUM_CHANNELS; channel += 2) {
+          uint8_t second = (channel + 1 < NUM_CHANNELS) ? (latest_samples[channel+1] & 0xf) : 0;
+          message_data[start+NUM_CHANNELS+channel/2] = ((latest_samples[channel] & 0xf) << 4) + second;
+        }
 //        if (start < HEADER_SIZE || start+5 >= MAX_MESSAGE_SIZE) {
 //        return;}
         sample_index += 1;
@@ -638,6 +649,11 @@ void communication_task(void* arg) {
     }
 
     *((uint16_t*)&message_data[24]) = num_samples_in_message;
+    uint16_t payload_end = HEADER_SIZE + num_samples_in_message*BYTES_PER_SAMPLE;
+    uint32_t crc = esp_rom_crc32_le(0, message_data + 8, payload_end - 8);
+    // not necessarily aligned, so no pointer cast here
+    memcpy(message_data + payload_end, &crc, CRC_SIZE);
+    uint16_t message_size = payload_end + CRC_SIZE;
     //uart_write_bytes(UART_NUM_0, message_data, message_size);
 //    for(uint16_t i = 0; i < 29; i++) {
 //      message_data[i+8] = i;
@@ -645,9 +661,9 @@ void communication_task(void* arg) {
 #ifdef EMG_USE_BLUETOOTH
     esp_ble_gatts_send_indicate(ghack, hack_conn_id, heart_rate_handle_table[IDX_CHAR_VAL_A],
           //uart_write_bytes(UART_NUM_0, message_data, message_size);
-                                        HEADER_SIZE + num_samples_in_message*6, message_data, false);
+                                        message_size, message_data, false);
 #else
-    uart_write_bytes(UART_NUM_0, message_data, HEADER_SIZE + num_samples_in_message*6);
+    uart_write_bytes(UART_NUM_0, message_data, message_size);
 //    write(1, message_data, 28);//HEADER_SIZE + num_samples_in_message*6);
     fflush(stdout);
 #endif
@@ -678,8 +694,8 @@ void adc_task(void * arg) {
 
     const adc_atten_t atten = ADC_ATTEN_DB_11;
     adc1_config_width(ADC_WIDTH_BIT_12);
-    const adc_channel_t adc_channels[4] = {ADC_CHANNEL_4, ADC_CHANNEL_5, ADC_CHANNEL_6, ADC_CHANNEL_7};
-    for(uint32_t adc_index = 0; adc_index < 4; adc_index++) {
+    const adc_channel_t adc_channels[NUM_CHANNELS] = {ADC_CHANNEL_4, ADC_CHANNEL_5, ADC_CHANNEL_6, ADC_CHANNEL_7};
+    for(uint32_t adc_index = 0; adc_index < NUM_CHANNELS; adc_index++) {
       adc1_config_channel_atten(adc_channels[adc_index], atten);
     }
 
@@ -694,11 +710,12 @@ void adc_task(void * arg) {
     const int64_t start_time = esp_timer_get_time();
 //    int vals[10] = {0,0,0,0,0,0,0,0,0,0};
     for(uint64_t sample_index = 0; ; sample_index++) {
-        for(uint32_t adc_index = 0; adc_index < 4; adc_index++) {
+        for(uint32_t adc_index = 0; adc_index < NUM_CHANNELS; adc_index++) {
           uint32_t total = 0;
           uint32_t count = 0;
-          int64_t start_us = start_time + (sample_index * 1000*1000 + adc_index * 250*1000)/1020;
-          int64_t stop_us = start_us + 200;
+          // the channels take turns, evenly spaced through each sample period
+          int64_t start_us = start_time + (sample_index * 1000*1000 + adc_index * 1000*1000/NUM_CHANNELS)/1020;
+          int64_t stop_us = start_us + 800/NUM_CHANNELS;
           adc_channel_t channel = adc_channels[adc_index];
           while (esp_timer_get_time() < start_us){}
 